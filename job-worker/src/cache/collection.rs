@@ -1,16 +1,16 @@
 use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
 use std::hash::Hash;
 
-use futures::future::join_all;
 use mongodb::bson::doc;
-use mongodb::options::ReplaceOptions;
 use serde::{Deserialize, Serialize};
 
-
-use crate::context::direct_collections::MongoCollectionImpl;
+use crate::context::direct_collections::{MongoBulkWriteOperation, MongoWriteCollection};
 use crate::data_model::ids::{unprotect_array, ProtectedId};
 
+use super::diff::diff_serialized;
 use super::doc::DocWithId;
+use super::save::{CacheSaveError, PendingChange, PendingSave};
 
 #[derive(Debug, Clone)]
 pub enum CacheCollectionError<Id: Clone> {
@@ -21,6 +21,26 @@ pub enum CacheCollectionError<Id: Clone> {
     NotFound(Id),
     IdMismatch(Id),
 }
+/** Errors are displayed once the ids have been unprotected, such as when wrapped in a JobError */
+impl Display for CacheCollectionError<String> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheCollectionError::NotImplemented => write!(f, "Not implemented"),
+            CacheCollectionError::IsToBeRemoved(method) => {
+                write!(f, "Collection is to be removed, \"{}\" is not allowed", method)
+            }
+            CacheCollectionError::AlreadyExists(id) => {
+                write!(f, "Document \"{}\" already exists", id)
+            }
+            CacheCollectionError::NotFound(id) => {
+                write!(f, "Document \"{}\" was not found", id)
+            }
+            CacheCollectionError::IdMismatch(id) => {
+                write!(f, "Document \"{}\" can't have its id changed", id)
+            }
+        }
+    }
+}
 
 pub struct CollectionDoc<T> {
     pub inserted: bool,
@@ -90,7 +110,8 @@ pub struct DbCacheWriteCollectionImpl<
     Id: Clone + PartialEq + Eq + Hash + ProtectedId,
 > {
     documents: HashMap<Id, Option<CollectionDoc<T>>>,
-    documents_raw: Vec<T>,
+    /** The documents as they are in the database, to diff against when saving */
+    documents_raw: HashMap<Id, T>,
 
     is_to_be_removed: bool,
 
@@ -110,7 +131,10 @@ impl<
                 .iter()
                 .map(|doc| (doc.doc_id().clone(), CollectionDoc::from(doc.clone())))
                 .collect::<HashMap<_, _>>(),
-            documents_raw: docs.to_vec(),
+            documents_raw: docs
+                .iter()
+                .map(|doc| (doc.doc_id().clone(), doc.clone()))
+                .collect::<HashMap<_, _>>(),
 
             is_to_be_removed: false,
 
@@ -126,89 +150,127 @@ impl<
         }
     }
 
-    pub async fn save_into_collection(
-        &mut self,
-        collection: &MongoCollectionImpl<T, Id>,
-    ) -> std::result::Result<(), String> {
-        if !self.is_to_be_removed {
-            let mut removed_docs = Vec::new();
-
-            let results = {
-                let mut updates = Vec::new();
-                for (id, entry) in self.documents.iter_mut() {
-                    if let Some(entry) = entry {
-                        if entry.inserted {
-                            let options = ReplaceOptions::builder().upsert(true).build();
-
-                            updates.push(collection.collection.replace_one(
-                                doc! {"_id": id.unprotect()},
-                                &entry.document,
-                                options,
-                            ));
-                        } else if entry.updated {
-                            let options = ReplaceOptions::builder().build();
-
-                            updates.push(collection.collection.replace_one(
-                                doc! {"_id": id.unprotect()},
-                                &entry.document,
-                                options,
-                            ));
-                        }
+    /**
+     * Determine the writes needed to save the changes in this collection.
+     * The cache is not modified until `complete_save` is called with the outcome
+     */
+    pub fn prepare_save(&self) -> PendingSave<T, Id> {
+        let mut pending = PendingSave::new(&self.name);
+        if self.is_to_be_removed {
+            return pending;
+        }
 
-                        entry.inserted = false;
-                        entry.updated = false;
-                        // 	}
-                    } else {
-                        removed_docs.push(id.clone());
+        let mut removed_docs = Vec::new();
+
+        for (id, entry) in self.documents.iter() {
+            if let Some(entry) = entry {
+                let operation = match (entry.inserted, self.documents_raw.get(id)) {
+                    (false, Some(raw_doc)) => {
+                        if entry.updated {
+                            match diff_serialized(raw_doc, &entry.document) {
+                                Ok(modifier) if modifier.is_empty() => {
+                                    // Nothing changed
+                                    None
+                                }
+                                Ok(modifier) => Some(MongoBulkWriteOperation::UpdateOne {
+                                    query: doc! { "_id": id.unprotect() },
+                                    modifier,
+                                }),
+                                Err(_) => {
+                                    // Fallback to replacing the whole document, which will report the error
+                                    Some(MongoBulkWriteOperation::ReplaceOne {
+                                        doc: entry.document.clone(),
+                                        upsert: false,
+                                    })
+                                }
+                            }
+                        } else {
+                            None
+                        }
+                    }
+                    _ => {
+                        // New, or not yet known to be in the database
+                        Some(MongoBulkWriteOperation::ReplaceOne {
+                            doc: entry.document.clone(),
+                            upsert: true,
+                        })
                     }
+                };
+
+                if let Some(operation) = operation {
+                    pending.push(
+                        operation,
+                        PendingChange {
+                            ids: vec![id.clone()],
+                            document: Some(entry.document.clone()),
+                        },
+                    );
                 }
+            } else {
+                removed_docs.push(id.clone());
+            }
+        }
 
-                if !updates.is_empty() {
-                    join_all(updates).await
-                } else {
-                    vec![]
-                }
-            };
-
-            let mut errs = results
-                .into_iter()
-                .filter_map(|r| match collection.wrap_mongodb_error(r) {
-                    Ok(_) => None,
-                    Err(e) => Some(e),
-                })
-                .collect::<Vec<_>>();
-
-            if !removed_docs.is_empty() {
-                let err = collection
-                    .collection
-                    .delete_many(
-                        doc! {
-                            "_id": { "$in": unprotect_array(&removed_docs)}
-                        },
-                        None,
-                    )
-                    .await;
+        if !removed_docs.is_empty() {
+            pending.push(
+                MongoBulkWriteOperation::Remove(doc! {
+                    "_id": { "$in": unprotect_array(&removed_docs)}
+                }),
+                PendingChange {
+                    ids: removed_docs,
+                    document: None,
+                },
+            );
+        }
 
-                if let Err(e) = collection.wrap_mongodb_error(err) {
-                    errs.push(e);
-                }
+        pending
+    }
 
-                for id in removed_docs {
-                    // TODO - fix this
-                    self.documents.remove(&id);
+    /**
+     * Update the cache to reflect the changes which have been written to the database.
+     * Only the first `applied_count` changes are marked as saved, anything after remains dirty to be retried on the next save
+     */
+    pub fn complete_save(&mut self, pending: PendingSave<T, Id>, applied_count: usize) {
+        let fully_applied = applied_count >= pending.len();
+
+        for change in pending.changes.into_iter().take(applied_count) {
+            if let Some(document) = change.document {
+                let id = document.doc_id().clone();
+                if let Some(Some(entry)) = self.documents.get_mut(&id) {
+                    entry.inserted = false;
+                    entry.updated = false;
+                }
+                self.documents_raw.insert(id, document);
+            } else {
+                for id in change.ids {
+                    self.documents_raw.remove(&id);
+                    if let Some(None) = self.documents.get(&id) {
+                        self.documents.remove(&id);
+                    }
                 }
             }
+        }
 
-            if !errs.is_empty() {
-                let str = errs.join("\n");
-                Err(str)
-            } else {
-                Ok(())
+        if fully_applied {
+            // Anything flagged as changed without needing a write is now in sync too
+            for entry in self.documents.values_mut().flatten() {
+                entry.inserted = false;
+                entry.updated = false;
             }
-        } else {
-            Ok(())
         }
     }
+
+    pub async fn save_into_collection(
+        &mut self,
+        collection: &dyn MongoWriteCollection<T, Id>,
+    ) -> std::result::Result<(), CacheSaveError> {
+        let mut pending = self.prepare_save();
+
+        let (applied_count, result) = pending.execute(collection, None).await;
+        self.complete_save(pending, applied_count);
+
+        result
+    }
 }
 impl<
         T: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de> + Serialize,
@@ -331,8 +393,9 @@ impl<
             }
         }
 
+        // Leave a tombstone, so that the removal is saved
         for id in &removed {
-            self.documents.remove(id);
+            self.documents.insert(id.clone(), None);
         }
 
         Ok(removed)
@@ -342,7 +405,7 @@ impl<
         if self.is_modified() {
             self.documents.clear();
 
-            for doc in &self.documents_raw {
+            for doc in self.documents_raw.values() {
                 self.documents.insert(
                     doc.doc_id().clone(),
                     Some(CollectionDoc {
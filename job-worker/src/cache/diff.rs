@@ -0,0 +1,193 @@
+use mongodb::bson::{self, Bson, Document};
+use serde::Serialize;
+
+/**
+ * Compute a mongo update modifier which will turn `old` into `new`.
+ * Sub-documents are diffed recursively, so only the paths which have changed get written. Arrays are replaced as a whole
+ */
+pub fn diff_documents(old: &Document, new: &Document) -> Document {
+    let mut set = Document::new();
+    let mut unset = Document::new();
+
+    diff_into(old, new, "", &mut set, &mut unset);
+
+    let mut modifier = Document::new();
+    if !set.is_empty() {
+        modifier.insert("$set", set);
+    }
+    if !unset.is_empty() {
+        modifier.insert("$unset", unset);
+    }
+    modifier
+}
+
+/**
+ * Compute a mongo update modifier which will turn `old` into `new`, after converting them to bson
+ */
+pub fn diff_serialized<T: Serialize>(old: &T, new: &T) -> Result<Document, String> {
+    let old = bson::to_document(old).map_err(|err| format!("Failed to serialize: {}", err))?;
+    let new = bson::to_document(new).map_err(|err| format!("Failed to serialize: {}", err))?;
+
+    Ok(diff_documents(&old, &new))
+}
+
+fn diff_into(
+    old: &Document,
+    new: &Document,
+    prefix: &str,
+    set: &mut Document,
+    unset: &mut Document,
+) {
+    for (key, new_value) in new {
+        let path = format!("{}{}", prefix, key);
+
+        match (old.get(key), new_value) {
+            (Some(Bson::Document(old_child)), Bson::Document(new_child)) => {
+                diff_into(old_child, new_child, &format!("{}.", path), set, unset);
+            }
+            (Some(old_value), new_value) if old_value == new_value => {
+                // Unchanged
+            }
+            _ => {
+                set.insert(path, new_value.clone());
+            }
+        }
+    }
+
+    for key in old.keys() {
+        if !new.contains_key(key) {
+            unset.insert(format!("{}{}", prefix, key), 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+    use serde::Serialize;
+
+    use super::*;
+
+    #[test]
+    fn diff_documents_paths() {
+        for (old, new, expected) in [
+            // Unchanged
+            (doc! {}, doc! {}, doc! {}),
+            (
+                doc! { "a": 1, "b": { "c": [1, 2] } },
+                doc! { "a": 1, "b": { "c": [1, 2] } },
+                doc! {},
+            ),
+            // Changed and added fields
+            (
+                doc! { "a": 1, "b": "x" },
+                doc! { "a": 2, "b": "x", "c": true },
+                doc! { "$set": { "a": 2, "c": true } },
+            ),
+            // A change of type is a change
+            (
+                doc! { "a": 1 },
+                doc! { "a": 1_i64 },
+                doc! { "$set": { "a": 1_i64 } },
+            ),
+            // Removed fields
+            (
+                doc! { "a": 1, "b": 2 },
+                doc! { "a": 1 },
+                doc! { "$unset": { "b": 1 } },
+            ),
+            // Nested documents are diffed by path
+            (
+                doc! { "a": { "b": 1, "c": { "d": 1, "e": 2 } } },
+                doc! { "a": { "b": 2, "c": { "d": 1 }, "f": 3 } },
+                doc! { "$set": { "a.b": 2, "a.f": 3 }, "$unset": { "a.c.e": 1 } },
+            ),
+            // A document replacing another type, or the reverse, is set as a whole
+            (
+                doc! { "a": 1, "b": { "c": 1 } },
+                doc! { "a": { "c": 1 }, "b": 1 },
+                doc! { "$set": { "a": { "c": 1 }, "b": 1 } },
+            ),
+            // Arrays are replaced as a whole
+            (
+                doc! { "a": [1, 2, 3] },
+                doc! { "a": [1, 2, 4] },
+                doc! { "$set": { "a": [1, 2, 4] } },
+            ),
+            (
+                doc! { "a": [{ "b": 1 }] },
+                doc! { "a": [{ "b": 1 }, { "b": 2 }] },
+                doc! { "$set": { "a": [{ "b": 1 }, { "b": 2 }] } },
+            ),
+            // Null is a value, not a removal
+            (
+                doc! { "a": 1 },
+                doc! { "a": null },
+                doc! { "$set": { "a": null } },
+            ),
+        ] {
+            assert_eq!(diff_documents(&old, &new), expected, "{} -> {}", old, new);
+        }
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TestDoc {
+        name: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        identifier: Option<String>,
+        nested: TestNested,
+    }
+
+    #[derive(Serialize)]
+    #[serde(rename_all = "camelCase")]
+    struct TestNested {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        started_playback: Option<i64>,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn diff_serialized_paths() {
+        let old = TestDoc {
+            name: "a".to_string(),
+            identifier: Some("id".to_string()),
+            nested: TestNested {
+                started_playback: Some(1000),
+                tags: vec!["x".to_string()],
+            },
+        };
+
+        assert_eq!(diff_serialized(&old, &old).unwrap(), doc! {});
+
+        // None fields are skipped when serialized, so are unset
+        let new = TestDoc {
+            name: "b".to_string(),
+            identifier: None,
+            nested: TestNested {
+                started_playback: None,
+                tags: vec!["x".to_string(), "y".to_string()],
+            },
+        };
+        assert_eq!(
+            diff_serialized(&old, &new).unwrap(),
+            doc! {
+                "$set": { "name": "b", "nested.tags": ["x", "y"] },
+                "$unset": { "identifier": 1, "nested.startedPlayback": 1 },
+            }
+        );
+
+        // And set again when they return
+        assert_eq!(
+            diff_serialized(&new, &old).unwrap(),
+            doc! {
+                "$set": {
+                    "name": "a",
+                    "identifier": "id",
+                    "nested.startedPlayback": 1000_i64,
+                    "nested.tags": ["x"],
+                },
+            }
+        );
+    }
+}
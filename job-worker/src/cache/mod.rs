@@ -1,3 +1,5 @@
 pub mod collection;
+pub mod diff;
 pub mod doc;
 pub mod object;
+pub mod save;
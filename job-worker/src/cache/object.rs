@@ -1,10 +1,18 @@
-use mongodb::{bson::doc, options::ReplaceOptions};
+use mongodb::bson::{self, doc, Bson};
 use serde::{Deserialize, Serialize};
 
-use crate::{context::direct_collections::MongoCollectionImpl, data_model::ids::ProtectedId};
+use crate::{
+    context::direct_collections::{MongoBulkWriteOperation, MongoWriteCollection},
+    data_model::ids::ProtectedId,
+};
 
-use super::doc::DocWithId;
+use super::{
+    diff::diff_serialized,
+    doc::DocWithId,
+    save::{CacheSaveError, PendingChange, PendingSave},
+};
 use core::hash::Hash;
+use std::fmt::Display;
 
 #[derive(Debug, Clone)]
 pub enum CacheObjectError {
@@ -12,6 +20,16 @@ pub enum CacheObjectError {
     NotImplemented,
     IsToBeRemoved(&'static str),
 }
+impl Display for CacheObjectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheObjectError::NotImplemented => write!(f, "Not implemented"),
+            CacheObjectError::IsToBeRemoved(method) => {
+                write!(f, "Object is to be removed, \"{}\" is not allowed", method)
+            }
+        }
+    }
+}
 
 type Result<T> = std::result::Result<T, CacheObjectError>;
 
@@ -40,6 +58,16 @@ pub trait DbCacheWriteObject<
     fn update<F: Fn(&T) -> Option<T>>(&mut self, cb: F) -> Result<bool>;
 }
 
+/**
+ * A precondition for saving an object, to detect when the document has been modified by someone else since it was loaded.
+ * The save will only succeed if the field still has the value it was loaded with, and the field gets bumped to a new value by the save
+ */
+pub struct ConcurrencyGuard<T> {
+    pub field: &'static str,
+    /** Set the field to a new value */
+    pub bump: fn(&mut T),
+}
+
 pub struct DbCacheWriteObjectImpl<
     T: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de> + Serialize,
     Id: Clone + PartialEq + Eq + Hash + ProtectedId,
@@ -52,6 +80,10 @@ pub struct DbCacheWriteObjectImpl<
     is_to_be_removed: bool,
     updated: bool,
 
+    concurrency_guard: Option<ConcurrencyGuard<T>>,
+    /** The guarded field has already been bumped in the database by `claim_for_save` */
+    claimed: bool,
+
     name: String,
 }
 impl<
@@ -69,10 +101,24 @@ impl<
             is_to_be_removed: false,
             updated: false,
 
+            concurrency_guard: None,
+            claimed: false,
+
             name: collection_name,
         }
     }
 
+    /**
+     * Guard saving of this object against the document being modified by someone else since it was loaded
+     */
+    pub fn with_concurrency_guard(
+        mut self,
+        guard: ConcurrencyGuard<T>,
+    ) -> DbCacheWriteObjectImpl<T, Id> {
+        self.concurrency_guard = Some(guard);
+        self
+    }
+
     fn assert_not_to_be_removed(&self, method: &'static str) -> Result<()> {
         if self.is_to_be_removed {
             Err(CacheObjectError::IsToBeRemoved(method))
@@ -81,27 +127,140 @@ impl<
         }
     }
 
-    pub async fn save_into_collection(
+    fn has_changes(&self) -> std::result::Result<bool, String> {
+        Ok(!self.is_to_be_removed
+            && self.updated
+            && !diff_serialized(&self.document_raw, &self.document)?.is_empty())
+    }
+
+    /**
+     * Build the query which matches the document, only if the guarded field still has the value it was loaded with
+     */
+    fn guarded_query(&self, field: &str) -> std::result::Result<bson::Document, String> {
+        let mut query = doc! { "_id": self.id.unprotect() };
+
+        let raw_doc = bson::to_document(&self.document_raw)
+            .map_err(|err| format!("Failed to serialize: {}", err))?;
+        match raw_doc.get(field) {
+            Some(value) => query.insert(field, value.clone()),
+            None => query.insert(field, doc! { "$exists": false }),
+        };
+
+        Ok(query)
+    }
+
+    /**
+     * Check the concurrency guard ahead of the save, by bumping the guarded field in the database only if it is unchanged.
+     * This allows a conflict to be detected before anything else is written, when the save can't be done in a transaction.
+     * The following save is then guarded by the bumped value
+     */
+    pub async fn claim_for_save(
         &mut self,
-        collection: &MongoCollectionImpl<T, Id>,
-    ) -> std::result::Result<(), String> {
-        if !self.is_to_be_removed && self.updated {
-            let options = ReplaceOptions::builder().upsert(true).build();
-
-            let err = collection
-                .collection
-                .replace_one(
-                    doc! {"_id": self.doc_id().unprotect() },
-                    &self.document,
-                    options,
-                )
-                .await;
-
-            collection.wrap_mongodb_error(err)?;
-        }
+        collection: &dyn MongoWriteCollection<T, Id>,
+    ) -> std::result::Result<(), CacheSaveError> {
+        let guard = match &self.concurrency_guard {
+            Some(guard) if !self.claimed && self.has_changes()? => guard,
+            _ => return Ok(()),
+        };
+
+        let mut document = self.document.clone();
+        (guard.bump)(&mut document);
+        let value = bson::to_document(&document)
+            .map_err(|err| format!("Failed to serialize: {}", err))?
+            .get(guard.field)
+            .cloned()
+            .unwrap_or(Bson::Null);
+
+        // The database now holds the bumped value
+        let mut raw_doc = bson::to_document(&self.document_raw)
+            .map_err(|err| format!("Failed to serialize: {}", err))?;
+        raw_doc.insert(guard.field, value.clone());
+        let document_raw: T = bson::from_document(raw_doc)
+            .map_err(|err| format!("Failed to deserialize: {}", err))?;
+
+        let mut pending = PendingSave::new(&self.name);
+        pending.require_matched = true;
+        pending.push(
+            MongoBulkWriteOperation::UpdateOne {
+                query: self.guarded_query(guard.field)?,
+                modifier: doc! { "$set": { guard.field: value } },
+            },
+            PendingChange {
+                ids: vec![self.id.clone()],
+                document: Some(document_raw.clone()),
+            },
+        );
+        pending.execute(collection, None).await.1?;
+
+        self.document = document;
+        self.document_raw = document_raw;
+        self.claimed = true;
 
         Ok(())
     }
+
+    /**
+     * Determine the write needed to save the changes to this object.
+     * The cache is not modified until `complete_save` is called with the outcome
+     */
+    pub fn prepare_save(&self) -> std::result::Result<PendingSave<T, Id>, String> {
+        let mut pending = PendingSave::new(&self.name);
+
+        if self.has_changes()? {
+            let mut query = doc! { "_id": self.id.unprotect() };
+            let mut document = self.document.clone();
+
+            if let Some(guard) = &self.concurrency_guard {
+                query = self.guarded_query(guard.field)?;
+
+                if !self.claimed {
+                    (guard.bump)(&mut document);
+                }
+                pending.require_matched = true;
+            }
+
+            pending.push(
+                MongoBulkWriteOperation::UpdateOne {
+                    query,
+                    modifier: diff_serialized(&self.document_raw, &document)?,
+                },
+                PendingChange {
+                    ids: vec![self.id.clone()],
+                    document: Some(document),
+                },
+            );
+        }
+
+        Ok(pending)
+    }
+
+    /**
+     * Update the cache to reflect the changes which have been written to the database
+     */
+    pub fn complete_save(&mut self, pending: PendingSave<T, Id>, applied_count: usize) {
+        if applied_count >= pending.len() {
+            // The saved document may differ from the cached one, if the concurrency guard was bumped
+            if let Some(document) = pending.changes.into_iter().find_map(|c| c.document) {
+                self.document = document;
+            }
+
+            self.document_raw = self.document.clone();
+            self.updated = false;
+            self.claimed = false;
+        }
+    }
+
+    pub async fn save_into_collection(
+        &mut self,
+        collection: &dyn MongoWriteCollection<T, Id>,
+    ) -> std::result::Result<(), CacheSaveError> {
+        let mut pending = self.prepare_save()?;
+
+        let (applied_count, result) = pending.execute(collection, None).await;
+        self.complete_save(pending, applied_count);
+
+        result
+    }
 }
 impl<
         T: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de> + Serialize,
@@ -0,0 +1,236 @@
+use std::{fmt::Display, hash::Hash};
+
+use itertools::Itertools;
+use mongodb::ClientSession;
+use serde::Deserialize;
+
+use crate::{
+    context::direct_collections::{
+        MongoBulkWriteOperation, MongoBulkWriteResult, MongoWriteCollection,
+    },
+    data_model::ids::{unprotect_array, ProtectedId},
+    error::JobError,
+};
+
+use super::doc::DocWithId;
+
+#[derive(Debug, Clone)]
+pub enum CacheSaveError {
+    /** The document was modified by someone else since it was loaded into the cache */
+    Conflict { collection: String, id: String },
+    /** Some of the changes were saved before the failure */
+    Partial {
+        saved: Vec<String>,
+        not_attempted: Vec<String>,
+        error: Box<CacheSaveError>,
+    },
+    Failed(String),
+    /** The write failed in a way which may succeed if retried, such as a write conflict inside of a transaction */
+    Transient(String),
+    /** The changes were saved, but some of the functions deferred until after the save failed */
+    AfterSave(Vec<JobError>),
+}
+impl CacheSaveError {
+    pub fn is_conflict(&self) -> bool {
+        match self {
+            CacheSaveError::Conflict { .. } => true,
+            CacheSaveError::Partial { error, .. } => error.is_conflict(),
+            CacheSaveError::Failed(_)
+            | CacheSaveError::Transient(_)
+            | CacheSaveError::AfterSave(_) => false,
+        }
+    }
+
+    pub fn is_transient(&self) -> bool {
+        matches!(self, CacheSaveError::Transient(_))
+    }
+}
+impl Display for CacheSaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CacheSaveError::Conflict { collection, id } => write!(
+                f,
+                "Document \"{}\" in \"{}\" has been modified since it was loaded",
+                id, collection
+            ),
+            CacheSaveError::Partial {
+                saved,
+                not_attempted,
+                error,
+            } => write!(
+                f,
+                "Save was partially completed. Saved: [{}], not attempted: [{}]\n{}",
+                saved.join(", "),
+                not_attempted.join(", "),
+                error
+            ),
+            CacheSaveError::Failed(message) => write!(f, "{}", message),
+            CacheSaveError::Transient(message) => write!(f, "{}", message),
+            CacheSaveError::AfterSave(errors) => write!(
+                f,
+                "Save was completed, but deferred functions failed:\n{}",
+                errors.iter().map(|err| err.to_string()).join("\n")
+            ),
+        }
+    }
+}
+impl From<String> for CacheSaveError {
+    fn from(message: String) -> Self {
+        CacheSaveError::Failed(message)
+    }
+}
+
+/**
+ * A change to a document in the cache that is waiting to be written to the database
+ */
+pub struct PendingChange<T, Id> {
+    /** The ids of the documents affected */
+    pub ids: Vec<Id>,
+    /** The document as it will be in the database once written, or None if it is being removed */
+    pub document: Option<T>,
+}
+
+/**
+ * The writes needed to save a cache collection or object to the database.
+ * Nothing in the cache is considered saved until `complete_save` is called with the outcome, so that a failed write leaves the cache dirty
+ */
+pub struct PendingSave<T, Id: Clone + PartialEq + Eq + Hash + ProtectedId> {
+    pub name: String,
+
+    operations: Vec<MongoBulkWriteOperation<T>>,
+    /** The change made by each operation, in the same order */
+    pub changes: Vec<PendingChange<T, Id>>,
+
+    /** Every operation must match a document, otherwise the save is treated as a conflict */
+    pub require_matched: bool,
+}
+impl<T, Id: Clone + PartialEq + Eq + Hash + ProtectedId> PendingSave<T, Id> {
+    pub fn new(name: &str) -> PendingSave<T, Id> {
+        PendingSave {
+            name: name.to_string(),
+
+            operations: Vec::new(),
+            changes: Vec::new(),
+
+            require_matched: false,
+        }
+    }
+
+    pub fn push(&mut self, operation: MongoBulkWriteOperation<T>, change: PendingChange<T, Id>) {
+        self.operations.push(operation);
+        self.changes.push(change);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /**
+     * Take the operations to be sent to the database. The changes are kept, to later be passed to `complete_save`
+     */
+    pub fn take_operations(&mut self) -> Vec<MongoBulkWriteOperation<T>> {
+        std::mem::take(&mut self.operations)
+    }
+
+    /**
+     * The number of operations which were applied to the database. As the bulk write is ordered, this is everything before the first failure
+     */
+    pub fn applied_count(&self, result: &MongoBulkWriteResult) -> usize {
+        result
+            .write_errors
+            .iter()
+            .map(|err| err.index)
+            .min()
+            .unwrap_or(self.changes.len())
+    }
+
+    /**
+     * Describe the failures from a bulk write, including which documents were affected
+     */
+    pub fn describe_failure(&self, result: &MongoBulkWriteResult) -> String {
+        let mut errs = result
+            .write_errors
+            .iter()
+            .map(|err| {
+                let ids = self
+                    .changes
+                    .get(err.index)
+                    .map(|change| unprotect_array(&change.ids).join(", "))
+                    .unwrap_or_default();
+                format!(
+                    "write failed for \"{}\" ({}): {}",
+                    self.name, ids, err.message
+                )
+            })
+            .collect::<Vec<_>>();
+
+        // The write is ordered, so anything after the failure was not attempted
+        if let Some(last_index) = result.write_errors.iter().map(|err| err.index).max() {
+            let skipped = self.changes.len().saturating_sub(last_index + 1);
+            if skipped > 0 {
+                errs.push(format!(
+                    "{} writes for \"{}\" were not attempted",
+                    skipped, self.name
+                ));
+            }
+        }
+
+        errs.join("\n")
+    }
+}
+impl<
+        T: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de>,
+        Id: Clone + PartialEq + Eq + Hash + ProtectedId,
+    > PendingSave<T, Id>
+{
+    /**
+     * Write the operations to the collection, optionally as part of a session.
+     * Returns the number of operations which were applied, along with any failure
+     */
+    pub async fn execute(
+        &mut self,
+        collection: &dyn MongoWriteCollection<T, Id>,
+        session: Option<&mut ClientSession>,
+    ) -> (usize, Result<(), CacheSaveError>) {
+        let operations = self.take_operations();
+        if operations.is_empty() {
+            return (0, Ok(()));
+        }
+
+        let result = match session {
+            Some(session) => collection.bulk_write_in_session(operations, session).await,
+            None => collection.bulk_write(operations).await,
+        };
+
+        match result {
+            Ok(result) if !result.is_ok() => (
+                self.applied_count(&result),
+                Err(CacheSaveError::Failed(self.describe_failure(&result))),
+            ),
+            Ok(result) if self.require_matched && result.matched_count < self.len() as u64 => {
+                let id = self
+                    .changes
+                    .first()
+                    .map(|change| unprotect_array(&change.ids).join(", "))
+                    .unwrap_or_default();
+
+                (
+                    0,
+                    Err(CacheSaveError::Conflict {
+                        collection: self.name.clone(),
+                        id,
+                    }),
+                )
+            }
+            Ok(_) => (self.len(), Ok(())),
+            Err(JobError::DatabaseTransient(message)) => {
+                (0, Err(CacheSaveError::Transient(message)))
+            }
+            Err(err) => (0, Err(CacheSaveError::Failed(err.to_string()))),
+        }
+    }
+}
@@ -1,5 +1,5 @@
-// /** How many parts lookahead will search through when no other value is specified  */
-// export const LOOKAHEAD_DEFAULT_SEARCH_DISTANCE = 10
+/** How many parts lookahead will search through when no other value is specified  */
+pub const LOOKAHEAD_DEFAULT_SEARCH_DISTANCE: usize = 10;
 
 /** TODO - this should be some kind of config */
 pub const PRESERVE_UNSYNCED_PLAYING_SEGMENT_CONTENTS: bool = false;
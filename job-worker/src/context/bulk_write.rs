@@ -0,0 +1,309 @@
+use mongodb::{
+    bson::{self, doc, from_document, Bson, Document},
+    error::TRANSIENT_TRANSACTION_ERROR,
+    ClientSession, Database,
+};
+use serde::Deserialize;
+
+use crate::error::JobError;
+
+use super::direct_collections::{
+    MongoBulkWriteError, MongoBulkWriteOperation, MongoBulkWriteResult,
+};
+
+/** The most operations MongoDB accepts in a single write command (`maxWriteBatchSize`) */
+const MAX_BATCH_OPERATIONS: usize = 100_000;
+/**
+ * The most bytes of operations to send in a single write command.
+ * The whole command has to fit within the 16MB document limit, so leave some room for the rest of it
+ */
+const MAX_BATCH_BYTES: usize = 16 * 1024 * 1024 - 64 * 1024;
+/** Allowance for the array key and type of each operation within the command */
+const OPERATION_OVERHEAD_BYTES: usize = 16;
+
+/**
+ * The type of command that an operation is sent to the database as.
+ * Consecutive operations of the same kind get batched into a single command
+ */
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum CommandKind {
+    Insert,
+    Update,
+    Delete,
+}
+impl CommandKind {
+    fn command_name(&self) -> &'static str {
+        match self {
+            CommandKind::Insert => "insert",
+            CommandKind::Update => "update",
+            CommandKind::Delete => "delete",
+        }
+    }
+    fn field_name(&self) -> &'static str {
+        match self {
+            CommandKind::Insert => "documents",
+            CommandKind::Update => "updates",
+            CommandKind::Delete => "deletes",
+        }
+    }
+}
+
+fn convert_operation(operation: MongoBulkWriteOperation<Document>) -> (CommandKind, Document) {
+    match operation {
+        MongoBulkWriteOperation::InsertOne(doc) => (CommandKind::Insert, doc),
+        MongoBulkWriteOperation::ReplaceOne { doc, upsert } => (
+            CommandKind::Update,
+            doc! {
+                "q": { "_id": doc.get("_id").cloned().unwrap_or(Bson::Null) },
+                "u": doc,
+                "upsert": upsert,
+                "multi": false,
+            },
+        ),
+        MongoBulkWriteOperation::UpdateOne { query, modifier } => (
+            CommandKind::Update,
+            doc! {
+                "q": query,
+                "u": modifier,
+                "multi": false,
+            },
+        ),
+        MongoBulkWriteOperation::UpdateMany { query, modifier } => (
+            CommandKind::Update,
+            doc! {
+                "q": query,
+                "u": modifier,
+                "multi": true,
+            },
+        ),
+        MongoBulkWriteOperation::Remove(query) => (
+            CommandKind::Delete,
+            doc! {
+                "q": query,
+                "limit": 0,
+            },
+        ),
+    }
+}
+
+/**
+ * A group of consecutive operations which are sent as a single command
+ */
+struct CommandBatch {
+    kind: CommandKind,
+    /** Index of the first operation of the batch, within the whole bulk write */
+    start_index: usize,
+    docs: Vec<Document>,
+    size: usize,
+}
+
+/**
+ * Group the operations into commands. Consecutive operations of the same kind share a command,
+ * until it reaches either the operation count or the size limit
+ */
+fn build_batches(
+    operations: Vec<MongoBulkWriteOperation<Document>>,
+    max_operations: usize,
+    max_bytes: usize,
+) -> Result<Vec<CommandBatch>, bson::ser::Error> {
+    let mut batches: Vec<CommandBatch> = Vec::new();
+    for (index, operation) in operations.into_iter().enumerate() {
+        let (kind, doc) = convert_operation(operation);
+        let size = bson::to_vec(&doc)?.len() + OPERATION_OVERHEAD_BYTES;
+
+        match batches.last_mut() {
+            Some(batch)
+                if batch.kind == kind
+                    && batch.docs.len() < max_operations
+                    && batch.size + size <= max_bytes =>
+            {
+                batch.docs.push(doc);
+                batch.size += size;
+            }
+            _ => batches.push(CommandBatch {
+                kind,
+                start_index: index,
+                docs: vec![doc],
+                size,
+            }),
+        }
+    }
+
+    Ok(batches)
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandWriteError {
+    index: usize,
+    #[serde(default)]
+    code: i32,
+    #[serde(default)]
+    errmsg: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct CommandResult {
+    #[serde(default)]
+    n: u64,
+    #[serde(default)]
+    upserted: Vec<Document>,
+    #[serde(rename = "writeErrors", default)]
+    write_errors: Vec<CommandWriteError>,
+    #[serde(rename = "writeConcernError")]
+    write_concern_error: Option<Document>,
+}
+
+/**
+ * Perform an ordered bulk write against a collection.
+ * Consecutive operations of the same type are sent as a single command, so a flush of many documents needs only a few round trips.
+ * Commands are split to stay within the MongoDB limits on the number of operations and the size of a command.
+ * As the write is ordered, execution stops at the first failing operation. The failures are reported with the index of the operation that failed
+ * If a session is provided, the commands are run as part of it, so that they can be included in a transaction
+ */
+pub async fn bulk_write(
+    db: &Database,
+    collection_name: &str,
+    operations: Vec<MongoBulkWriteOperation<Document>>,
+    mut session: Option<&mut ClientSession>,
+) -> Result<MongoBulkWriteResult, JobError> {
+    let wrap_error = |err: mongodb::error::Error| {
+        let message = format!("bulk write failed for \"{}\": {}", collection_name, err);
+        if err.contains_label(TRANSIENT_TRANSACTION_ERROR) {
+            // The transaction can be retried
+            JobError::DatabaseTransient(message)
+        } else {
+            JobError::Database(message)
+        }
+    };
+
+    let batches =
+        build_batches(operations, MAX_BATCH_OPERATIONS, MAX_BATCH_BYTES).map_err(|err| {
+            JobError::Database(format!(
+                "bulk write failed for \"{}\": {}",
+                collection_name, err
+            ))
+        })?;
+
+    let mut result = MongoBulkWriteResult::default();
+
+    for CommandBatch {
+        kind,
+        start_index,
+        docs,
+        ..
+    } in batches
+    {
+        let command = doc! {
+            kind.command_name(): collection_name,
+            kind.field_name(): docs,
+            "ordered": true,
+        };
+
+        let res = match session.as_deref_mut() {
+            Some(session) => db.run_command_with_session(command, None, session).await,
+            None => db.run_command(command, None).await,
+        }
+        .map_err(wrap_error)?;
+        let res: CommandResult = from_document(res).map_err(|err| {
+            JobError::Database(format!(
+                "bulk write failed for \"{}\": bad response: {}",
+                collection_name, err
+            ))
+        })?;
+
+        if kind == CommandKind::Update {
+            // For updates, `n` includes any documents which were upserted
+            result.matched_count += res.n.saturating_sub(res.upserted.len() as u64);
+        }
+
+        if let Some(write_concern_error) = res.write_concern_error {
+            return Err(JobError::Database(format!(
+                "bulk write failed for \"{}\": write concern error: {}",
+                collection_name, write_concern_error
+            )));
+        }
+
+        if !res.write_errors.is_empty() {
+            result
+                .write_errors
+                .extend(res.write_errors.into_iter().map(|err| MongoBulkWriteError {
+                    index: start_index + err.index,
+                    code: err.code,
+                    message: err.errmsg,
+                }));
+
+            // The write is ordered, so nothing after this should be attempted
+            break;
+        }
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn insert(id: usize) -> MongoBulkWriteOperation<Document> {
+        MongoBulkWriteOperation::InsertOne(
+            doc! { "_id": format!("doc{}", id), "value": "x".repeat(100) },
+        )
+    }
+
+    fn remove(id: usize) -> MongoBulkWriteOperation<Document> {
+        MongoBulkWriteOperation::Remove(doc! { "_id": format!("doc{}", id) })
+    }
+
+    fn batch_shapes(batches: &[CommandBatch]) -> Vec<(&'static str, usize, usize)> {
+        batches
+            .iter()
+            .map(|b| (b.kind.command_name(), b.start_index, b.docs.len()))
+            .collect()
+    }
+
+    #[test]
+    fn group_consecutive_operations() {
+        let batches = build_batches(
+            vec![insert(0), insert(1), remove(2), remove(3), insert(4)],
+            MAX_BATCH_OPERATIONS,
+            MAX_BATCH_BYTES,
+        )
+        .unwrap();
+
+        assert_eq!(
+            batch_shapes(&batches),
+            vec![("insert", 0, 2), ("delete", 2, 2), ("insert", 4, 1)]
+        );
+    }
+
+    #[test]
+    fn split_by_operation_count() {
+        let batches = build_batches((0..7).map(insert).collect(), 3, MAX_BATCH_BYTES).unwrap();
+
+        assert_eq!(
+            batch_shapes(&batches),
+            vec![("insert", 0, 3), ("insert", 3, 3), ("insert", 6, 1)]
+        );
+    }
+
+    #[test]
+    fn split_by_size() {
+        let (_, doc) = convert_operation(insert(0));
+        let size = bson::to_vec(&doc).unwrap().len() + OPERATION_OVERHEAD_BYTES;
+
+        let batches =
+            build_batches((0..5).map(insert).collect(), MAX_BATCH_OPERATIONS, size * 2).unwrap();
+        assert_eq!(
+            batch_shapes(&batches),
+            vec![("insert", 0, 2), ("insert", 2, 2), ("insert", 4, 1)]
+        );
+
+        // An operation larger than the limit is still sent, on its own
+        let batches =
+            build_batches((0..2).map(insert).collect(), MAX_BATCH_OPERATIONS, size / 2).unwrap();
+        assert_eq!(
+            batch_shapes(&batches),
+            vec![("insert", 0, 1), ("insert", 1, 1)]
+        );
+    }
+}
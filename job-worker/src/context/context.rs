@@ -1,37 +1,83 @@
 use std::rc::Rc;
 
-use crate::data_model::{
-    ids::{ShowStyleBaseId, ShowStyleVariantId},
-    show_style_base::SourceLayers,
+use crate::{
+    data_model::{
+        ids::{ShowStyleBaseId, ShowStyleVariantId},
+        show_style_base::{OutputLayers, SourceLayers},
+    },
+    error::JobError,
+    events::EventsQueue,
+    playout::{autonext::AutonextScheduler, cache::CacheWriteMode, lock::PlaylistLockManager},
 };
 
-use super::direct_collections::{DirectCollections, MongoReadOnlyCollection};
+use super::direct_collections::DirectCollections;
 
 pub struct JobContext {
     //
     collections: Rc<DirectCollections>,
+    playlist_locks: Rc<PlaylistLockManager>,
+    autonext_scheduler: Rc<AutonextScheduler>,
+    events_queue: EventsQueue,
+    write_mode: CacheWriteMode,
 }
 impl JobContext {
-    pub fn create(collections: Rc<DirectCollections>) -> JobContext {
-        JobContext { collections }
+    pub fn create(
+        collections: Rc<DirectCollections>,
+        playlist_locks: Rc<PlaylistLockManager>,
+        autonext_scheduler: Rc<AutonextScheduler>,
+        events_queue: EventsQueue,
+        write_mode: CacheWriteMode,
+    ) -> JobContext {
+        JobContext {
+            collections,
+            playlist_locks,
+            autonext_scheduler,
+            events_queue,
+            write_mode,
+        }
     }
 
     pub fn direct_collections(&self) -> &DirectCollections {
         &self.collections
     }
+    pub fn direct_collections_rc(&self) -> &Rc<DirectCollections> {
+        &self.collections
+    }
+
+    pub fn playlist_locks(&self) -> &PlaylistLockManager {
+        &self.playlist_locks
+    }
+
+    pub fn autonext_scheduler(&self) -> &AutonextScheduler {
+        &self.autonext_scheduler
+    }
+
+    /**
+     * Queue for low priority jobs, to be run after the current job
+     */
+    pub fn events_queue(&self) -> &EventsQueue {
+        &self.events_queue
+    }
+
+    /**
+     * How caches should be written to the database
+     */
+    pub fn write_mode(&self) -> CacheWriteMode {
+        self.write_mode
+    }
 
     pub async fn get_show_style_compound(
         &self,
         _variant_id: &ShowStyleVariantId,
         base_id: &ShowStyleBaseId,
-    ) -> Result<Option<ShowStyleBase>, String> {
+    ) -> Result<Option<ShowStyleBase>, JobError> {
         // TODO - properly
         self.get_show_style_base(base_id).await
     }
     pub async fn get_show_style_base(
         &self,
         base_id: &ShowStyleBaseId,
-    ) -> Result<Option<ShowStyleBase>, String> {
+    ) -> Result<Option<ShowStyleBase>, JobError> {
         let db_show_style = self
             .collections
             .show_style_bases
@@ -41,6 +87,7 @@ impl JobContext {
         Ok(db_show_style.map(|show_style| ShowStyleBase {
                 id: show_style.id,
                 source_layers: show_style.source_layers_with_overrides.defaults, // TODO - respect overrides
+                output_layers: show_style.output_layers_with_overrides.defaults,
             }))
     }
 }
@@ -49,4 +96,5 @@ pub struct ShowStyleBase {
     pub id: ShowStyleBaseId,
 
     pub source_layers: SourceLayers,
+    pub output_layers: OutputLayers,
 }
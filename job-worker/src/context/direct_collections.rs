@@ -1,29 +1,63 @@
 use futures::future::LocalBoxFuture;
 use mongodb::{
-    bson::{doc, Document},
-    Collection, Database,
+    bson::{self, doc, Document},
+    error::{ErrorKind, WriteFailure},
+    options::{FindOptions as MongoFindOptions, ReplaceOptions},
+    Client, ClientSession, Collection, Database,
 };
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{hash::Hash, rc::Rc};
 
 use crate::{
     cache::doc::DocWithId,
     data_model::{
         ids::{
-            unprotect_array, PartId, PartInstanceId, PieceId, PieceInstanceId, ProtectedId,
-            RundownId, RundownPlaylistId, SegmentId, ShowStyleBaseId,
+            unprotect_array, PartId, PartInstanceId, PeripheralDeviceCommandId, PieceId,
+            PieceInstanceId, ProtectedId, RundownId, RundownPlaylistId, SegmentId, ShowStyleBaseId,
+            StudioId,
         },
         part::Part,
         part_instance::PartInstance,
+        peripheral_device_command::PeripheralDeviceCommand,
         piece::Piece,
         piece_instance::PieceInstance,
+        playlist_lock::PlaylistLock,
         rundown::Rundown,
         rundown_playlist::RundownPlaylist,
         segment::Segment,
         show_style_base::DBShowStyleBase,
+        timeline::TimelineComplete,
     },
+    error::JobError,
 };
 
+use super::{bulk_write::bulk_write, memory_collection::MemoryCollectionImpl};
+
+/** The error code reported by MongoDB when a write would duplicate a unique key, such as the `_id` */
+pub const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+/**
+ * Options for a find query. These mirror the subset of the MongoDB find options that we make use of
+ */
+#[derive(Debug, Clone, Default)]
+pub struct FindOptions {
+    /** Limit the fields returned. For the typed methods, any fields excluded must be optional in the document */
+    pub projection: Option<Document>,
+    pub sort: Option<Document>,
+    pub limit: Option<i64>,
+    pub skip: Option<u64>,
+}
+impl From<FindOptions> for MongoFindOptions {
+    fn from(options: FindOptions) -> Self {
+        MongoFindOptions::builder()
+            .projection(options.projection)
+            .sort(options.sort)
+            .limit(options.limit)
+            .skip(options.skip)
+            .build()
+    }
+}
+
 pub trait MongoReadOnlyCollection<
     Doc: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de>,
     Id: Clone + PartialEq + Eq + Hash,
@@ -34,24 +68,198 @@ pub trait MongoReadOnlyCollection<
     fn find_fetch_by_ids<'a>(
         &'a self,
         ids: &'a [Id],
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, String>>;
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, JobError>>;
     fn find_fetch<'a>(
         &'a self,
-        query: impl Into<Option<Document>> + 'a,
-        options: Option<String>,
-    ) -> LocalBoxFuture<Result<Vec<Doc>, String>>;
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, JobError>>;
+    /**
+     * Fetch the documents without converting them to the typed document.
+     * This is necessary when using a projection which excludes fields that the typed document requires
+     */
+    fn find_fetch_raw<'a>(
+        &'a self,
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Document>, JobError>>;
 
     fn find_one_by_id<'a>(
         &'a self,
         id: &'a Id,
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Option<Doc>, String>>;
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Option<Doc>, JobError>>;
     fn find_one<'a>(
         &'a self,
-        query: impl Into<Option<Document>> + 'a,
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Option<Doc>, String>>;
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Option<Doc>, JobError>>;
+}
+
+pub trait MongoWriteCollection<
+    Doc: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de>,
+    Id: Clone + PartialEq + Eq + Hash,
+>: MongoReadOnlyCollection<Doc, Id>
+{
+    /**
+     * Replace the document with the same id
+     * Returns the number of documents matched, which will be 0 if the document was inserted
+     */
+    fn replace_one<'a>(
+        &'a self,
+        doc: &'a Doc,
+        upsert: bool,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>>;
+
+    fn remove_by_ids<'a>(&'a self, ids: &'a [Id]) -> LocalBoxFuture<'a, Result<(), JobError>>;
+
+    /**
+     * Insert a new document. This fails with `JobError::DuplicateKey` if the id is already in use
+     */
+    fn insert_one<'a>(&'a self, doc: &'a Doc) -> LocalBoxFuture<'a, Result<(), JobError>>;
+    fn insert_many<'a>(&'a self, docs: &'a [Doc]) -> LocalBoxFuture<'a, Result<(), JobError>>;
+
+    /**
+     * Update the first document matching the query, using a modifier such as `{ $set: { ... } }`
+     * Returns the number of documents matched
+     */
+    fn update_one<'a>(
+        &'a self,
+        query: Document,
+        modifier: Document,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>>;
+    /**
+     * Update all documents matching the query, using a modifier such as `{ $set: { ... } }`
+     * Returns the number of documents matched
+     */
+    fn update_many<'a>(
+        &'a self,
+        query: Document,
+        modifier: Document,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>>;
+
+    /**
+     * Remove all documents matching the query
+     * Returns the number of documents removed
+     */
+    fn remove<'a>(&'a self, query: Document) -> LocalBoxFuture<'a, Result<u64, JobError>>;
+
+    /**
+     * Perform a series of write operations, in the order provided.
+     * Execution stops at the first operation to fail, with the failure reported in the result
+     */
+    fn bulk_write<'a>(
+        &'a self,
+        operations: Vec<MongoBulkWriteOperation<Doc>>,
+    ) -> LocalBoxFuture<'a, Result<MongoBulkWriteResult, JobError>>;
+    /**
+     * Perform a bulk write as part of a session, so that it can be included in a transaction
+     */
+    fn bulk_write_in_session<'a>(
+        &'a self,
+        operations: Vec<MongoBulkWriteOperation<Doc>>,
+        session: &'a mut ClientSession,
+    ) -> LocalBoxFuture<'a, Result<MongoBulkWriteResult, JobError>>;
+}
+
+/**
+ * Perform the operations of a bulk write one at a time, stopping at the first failure
+ */
+pub(crate) async fn bulk_write_sequentially<
+    Doc: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de>,
+    Id: Clone + PartialEq + Eq + Hash,
+>(
+    collection: &dyn MongoWriteCollection<Doc, Id>,
+    operations: Vec<MongoBulkWriteOperation<Doc>>,
+) -> Result<MongoBulkWriteResult, JobError> {
+    let mut result = MongoBulkWriteResult::default();
+
+    for (index, operation) in operations.into_iter().enumerate() {
+        let res = match operation {
+            MongoBulkWriteOperation::InsertOne(doc) => collection.insert_one(&doc).await.map(|_| 0),
+            MongoBulkWriteOperation::ReplaceOne { doc, upsert } => {
+                collection.replace_one(&doc, upsert).await
+            }
+            MongoBulkWriteOperation::UpdateOne { query, modifier } => {
+                collection.update_one(query, modifier).await
+            }
+            MongoBulkWriteOperation::UpdateMany { query, modifier } => {
+                collection.update_many(query, modifier).await
+            }
+            MongoBulkWriteOperation::Remove(query) => collection.remove(query).await.map(|_| 0),
+        };
+
+        match res {
+            Ok(matched) => result.matched_count += matched,
+            Err(err) => {
+                result.write_errors.push(MongoBulkWriteError {
+                    index,
+                    code: match err {
+                        JobError::DuplicateKey(_) => DUPLICATE_KEY_ERROR_CODE,
+                        _ => 0,
+                    },
+                    message: err.to_string(),
+                });
+                break;
+            }
+        }
+    }
+
+    Ok(result)
+}
+
+pub enum MongoBulkWriteOperation<Doc> {
+    InsertOne(Doc),
+    ReplaceOne { doc: Doc, upsert: bool },
+    UpdateOne { query: Document, modifier: Document },
+    UpdateMany { query: Document, modifier: Document },
+    Remove(Document),
+}
+impl<Doc: Serialize> MongoBulkWriteOperation<Doc> {
+    fn into_document(self) -> Result<MongoBulkWriteOperation<Document>, bson::ser::Error> {
+        Ok(match self {
+            MongoBulkWriteOperation::InsertOne(doc) => {
+                MongoBulkWriteOperation::InsertOne(bson::to_document(&doc)?)
+            }
+            MongoBulkWriteOperation::ReplaceOne { doc, upsert } => {
+                MongoBulkWriteOperation::ReplaceOne {
+                    doc: bson::to_document(&doc)?,
+                    upsert,
+                }
+            }
+            MongoBulkWriteOperation::UpdateOne { query, modifier } => {
+                MongoBulkWriteOperation::UpdateOne { query, modifier }
+            }
+            MongoBulkWriteOperation::UpdateMany { query, modifier } => {
+                MongoBulkWriteOperation::UpdateMany { query, modifier }
+            }
+            MongoBulkWriteOperation::Remove(query) => MongoBulkWriteOperation::Remove(query),
+        })
+    }
+}
+
+/**
+ * The failure of a single operation in a bulk write
+ */
+#[derive(Debug, Clone)]
+pub struct MongoBulkWriteError {
+    /** Index of the operation which failed */
+    pub index: usize,
+    pub code: i32,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct MongoBulkWriteResult {
+    /** The number of documents matched by the update and replace operations */
+    pub matched_count: u64,
+    pub write_errors: Vec<MongoBulkWriteError>,
+}
+impl MongoBulkWriteResult {
+    pub fn is_ok(&self) -> bool {
+        self.write_errors.is_empty()
+    }
 }
 
 // pub trait MongoTransform<TLocal, TMongo> {
@@ -67,7 +275,8 @@ pub struct MongoCollectionImpl<
 
     ai: Option<Id>,
 
-    pub collection: Collection<Doc>,
+    db: Database,
+    collection: Collection<Doc>,
 }
 impl<
         Doc: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de>,
@@ -82,13 +291,35 @@ impl<
 
             ai: None,
 
+            db: db.clone(),
             collection,
         }
     }
 
+    async fn bulk_write_inner(
+        &self,
+        operations: Vec<MongoBulkWriteOperation<Doc>>,
+        session: Option<&mut ClientSession>,
+    ) -> Result<MongoBulkWriteResult, JobError>
+    where
+        Doc: Serialize,
+    {
+        let operations = operations
+            .into_iter()
+            .map(|op| op.into_document())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| {
+                JobError::Database(format!("bulk write failed for \"{}\": {}", &self.name, err))
+            })?;
+
+        bulk_write(&self.db, &self.name, operations, session).await
+    }
+
     #[inline]
-    pub fn wrap_mongodb_error<T>(&self, value: mongodb::error::Result<T>) -> Result<T, String> {
-        value.map_err(|err| format!("query failed for \"{}\": {}", &self.name, err))
+    pub fn wrap_mongodb_error<T>(&self, value: mongodb::error::Result<T>) -> Result<T, JobError> {
+        value.map_err(|err| {
+            JobError::Database(format!("query failed for \"{}\": {}", &self.name, err))
+        })
     }
 }
 impl<
@@ -103,22 +334,22 @@ impl<
     fn find_fetch_by_ids<'a>(
         &'a self,
         ids: &'a [Id],
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, String>> {
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, JobError>> {
         self.find_fetch(doc! { "_id": { "$in": unprotect_array(ids)} }, options)
     }
 
     fn find_fetch<'a>(
         &'a self,
-        query: impl Into<Option<Document>> + 'a,
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, String>> {
-        if options.is_some() {
-            unimplemented!()
-        }
-
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, JobError>> {
         Box::pin(async move {
-            let mut cursor = self.wrap_mongodb_error(self.collection.find(query, None).await)?;
+            let mut cursor = self.wrap_mongodb_error(
+                self.collection
+                    .find(query, options.map(MongoFindOptions::from))
+                    .await,
+            )?;
 
             // TODO - use try_collect() or try_stream() once that is possible without making the docs Send+Sync
 
@@ -132,25 +363,53 @@ impl<
         })
     }
 
+    fn find_fetch_raw<'a>(
+        &'a self,
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Document>, JobError>> {
+        Box::pin(async move {
+            let mut cursor = self.wrap_mongodb_error(
+                self.collection
+                    .clone_with_type::<Document>()
+                    .find(query, options.map(MongoFindOptions::from))
+                    .await,
+            )?;
+
+            let mut docs = vec![];
+
+            while self.wrap_mongodb_error(cursor.advance().await)? {
+                docs.push(self.wrap_mongodb_error(cursor.deserialize_current())?);
+            }
+
+            Ok(docs)
+        })
+    }
+
     fn find_one_by_id<'a>(
         &'a self,
         id: &'a Id,
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Option<Doc>, String>> {
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Option<Doc>, JobError>> {
         self.find_one(doc! { "_id": id.unprotect() }, options)
     }
 
     fn find_one<'a>(
         &'a self,
-        query: impl Into<Option<Document>> + 'a,
-        options: Option<String>,
-    ) -> LocalBoxFuture<'a, Result<Option<Doc>, String>> {
-        if options.is_some() {
-            unimplemented!()
-        }
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Option<Doc>, JobError>> {
+        let options = FindOptions {
+            limit: Some(1),
+            ..options.unwrap_or_default()
+        };
 
         Box::pin(async move {
-            let mut cursor = self.wrap_mongodb_error(self.collection.find(query, None).await)?;
+            let mut cursor = self.wrap_mongodb_error(
+                self.collection
+                    .find(query, MongoFindOptions::from(options))
+                    .await,
+            )?;
 
             if self.wrap_mongodb_error(cursor.advance().await)? {
                 let doc = self.wrap_mongodb_error(cursor.deserialize_current())?;
@@ -161,8 +420,131 @@ impl<
         })
     }
 }
+impl<
+        Doc: for<'b> DocWithId<'b, Id> + for<'de> Deserialize<'de> + Serialize,
+        Id: Clone + PartialEq + Eq + Hash + ProtectedId,
+    > MongoWriteCollection<Doc, Id> for MongoCollectionImpl<Doc, Id>
+{
+    fn replace_one<'a>(
+        &'a self,
+        doc: &'a Doc,
+        upsert: bool,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move {
+            let options = ReplaceOptions::builder().upsert(upsert).build();
+
+            let res = self
+                .collection
+                .replace_one(doc! {"_id": doc.doc_id().unprotect() }, doc, options)
+                .await;
+
+            self.wrap_mongodb_error(res).map(|res| res.matched_count)
+        })
+    }
+
+    fn remove_by_ids<'a>(&'a self, ids: &'a [Id]) -> LocalBoxFuture<'a, Result<(), JobError>> {
+        Box::pin(async move {
+            let res = self
+                .collection
+                .delete_many(doc! { "_id": { "$in": unprotect_array(ids)} }, None)
+                .await;
+
+            self.wrap_mongodb_error(res).map(|_| ())
+        })
+    }
+
+    fn insert_one<'a>(&'a self, doc: &'a Doc) -> LocalBoxFuture<'a, Result<(), JobError>> {
+        Box::pin(async move {
+            match self.collection.insert_one(doc, None).await {
+                Err(err) if is_duplicate_key_error(&err) => Err(JobError::DuplicateKey(format!(
+                    "insert failed for \"{}\": {}",
+                    &self.name, err
+                ))),
+                res => self.wrap_mongodb_error(res).map(|_| ()),
+            }
+        })
+    }
+
+    fn insert_many<'a>(&'a self, docs: &'a [Doc]) -> LocalBoxFuture<'a, Result<(), JobError>> {
+        Box::pin(async move {
+            if docs.is_empty() {
+                return Ok(());
+            }
+
+            match self.collection.insert_many(docs, None).await {
+                Err(err) if is_duplicate_key_error(&err) => Err(JobError::DuplicateKey(format!(
+                    "insert failed for \"{}\": {}",
+                    &self.name, err
+                ))),
+                res => self.wrap_mongodb_error(res).map(|_| ()),
+            }
+        })
+    }
+
+    fn update_one<'a>(
+        &'a self,
+        query: Document,
+        modifier: Document,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move {
+            let res = self.collection.update_one(query, modifier, None).await;
+
+            self.wrap_mongodb_error(res).map(|res| res.matched_count)
+        })
+    }
+
+    fn update_many<'a>(
+        &'a self,
+        query: Document,
+        modifier: Document,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move {
+            let res = self.collection.update_many(query, modifier, None).await;
+
+            self.wrap_mongodb_error(res).map(|res| res.matched_count)
+        })
+    }
+
+    fn remove<'a>(&'a self, query: Document) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move {
+            let res = self.collection.delete_many(query, None).await;
+
+            self.wrap_mongodb_error(res).map(|res| res.deleted_count)
+        })
+    }
+
+    fn bulk_write<'a>(
+        &'a self,
+        operations: Vec<MongoBulkWriteOperation<Doc>>,
+    ) -> LocalBoxFuture<'a, Result<MongoBulkWriteResult, JobError>> {
+        Box::pin(self.bulk_write_inner(operations, None))
+    }
+
+    fn bulk_write_in_session<'a>(
+        &'a self,
+        operations: Vec<MongoBulkWriteOperation<Doc>>,
+        session: &'a mut ClientSession,
+    ) -> LocalBoxFuture<'a, Result<MongoBulkWriteResult, JobError>> {
+        Box::pin(self.bulk_write_inner(operations, Some(session)))
+    }
+}
+
+fn is_duplicate_key_error(err: &mongodb::error::Error) -> bool {
+    match err.kind.as_ref() {
+        ErrorKind::Write(WriteFailure::WriteError(err)) => err.code == DUPLICATE_KEY_ERROR_CODE,
+        // insert_many reports the failure of each document separately
+        ErrorKind::BulkWrite(failure) => failure
+            .write_errors
+            .iter()
+            .flatten()
+            .any(|err| err.code == DUPLICATE_KEY_ERROR_CODE),
+        _ => false,
+    }
+}
 
 pub struct DirectCollections {
+    client: Option<Client>,
+
     // AdLibActions: ICollection<AdLibAction>
     // AdLibPieces: ICollection<AdLibPiece>
     // Blueprints: ICollection<Blueprint>
@@ -171,23 +553,24 @@ pub struct DirectCollections {
     // ExpectedMediaItems: ICollection<ExpectedMediaItem>
     // ExpectedPlayoutItems: ICollection<ExpectedPlayoutItem>
     // IngestDataCache: ICollection<IngestDataCacheObj>
-    pub parts: MongoCollectionImpl<Part, PartId>,
-    pub part_instances: MongoCollectionImpl<PartInstance, PartInstanceId>,
+    pub parts: Box<dyn MongoWriteCollection<Part, PartId>>,
+    pub part_instances: Box<dyn MongoWriteCollection<PartInstance, PartInstanceId>>,
     // PeripheralDevices: ICollection<PeripheralDevice>
-    // PeripheralDeviceCommands: ICollection<PeripheralDeviceCommand>
-    pub pieces: MongoCollectionImpl<Piece, PieceId>,
-    pub piece_instances: MongoCollectionImpl<PieceInstance, PieceInstanceId>,
-    pub rundowns: MongoCollectionImpl<Rundown, RundownId>,
+    pub peripheral_device_commands:
+        Box<dyn MongoWriteCollection<PeripheralDeviceCommand, PeripheralDeviceCommandId>>,
+    pub pieces: Box<dyn MongoWriteCollection<Piece, PieceId>>,
+    pub piece_instances: Box<dyn MongoWriteCollection<PieceInstance, PieceInstanceId>>,
+    pub rundowns: Box<dyn MongoWriteCollection<Rundown, RundownId>>,
     // RundownBaselineAdLibActions: ICollection<RundownBaselineAdLibAction>
     // RundownBaselineAdLibPieces: ICollection<RundownBaselineAdLibItem>
     // RundownBaselineObjects: ICollection<RundownBaselineObj>
-    pub rundown_playlists: MongoCollectionImpl<RundownPlaylist, RundownPlaylistId>,
-    pub segments: MongoCollectionImpl<Segment, SegmentId>,
-    pub show_style_bases: MongoCollectionImpl<DBShowStyleBase, ShowStyleBaseId>,
+    pub rundown_playlists: Box<dyn MongoWriteCollection<RundownPlaylist, RundownPlaylistId>>,
+    pub segments: Box<dyn MongoWriteCollection<Segment, SegmentId>>,
+    pub show_style_bases: Box<dyn MongoReadOnlyCollection<DBShowStyleBase, ShowStyleBaseId>>,
+    pub playlist_locks: Box<dyn MongoWriteCollection<PlaylistLock, RundownPlaylistId>>,
     // ShowStyleVariants: ICollection<DBShowStyleVariant>
     // Studios: ICollection<DBStudio>
-    // Timelines: ICollection<TimelineComplete>
-
+    pub timelines: Box<dyn MongoWriteCollection<TimelineComplete, StudioId>>,
     // ExpectedPackages: ICollection<ExpectedPackageDB>
     // PackageInfos: ICollection<PackageInfoDB>
 
@@ -196,16 +579,114 @@ pub struct DirectCollections {
     // MediaObjects: ICollection<MediaObjects>
 }
 impl DirectCollections {
-    pub fn create(db: &Database) -> Rc<DirectCollections> {
+    pub fn create(client: &Client, database_name: &str) -> Rc<DirectCollections> {
+        let db = &client.database(database_name);
+
         Rc::new(DirectCollections {
-            parts: MongoCollectionImpl::create(db, "parts"),
-            part_instances: MongoCollectionImpl::create(db, "partInstances"),
-            pieces: MongoCollectionImpl::create(db, "pieces"),
-            piece_instances: MongoCollectionImpl::create(db, "pieceInstances"),
-            rundowns: MongoCollectionImpl::create(db, "rundowns"),
-            rundown_playlists: MongoCollectionImpl::create(db, "rundownPlaylists"),
-            segments: MongoCollectionImpl::create(db, "segments"),
-            show_style_bases: MongoCollectionImpl::create(db, "showStyleBases"),
+            client: Some(client.clone()),
+
+            parts: Box::new(MongoCollectionImpl::create(db, "parts")),
+            part_instances: Box::new(MongoCollectionImpl::create(db, "partInstances")),
+            peripheral_device_commands: Box::new(MongoCollectionImpl::create(
+                db,
+                "peripheralDeviceCommands",
+            )),
+            pieces: Box::new(MongoCollectionImpl::create(db, "pieces")),
+            piece_instances: Box::new(MongoCollectionImpl::create(db, "pieceInstances")),
+            rundowns: Box::new(MongoCollectionImpl::create(db, "rundowns")),
+            rundown_playlists: Box::new(MongoCollectionImpl::create(db, "rundownPlaylists")),
+            segments: Box::new(MongoCollectionImpl::create(db, "segments")),
+            show_style_bases: Box::new(MongoCollectionImpl::create(db, "showStyleBases")),
+            playlist_locks: Box::new(MongoCollectionImpl::create(db, "workerPlaylistLocks")),
+            timelines: Box::new(MongoCollectionImpl::create(db, "timeline")),
         })
     }
+
+    /**
+     * Create a set of collections which are held in memory, populated with the provided documents.
+     * This allows for running jobs without a MongoDB instance, such as in tests
+     */
+    pub fn create_in_memory(
+        data: InMemoryCollectionsData,
+    ) -> Result<Rc<DirectCollections>, JobError> {
+        Ok(Rc::new(DirectCollections {
+            client: None,
+
+            parts: Box::new(MemoryCollectionImpl::from_documents("parts", &data.parts)?),
+            part_instances: Box::new(MemoryCollectionImpl::from_documents(
+                "partInstances",
+                &data.part_instances,
+            )?),
+            peripheral_device_commands: Box::new(MemoryCollectionImpl::create(
+                "peripheralDeviceCommands",
+            )),
+            pieces: Box::new(MemoryCollectionImpl::from_documents(
+                "pieces",
+                &data.pieces,
+            )?),
+            piece_instances: Box::new(MemoryCollectionImpl::from_documents(
+                "pieceInstances",
+                &data.piece_instances,
+            )?),
+            rundowns: Box::new(MemoryCollectionImpl::from_documents(
+                "rundowns",
+                &data.rundowns,
+            )?),
+            rundown_playlists: Box::new(MemoryCollectionImpl::from_documents(
+                "rundownPlaylists",
+                &data.rundown_playlists,
+            )?),
+            segments: Box::new(MemoryCollectionImpl::from_documents(
+                "segments",
+                &data.segments,
+            )?),
+            show_style_bases: Box::new(MemoryCollectionImpl::from_documents(
+                "showStyleBases",
+                &data.show_style_bases,
+            )?),
+            playlist_locks: Box::new(MemoryCollectionImpl::create("workerPlaylistLocks")),
+            timelines: Box::new(MemoryCollectionImpl::create("timeline")),
+        }))
+    }
+
+    /**
+     * The client to the database, if these collections are backed by one
+     */
+    pub fn client(&self) -> Option<&Client> {
+        self.client.as_ref()
+    }
+
+    /**
+     * Check whether the database supports transactions. This requires the database to be a replica set or a sharded cluster
+     */
+    pub async fn supports_transactions(&self) -> Result<bool, JobError> {
+        if let Some(client) = &self.client {
+            let res = client
+                .database("admin")
+                .run_command(doc! { "hello": 1 }, None)
+                .await
+                .map_err(|err| {
+                    JobError::Database(format!("Failed to check database topology: {}", err))
+                })?;
+
+            Ok(res.contains_key("setName") || res.get_str("msg") == Ok("isdbgrid"))
+        } else {
+            Ok(false)
+        }
+    }
+}
+
+/**
+ * The documents to populate the collections with, for `DirectCollections::create_in_memory`
+ */
+#[derive(Default)]
+pub struct InMemoryCollectionsData {
+    pub parts: Vec<Part>,
+    pub part_instances: Vec<PartInstance>,
+    pub pieces: Vec<Piece>,
+    pub piece_instances: Vec<PieceInstance>,
+    pub rundowns: Vec<Rundown>,
+    pub rundown_playlists: Vec<RundownPlaylist>,
+    pub segments: Vec<Segment>,
+    pub show_style_bases: Vec<DBShowStyleBase>,
 }
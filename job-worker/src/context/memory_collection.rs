@@ -0,0 +1,839 @@
+use futures::future::LocalBoxFuture;
+use mongodb::{
+    bson::{self, doc, Bson, Document},
+    ClientSession,
+};
+use serde::{Deserialize, Serialize};
+use std::{cell::RefCell, cmp::Ordering, hash::Hash, marker::PhantomData};
+
+use crate::{
+    cache::doc::DocWithId,
+    data_model::ids::{unprotect_array, ProtectedId},
+    error::JobError,
+};
+
+use super::{
+    direct_collections::{
+        bulk_write_sequentially, FindOptions, MongoBulkWriteOperation, MongoBulkWriteResult,
+        MongoReadOnlyCollection, MongoWriteCollection,
+    },
+    mongo_update::mongo_update,
+    mongo_where::{compare_bson, lookup_path, mongo_where},
+};
+
+/**
+ * A collection which is held in memory instead of in MongoDB.
+ * Documents are stored in their bson form, so that queries can be evaluated against them in the same way as MongoDB would
+ */
+pub struct MemoryCollectionImpl<
+    Doc: for<'b> DocWithId<'b, Id> + for<'de> Deserialize<'de>,
+    Id: Clone + PartialEq + Eq + Hash,
+> {
+    name: String,
+
+    documents: RefCell<Vec<Document>>,
+
+    _phantom: PhantomData<(Doc, Id)>,
+}
+impl<
+        Doc: for<'a> DocWithId<'a, Id> + for<'de> Deserialize<'de> + Serialize,
+        Id: Clone + PartialEq + Eq + Hash + ProtectedId,
+    > MemoryCollectionImpl<Doc, Id>
+{
+    pub fn create(name: &str) -> MemoryCollectionImpl<Doc, Id> {
+        MemoryCollectionImpl {
+            name: name.to_string(),
+
+            documents: RefCell::new(Vec::new()),
+
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn from_documents(
+        name: &str,
+        docs: &[Doc],
+    ) -> Result<MemoryCollectionImpl<Doc, Id>, JobError> {
+        let collection = MemoryCollectionImpl::create(name);
+
+        {
+            let mut documents = collection.documents.borrow_mut();
+            for doc in docs {
+                documents.push(collection.serialize(doc)?);
+            }
+        }
+
+        Ok(collection)
+    }
+
+    fn serialize(&self, doc: &Doc) -> Result<Document, JobError> {
+        bson::to_document(doc).map_err(|err| {
+            JobError::Database(format!("serialize failed for \"{}\": {}", &self.name, err))
+        })
+    }
+
+    fn deserialize(&self, doc: &Document) -> Result<Doc, JobError> {
+        bson::from_document(doc.clone()).map_err(|err| {
+            JobError::Database(format!(
+                "deserialize failed for \"{}\": {}",
+                &self.name, err
+            ))
+        })
+    }
+
+    fn wrap_query_error<T>(&self, value: Result<T, String>) -> Result<T, JobError> {
+        value.map_err(|err| {
+            JobError::Database(format!("query failed for \"{}\": {}", &self.name, err))
+        })
+    }
+
+    fn find_matching(
+        &self,
+        query: &Document,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Document>, JobError> {
+        let options = options.unwrap_or_default();
+        let documents = self.documents.borrow();
+
+        let mut result = Vec::new();
+        for doc in documents.iter() {
+            if self.wrap_query_error(mongo_where(doc, query))? {
+                result.push(doc);
+            }
+        }
+
+        if let Some(sort) = &options.sort {
+            result.sort_by(|a, b| compare_for_sort(a, b, sort));
+        }
+
+        let skip = options.skip.unwrap_or(0) as usize;
+        let limit = match options.limit {
+            // Like mongo, a negative limit is treated the same as a positive one
+            Some(limit) if limit != 0 => limit.unsigned_abs() as usize,
+            _ => usize::MAX,
+        };
+
+        result
+            .into_iter()
+            .skip(skip)
+            .take(limit)
+            .map(|doc| match &options.projection {
+                Some(projection) => self.wrap_query_error(apply_projection(doc, projection)),
+                None => Ok(doc.clone()),
+            })
+            .collect()
+    }
+
+    fn update_matching(
+        &self,
+        query: &Document,
+        modifier: &Document,
+        limit: Option<usize>,
+    ) -> Result<u64, JobError> {
+        let mut documents = self.documents.borrow_mut();
+
+        // Compute every update before applying any, so that an error leaves the collection untouched
+        let mut updates = Vec::new();
+        for (index, doc) in documents.iter().enumerate() {
+            if limit.map_or(false, |limit| updates.len() >= limit) {
+                break;
+            }
+
+            if self.wrap_query_error(mongo_where(doc, query))? {
+                let mut new_doc = doc.clone();
+                mongo_update(&mut new_doc, modifier).map_err(|err| {
+                    JobError::Database(format!("update failed for \"{}\": {}", &self.name, err))
+                })?;
+                updates.push((index, new_doc));
+            }
+        }
+
+        let matched = updates.len();
+        for (index, new_doc) in updates {
+            documents[index] = new_doc;
+        }
+
+        Ok(matched as u64)
+    }
+
+    fn find_matching_typed(
+        &self,
+        query: &Document,
+        options: Option<FindOptions>,
+    ) -> Result<Vec<Doc>, JobError> {
+        self.find_matching(query, options)?
+            .iter()
+            .map(|doc| self.deserialize(doc))
+            .collect()
+    }
+}
+
+fn get_sort_value<'a>(doc: &'a Document, path: &str) -> Option<&'a Bson> {
+    let mut values = Vec::new();
+    lookup_path(doc, &path.split('.').collect::<Vec<_>>(), &mut values);
+    values.into_iter().next()
+}
+
+fn compare_for_sort(a: &Document, b: &Document, sort: &Document) -> Ordering {
+    for (path, direction) in sort {
+        let ordering = match (get_sort_value(a, path), get_sort_value(b, path)) {
+            (None, None) => Ordering::Equal,
+            // Missing values sort before anything else
+            (None, Some(_)) => Ordering::Less,
+            (Some(_), None) => Ordering::Greater,
+            (Some(a), Some(b)) => compare_bson(a, b).unwrap_or(Ordering::Equal),
+        };
+
+        let descending = matches!(direction, Bson::Int32(v) if *v < 0)
+            || matches!(direction, Bson::Int64(v) if *v < 0)
+            || matches!(direction, Bson::Double(v) if *v < 0.0);
+
+        let ordering = if descending {
+            ordering.reverse()
+        } else {
+            ordering
+        };
+
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+
+    Ordering::Equal
+}
+
+fn is_projection_inclusion(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(v) => *v,
+        Bson::Int32(v) => *v != 0,
+        Bson::Int64(v) => *v != 0,
+        Bson::Double(v) => *v != 0.0,
+        _ => true,
+    }
+}
+
+/**
+ * Apply a mongo style projection to a document.
+ * Supports either an inclusion or an exclusion of fields, optionally with `_id` excluded
+ */
+fn apply_projection(doc: &Document, projection: &Document) -> Result<Document, String> {
+    let mut include_id = true;
+    let mut inclusive = None;
+    for (key, value) in projection {
+        let include = is_projection_inclusion(value);
+        if key == "_id" {
+            include_id = include;
+        } else if inclusive.map_or(false, |inclusive| inclusive != include) {
+            return Err("Projection can't mix inclusion and exclusion".to_string());
+        } else {
+            inclusive = Some(include);
+        }
+    }
+
+    let mut result = if inclusive.unwrap_or(false) {
+        let mut result = Document::new();
+        for (key, value) in projection {
+            if key != "_id" && is_projection_inclusion(value) {
+                copy_path(doc, &mut result, &key.split('.').collect::<Vec<_>>());
+            }
+        }
+        if include_id {
+            if let Some(id) = doc.get("_id") {
+                result.insert("_id", id.clone());
+            }
+        }
+        result
+    } else {
+        let mut result = doc.clone();
+        for (key, _) in projection {
+            if key != "_id" {
+                remove_path(&mut result, &key.split('.').collect::<Vec<_>>());
+            }
+        }
+        result
+    };
+
+    if !include_id {
+        result.remove("_id");
+    }
+
+    Ok(result)
+}
+
+fn copy_path(source: &Document, target: &mut Document, path: &[&str]) {
+    if let Some((first, rest)) = path.split_first() {
+        if let Some(value) = source.get(first) {
+            if rest.is_empty() {
+                target.insert(*first, value.clone());
+            } else if let Bson::Document(source_child) = value {
+                if !matches!(target.get(first), Some(Bson::Document(_))) {
+                    target.insert(*first, Document::new());
+                }
+                if let Ok(target_child) = target.get_document_mut(first) {
+                    copy_path(source_child, target_child, rest);
+                }
+            }
+        }
+    }
+}
+
+fn remove_path(target: &mut Document, path: &[&str]) {
+    if let Some((first, rest)) = path.split_first() {
+        if rest.is_empty() {
+            target.remove(first);
+        } else if let Ok(child) = target.get_document_mut(first) {
+            remove_path(child, rest);
+        }
+    }
+}
+impl<
+        Doc: for<'b> DocWithId<'b, Id> + for<'de> Deserialize<'de> + Serialize,
+        Id: Clone + PartialEq + Eq + Hash + ProtectedId,
+    > MongoReadOnlyCollection<Doc, Id> for MemoryCollectionImpl<Doc, Id>
+{
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn find_fetch_by_ids<'a>(
+        &'a self,
+        ids: &'a [Id],
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, JobError>> {
+        self.find_fetch(doc! { "_id": { "$in": unprotect_array(ids)} }, options)
+    }
+
+    fn find_fetch<'a>(
+        &'a self,
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Doc>, JobError>> {
+        Box::pin(async move { self.find_matching_typed(&query, options) })
+    }
+
+    fn find_fetch_raw<'a>(
+        &'a self,
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Vec<Document>, JobError>> {
+        Box::pin(async move { self.find_matching(&query, options) })
+    }
+
+    fn find_one_by_id<'a>(
+        &'a self,
+        id: &'a Id,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Option<Doc>, JobError>> {
+        self.find_one(doc! { "_id": id.unprotect() }, options)
+    }
+
+    fn find_one<'a>(
+        &'a self,
+        query: Document,
+        options: Option<FindOptions>,
+    ) -> LocalBoxFuture<'a, Result<Option<Doc>, JobError>> {
+        let options = FindOptions {
+            limit: Some(1),
+            ..options.unwrap_or_default()
+        };
+
+        Box::pin(async move {
+            Ok(self
+                .find_matching_typed(&query, Some(options))?
+                .into_iter()
+                .next())
+        })
+    }
+}
+impl<
+        Doc: for<'b> DocWithId<'b, Id> + for<'de> Deserialize<'de> + Serialize,
+        Id: Clone + PartialEq + Eq + Hash + ProtectedId,
+    > MongoWriteCollection<Doc, Id> for MemoryCollectionImpl<Doc, Id>
+{
+    fn replace_one<'a>(
+        &'a self,
+        doc: &'a Doc,
+        upsert: bool,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move {
+            let new_doc = self.serialize(doc)?;
+            let id = Bson::String(doc.doc_id().unprotect().to_string());
+
+            let mut documents = self.documents.borrow_mut();
+            if let Some(existing) = documents.iter_mut().find(|d| d.get("_id") == Some(&id)) {
+                *existing = new_doc;
+                Ok(1)
+            } else {
+                if upsert {
+                    documents.push(new_doc);
+                }
+                Ok(0)
+            }
+        })
+    }
+
+    fn remove_by_ids<'a>(&'a self, ids: &'a [Id]) -> LocalBoxFuture<'a, Result<(), JobError>> {
+        Box::pin(async move {
+            let ids = unprotect_array(ids);
+
+            self.documents.borrow_mut().retain(|d| match d.get("_id") {
+                Some(Bson::String(id)) => !ids.iter().any(|i| i == id),
+                _ => true,
+            });
+
+            Ok(())
+        })
+    }
+
+    fn insert_one<'a>(&'a self, doc: &'a Doc) -> LocalBoxFuture<'a, Result<(), JobError>> {
+        Box::pin(async move {
+            let new_doc = self.serialize(doc)?;
+            let id = Bson::String(doc.doc_id().unprotect().to_string());
+
+            let mut documents = self.documents.borrow_mut();
+            if documents.iter().any(|d| d.get("_id") == Some(&id)) {
+                return Err(JobError::DuplicateKey(format!(
+                    "insert failed for \"{}\": duplicate _id \"{}\"",
+                    &self.name,
+                    doc.doc_id().unprotect()
+                )));
+            }
+
+            documents.push(new_doc);
+
+            Ok(())
+        })
+    }
+
+    fn insert_many<'a>(&'a self, docs: &'a [Doc]) -> LocalBoxFuture<'a, Result<(), JobError>> {
+        Box::pin(async move {
+            for doc in docs {
+                self.insert_one(doc).await?;
+            }
+
+            Ok(())
+        })
+    }
+
+    fn update_one<'a>(
+        &'a self,
+        query: Document,
+        modifier: Document,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move { self.update_matching(&query, &modifier, Some(1)) })
+    }
+
+    fn update_many<'a>(
+        &'a self,
+        query: Document,
+        modifier: Document,
+    ) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move { self.update_matching(&query, &modifier, None) })
+    }
+
+    fn remove<'a>(&'a self, query: Document) -> LocalBoxFuture<'a, Result<u64, JobError>> {
+        Box::pin(async move {
+            let mut documents = self.documents.borrow_mut();
+
+            // Evaluate the query against everything first, so that an error leaves the collection untouched
+            let matched = documents
+                .iter()
+                .map(|doc| self.wrap_query_error(mongo_where(doc, &query)))
+                .collect::<Result<Vec<bool>, _>>()?;
+            let removed = matched.iter().filter(|m| **m).count();
+
+            let mut matched = matched.into_iter();
+            documents.retain(|_| !matched.next().unwrap_or(false));
+
+            Ok(removed as u64)
+        })
+    }
+
+    fn bulk_write<'a>(
+        &'a self,
+        operations: Vec<MongoBulkWriteOperation<Doc>>,
+    ) -> LocalBoxFuture<'a, Result<MongoBulkWriteResult, JobError>> {
+        Box::pin(bulk_write_sequentially(self, operations))
+    }
+
+    fn bulk_write_in_session<'a>(
+        &'a self,
+        _operations: Vec<MongoBulkWriteOperation<Doc>>,
+        _session: &'a mut ClientSession,
+    ) -> LocalBoxFuture<'a, Result<MongoBulkWriteResult, JobError>> {
+        Box::pin(async move {
+            Err(JobError::Database(format!(
+                "bulk write failed for \"{}\": sessions are not supported in memory",
+                &self.name
+            )))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    use super::*;
+    use crate::data_model::{ids::SegmentId, segment::Segment};
+
+    fn create_segment(id: &str, rank: f32, name: &str) -> Segment {
+        serde_json::from_value(json!({
+            "_id": id,
+            "_rank": rank,
+            "rundownId": "rundown0",
+            "externalId": id,
+            "externalModified": 0,
+            "name": name,
+        }))
+        .unwrap()
+    }
+
+    fn create_collection() -> MemoryCollectionImpl<Segment, SegmentId> {
+        MemoryCollectionImpl::from_documents(
+            "segments",
+            &[
+                create_segment("segment0", 2.0, "b"),
+                create_segment("segment1", 1.0, "a"),
+                create_segment("segment2", 3.0, "a"),
+            ],
+        )
+        .unwrap()
+    }
+
+    fn segment_ids(segments: &[Segment]) -> Vec<&str> {
+        segments.iter().map(|s| s.id.unprotect()).collect()
+    }
+
+    #[test]
+    fn find_with_options() {
+        let collection = create_collection();
+
+        let cases: Vec<(&str, Document, Option<FindOptions>, Vec<&str>)> = vec![
+            (
+                "all",
+                doc! {},
+                None,
+                vec!["segment0", "segment1", "segment2"],
+            ),
+            (
+                "equality",
+                doc! { "name": "a" },
+                None,
+                vec!["segment1", "segment2"],
+            ),
+            (
+                "$in",
+                doc! { "_id": { "$in": ["segment2", "segment0", "missing"] } },
+                None,
+                vec!["segment0", "segment2"],
+            ),
+            (
+                "sort",
+                doc! {},
+                Some(FindOptions {
+                    sort: Some(doc! { "_rank": 1 }),
+                    ..Default::default()
+                }),
+                vec!["segment1", "segment0", "segment2"],
+            ),
+            (
+                "sort descending",
+                doc! {},
+                Some(FindOptions {
+                    sort: Some(doc! { "_rank": -1 }),
+                    ..Default::default()
+                }),
+                vec!["segment2", "segment0", "segment1"],
+            ),
+            (
+                "sort multiple fields",
+                doc! {},
+                Some(FindOptions {
+                    sort: Some(doc! { "name": 1, "_rank": -1 }),
+                    ..Default::default()
+                }),
+                vec!["segment2", "segment1", "segment0"],
+            ),
+            (
+                "skip and limit",
+                doc! {},
+                Some(FindOptions {
+                    sort: Some(doc! { "_rank": 1 }),
+                    skip: Some(1),
+                    limit: Some(1),
+                    ..Default::default()
+                }),
+                vec!["segment0"],
+            ),
+            (
+                "negative limit",
+                doc! {},
+                Some(FindOptions {
+                    limit: Some(-2),
+                    ..Default::default()
+                }),
+                vec!["segment0", "segment1"],
+            ),
+        ];
+
+        for (name, query, options, expected) in cases {
+            let found = block_on(collection.find_fetch(query, options)).unwrap();
+            assert_eq!(segment_ids(&found), expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn find_raw_with_projection() {
+        let collection = create_collection();
+
+        let cases: Vec<(&str, Document, Document)> = vec![
+            (
+                "inclusion",
+                doc! { "name": 1 },
+                doc! { "_id": "segment1", "name": "a" },
+            ),
+            (
+                "inclusion without _id",
+                doc! { "name": 1, "_id": 0 },
+                doc! { "name": "a" },
+            ),
+            (
+                "exclusion",
+                doc! { "externalId": 0, "externalModified": 0, "rundownId": 0 },
+                doc! { "_id": "segment1", "_rank": 1.0, "name": "a", "isHidden": false, "showShelf": false },
+            ),
+        ];
+
+        for (name, projection, expected) in cases {
+            let found = block_on(collection.find_fetch_raw(
+                doc! { "_id": "segment1" },
+                Some(FindOptions {
+                    projection: Some(projection),
+                    ..Default::default()
+                }),
+            ))
+            .unwrap();
+            assert_eq!(found, vec![expected], "{}", name);
+        }
+
+        assert!(block_on(collection.find_fetch_raw(
+            doc! {},
+            Some(FindOptions {
+                projection: Some(doc! { "name": 1, "rundownId": 0 }),
+                ..Default::default()
+            }),
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn find_typed_with_projection() {
+        let collection = create_collection();
+        block_on(collection.update_one(
+            doc! { "_id": "segment1" },
+            doc! { "$set": { "identifier": "id" } },
+        ))
+        .unwrap();
+
+        let found = block_on(collection.find_fetch(
+            doc! { "_id": "segment1" },
+            Some(FindOptions {
+                projection: Some(doc! { "identifier": 0 }),
+                ..Default::default()
+            }),
+        ))
+        .unwrap();
+        assert_eq!(segment_ids(&found), vec!["segment1"]);
+        assert_eq!(found[0].identifier, None);
+
+        // Like with mongo, excluding a required field fails to deserialize
+        assert!(block_on(collection.find_fetch(
+            doc! { "_id": "segment1" },
+            Some(FindOptions {
+                projection: Some(doc! { "name": 0 }),
+                ..Default::default()
+            }),
+        ))
+        .is_err());
+    }
+
+    #[test]
+    fn find_one() {
+        let collection = create_collection();
+
+        let found = block_on(collection.find_one(
+            doc! { "name": "a" },
+            Some(FindOptions {
+                sort: Some(doc! { "_rank": -1 }),
+                ..Default::default()
+            }),
+        ))
+        .unwrap();
+        assert_eq!(
+            found.map(|s| s.id),
+            Some(SegmentId::new_from("segment2".to_string()))
+        );
+
+        let found =
+            block_on(collection.find_one_by_id(&SegmentId::new_from("missing".to_string()), None))
+                .unwrap();
+        assert!(found.is_none());
+    }
+
+    #[test]
+    fn unsupported_query_is_an_error() {
+        let collection = create_collection();
+
+        assert!(block_on(collection.find_fetch(doc! { "name": { "$regex": "a" } }, None)).is_err());
+        assert!(block_on(
+            collection.update_many(doc! { "$where": "true" }, doc! { "$set": { "name": "c" } })
+        )
+        .is_err());
+        assert!(
+            block_on(collection.update_many(doc! {}, doc! { "$rename": { "name": "title" } }))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn update() {
+        let collection = create_collection();
+
+        let matched = block_on(collection.update_one(
+            doc! { "name": "a" },
+            doc! { "$set": { "name": "c", "identifier": "id" } },
+        ))
+        .unwrap();
+        assert_eq!(matched, 1);
+
+        let matched = block_on(collection.update_many(
+            doc! { "name": { "$ne": "c" } },
+            doc! { "$inc": { "_rank": 10 }, "$unset": { "identifier": 1 } },
+        ))
+        .unwrap();
+        assert_eq!(matched, 2);
+
+        let found = block_on(collection.find_fetch(
+            doc! {},
+            Some(FindOptions {
+                sort: Some(doc! { "_rank": 1 }),
+                ..Default::default()
+            }),
+        ))
+        .unwrap();
+        assert_eq!(
+            segment_ids(&found),
+            vec!["segment1", "segment0", "segment2"]
+        );
+        assert_eq!(found[0].name, "c");
+        assert_eq!(found[0].identifier.as_deref(), Some("id"));
+        assert_eq!(found[1].rank, 12.0);
+    }
+
+    #[test]
+    fn replace_and_upsert() {
+        let collection = create_collection();
+
+        let matched =
+            block_on(collection.replace_one(&create_segment("segment0", 5.0, "z"), false)).unwrap();
+        assert_eq!(matched, 1);
+
+        let matched =
+            block_on(collection.replace_one(&create_segment("segment3", 0.0, "y"), false)).unwrap();
+        assert_eq!(matched, 0);
+        assert!(block_on(
+            collection.find_one_by_id(&SegmentId::new_from("segment3".to_string()), None)
+        )
+        .unwrap()
+        .is_none());
+
+        let matched =
+            block_on(collection.replace_one(&create_segment("segment3", 0.0, "y"), true)).unwrap();
+        assert_eq!(matched, 0);
+
+        let found =
+            block_on(collection.find_fetch(doc! { "name": { "$in": ["y", "z"] } }, None)).unwrap();
+        assert_eq!(segment_ids(&found), vec!["segment0", "segment3"]);
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let collection = create_collection();
+
+        block_on(collection.insert_one(&create_segment("segment3", 4.0, "b"))).unwrap();
+        assert!(matches!(
+            block_on(collection.insert_one(&create_segment("segment3", 4.0, "b"))),
+            Err(JobError::DuplicateKey(_))
+        ));
+        assert!(matches!(
+            block_on(collection.insert_many(&[create_segment("segment3", 4.0, "b")])),
+            Err(JobError::DuplicateKey(_))
+        ));
+
+        let removed = block_on(collection.remove(doc! { "name": "b" })).unwrap();
+        assert_eq!(removed, 2);
+
+        block_on(collection.remove_by_ids(&[SegmentId::new_from("segment1".to_string())])).unwrap();
+
+        let found = block_on(collection.find_fetch(doc! {}, None)).unwrap();
+        assert_eq!(segment_ids(&found), vec!["segment2"]);
+    }
+
+    #[test]
+    fn failed_remove_keeps_documents() {
+        let collection = create_collection();
+
+        // The first document matches before the unsupported operator is reached for the second
+        let query = doc! { "$or": [{ "name": "b" }, { "identifier": { "$regex": "i" } }] };
+
+        assert!(block_on(collection.remove(query)).is_err());
+        assert_eq!(
+            block_on(collection.find_fetch(doc! {}, None))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+
+    #[test]
+    fn failed_update_keeps_documents() {
+        let collection = create_collection();
+
+        // The first document matches before the unsupported operator is reached for the second
+        let query = doc! { "$or": [{ "name": "b" }, { "identifier": { "$regex": "i" } }] };
+
+        assert!(block_on(collection.update_many(query, doc! { "$set": { "name": "c" } })).is_err());
+        assert!(block_on(collection.find_fetch(doc! { "name": "c" }, None))
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn bulk_write_stops_at_first_failure() {
+        let collection = create_collection();
+
+        let result = block_on(collection.bulk_write(vec![
+            MongoBulkWriteOperation::UpdateOne {
+                query: doc! { "_id": "segment0" },
+                modifier: doc! { "$set": { "name": "c" } },
+            },
+            MongoBulkWriteOperation::InsertOne(create_segment("segment1", 0.0, "d")),
+            MongoBulkWriteOperation::Remove(doc! {}),
+        ]))
+        .unwrap();
+        assert_eq!(result.matched_count, 1);
+        assert_eq!(result.write_errors.len(), 1);
+        assert_eq!(result.write_errors[0].index, 1);
+
+        let found = block_on(collection.find_fetch(doc! { "name": "c" }, None)).unwrap();
+        assert_eq!(segment_ids(&found), vec!["segment0"]);
+        assert_eq!(
+            block_on(collection.find_fetch(doc! {}, None))
+                .unwrap()
+                .len(),
+            3
+        );
+    }
+}
@@ -1,3 +1,6 @@
-// pub mod bulk_update; // This doesn't handle insert or remove, only update so is only half useful..
+pub mod bulk_write;
 pub mod context;
 pub mod direct_collections;
+pub mod memory_collection;
+pub mod mongo_update;
+pub mod mongo_where;
@@ -0,0 +1,284 @@
+use mongodb::bson::{Bson, Document};
+
+/**
+ * Apply a mongo update modifier to a document, in the same way that MongoDB would.
+ * Only `$set`, `$unset`, `$inc` and `$push` are supported, an error is returned if anything else is encountered
+ */
+pub fn mongo_update(doc: &mut Document, modifier: &Document) -> Result<(), String> {
+    for (operator, fields) in modifier {
+        let fields = match fields {
+            Bson::Document(fields) => fields,
+            _ => return Err(format!("\"{}\" must be an object", operator)),
+        };
+
+        for (key, value) in fields {
+            if key == "_id" {
+                return Err("Modifying \"_id\" is not allowed".to_string());
+            }
+
+            let path = key.split('.').collect::<Vec<_>>();
+
+            match operator.as_str() {
+                "$set" => set_path(doc, &path, value.clone())?,
+                "$unset" => unset_path(doc, &path),
+                "$inc" => {
+                    let current = get_path(doc, &path).cloned().unwrap_or(Bson::Int32(0));
+                    set_path(doc, &path, increment(&current, value)?)?;
+                }
+                "$push" => {
+                    let mut entries = match get_path(doc, &path) {
+                        Some(Bson::Array(entries)) => entries.clone(),
+                        Some(_) => {
+                            return Err(format!("\"$push\" requires \"{}\" to be an array", key))
+                        }
+                        None => Vec::new(),
+                    };
+                    push_values(&mut entries, value)?;
+                    set_path(doc, &path, Bson::Array(entries))?;
+                }
+                _ => return Err(format!("Unsupported update operator \"{}\"", operator)),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn increment(current: &Bson, amount: &Bson) -> Result<Bson, String> {
+    match (current, amount) {
+        (Bson::Int32(a), Bson::Int32(b)) => Ok(Bson::Int32(a + b)),
+        (Bson::Int64(a), Bson::Int64(b)) => Ok(Bson::Int64(a + b)),
+        (Bson::Int32(a), Bson::Int64(b)) => Ok(Bson::Int64(*a as i64 + b)),
+        (Bson::Int64(a), Bson::Int32(b)) => Ok(Bson::Int64(a + *b as i64)),
+        (Bson::Double(a), Bson::Double(b)) => Ok(Bson::Double(a + b)),
+        (Bson::Double(a), Bson::Int32(b)) => Ok(Bson::Double(a + *b as f64)),
+        (Bson::Double(a), Bson::Int64(b)) => Ok(Bson::Double(a + *b as f64)),
+        (Bson::Int32(a), Bson::Double(b)) => Ok(Bson::Double(*a as f64 + b)),
+        (Bson::Int64(a), Bson::Double(b)) => Ok(Bson::Double(*a as f64 + b)),
+        _ => Err("\"$inc\" can only be applied to numbers".to_string()),
+    }
+}
+
+/**
+ * Append the value of a `$push` to the array. This supports the `$each` modifier to push multiple values
+ */
+fn push_values(entries: &mut Vec<Bson>, value: &Bson) -> Result<(), String> {
+    match value {
+        Bson::Document(modifiers) if modifiers.keys().any(|k| k.starts_with('$')) => {
+            for (modifier, operand) in modifiers {
+                match (modifier.as_str(), operand) {
+                    ("$each", Bson::Array(values)) => entries.extend(values.iter().cloned()),
+                    ("$each", _) => return Err("\"$each\" needs an array".to_string()),
+                    _ => return Err(format!("Unsupported \"$push\" modifier \"{}\"", modifier)),
+                }
+            }
+            Ok(())
+        }
+        _ => {
+            entries.push(value.clone());
+            Ok(())
+        }
+    }
+}
+
+fn get_path<'a>(doc: &'a Document, path: &[&str]) -> Option<&'a Bson> {
+    let (first, rest) = path.split_first()?;
+    let value = doc.get(first)?;
+
+    if rest.is_empty() {
+        Some(value)
+    } else {
+        get_value_path(value, rest)
+    }
+}
+
+fn get_value_path<'a>(value: &'a Bson, path: &[&str]) -> Option<&'a Bson> {
+    match value {
+        Bson::Document(doc) => get_path(doc, path),
+        Bson::Array(entries) => {
+            let (first, rest) = path.split_first()?;
+            let entry = entries.get(first.parse::<usize>().ok()?)?;
+
+            if rest.is_empty() {
+                Some(entry)
+            } else {
+                get_value_path(entry, rest)
+            }
+        }
+        _ => None,
+    }
+}
+
+fn set_path(doc: &mut Document, path: &[&str], value: Bson) -> Result<(), String> {
+    if let Some((first, rest)) = path.split_first() {
+        if rest.is_empty() {
+            doc.insert(*first, value);
+        } else {
+            let child = doc
+                .entry(first.to_string())
+                .or_insert_with(|| Bson::Document(Document::new()));
+            set_value_path(child, rest, value)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_value_path(target: &mut Bson, path: &[&str], value: Bson) -> Result<(), String> {
+    match target {
+        Bson::Document(doc) => set_path(doc, path, value),
+        Bson::Array(entries) => {
+            let (first, rest) = path
+                .split_first()
+                .ok_or_else(|| "Invalid update path".to_string())?;
+            let index = first
+                .parse::<usize>()
+                .map_err(|_| format!("Cannot create field \"{}\" in an array", first))?;
+
+            while entries.len() <= index {
+                entries.push(Bson::Null);
+            }
+
+            if rest.is_empty() {
+                entries[index] = value;
+                Ok(())
+            } else {
+                if entries[index] == Bson::Null {
+                    entries[index] = Bson::Document(Document::new());
+                }
+                set_value_path(&mut entries[index], rest, value)
+            }
+        }
+        _ => Err(format!(
+            "Cannot create field \"{}\" in a non-object",
+            path.join(".")
+        )),
+    }
+}
+
+fn unset_path(doc: &mut Document, path: &[&str]) {
+    if let Some((first, rest)) = path.split_first() {
+        if rest.is_empty() {
+            doc.remove(first);
+        } else if let Some(Bson::Document(child)) = doc.get_mut(first) {
+            unset_path(child, rest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Document};
+
+    use super::*;
+
+    fn base_doc() -> Document {
+        doc! {
+            "_id": "doc0",
+            "name": "first",
+            "count": 1,
+            "timings": { "take": 100 },
+            "tags": ["a"],
+        }
+    }
+
+    #[test]
+    fn apply_modifiers() {
+        let cases: Vec<(&str, Document, Document)> = vec![
+            (
+                "$set top level",
+                doc! { "$set": { "name": "second" } },
+                doc! { "_id": "doc0", "name": "second", "count": 1, "timings": { "take": 100 }, "tags": ["a"] },
+            ),
+            (
+                "$set dotted path",
+                doc! { "$set": { "timings.next": 200 } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100, "next": 200 }, "tags": ["a"] },
+            ),
+            (
+                "$set creates parents",
+                doc! { "$set": { "a.b.c": true } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100 }, "tags": ["a"], "a": { "b": { "c": true } } },
+            ),
+            (
+                "$set array index",
+                doc! { "$set": { "tags.0": "b" } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100 }, "tags": ["b"] },
+            ),
+            (
+                "$unset",
+                doc! { "$unset": { "name": 1, "timings.take": 1, "missing": 1 } },
+                doc! { "_id": "doc0", "count": 1, "timings": {}, "tags": ["a"] },
+            ),
+            (
+                "$inc existing",
+                doc! { "$inc": { "count": 2 } },
+                doc! { "_id": "doc0", "name": "first", "count": 3, "timings": { "take": 100 }, "tags": ["a"] },
+            ),
+            (
+                "$inc missing",
+                doc! { "$inc": { "other": 5_i64 } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100 }, "tags": ["a"], "other": 5_i64 },
+            ),
+            (
+                "$inc widens to double",
+                doc! { "$inc": { "count": 0.5 } },
+                doc! { "_id": "doc0", "name": "first", "count": 1.5, "timings": { "take": 100 }, "tags": ["a"] },
+            ),
+            (
+                "$push existing",
+                doc! { "$push": { "tags": "b" } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100 }, "tags": ["a", "b"] },
+            ),
+            (
+                "$push missing",
+                doc! { "$push": { "other": { "x": 1 } } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100 }, "tags": ["a"], "other": [{ "x": 1 }] },
+            ),
+            (
+                "$push $each",
+                doc! { "$push": { "tags": { "$each": ["b", "c"] } } },
+                doc! { "_id": "doc0", "name": "first", "count": 1, "timings": { "take": 100 }, "tags": ["a", "b", "c"] },
+            ),
+            (
+                "multiple operators",
+                doc! { "$set": { "name": "second" }, "$unset": { "tags": 1 }, "$inc": { "count": -1 } },
+                doc! { "_id": "doc0", "name": "second", "count": 0, "timings": { "take": 100 } },
+            ),
+        ];
+
+        for (name, modifier, expected) in cases {
+            let mut doc = base_doc();
+            assert_eq!(mongo_update(&mut doc, &modifier), Ok(()), "{}", name);
+            assert_eq!(doc, expected, "{}", name);
+        }
+    }
+
+    #[test]
+    fn reject_invalid_modifiers() {
+        let cases: Vec<(&str, Document)> = vec![
+            (
+                "unsupported operator",
+                doc! { "$rename": { "name": "title" } },
+            ),
+            ("replacement document", doc! { "name": "second" }),
+            ("modify _id", doc! { "$set": { "_id": "doc1" } }),
+            ("$inc a string", doc! { "$inc": { "name": 1 } }),
+            ("$inc by a string", doc! { "$inc": { "count": "1" } }),
+            ("$push to a non-array", doc! { "$push": { "name": "b" } }),
+            (
+                "$push unsupported modifier",
+                doc! { "$push": { "tags": { "$slice": 1 } } },
+            ),
+            (
+                "$set inside a non-object",
+                doc! { "$set": { "name.first": "b" } },
+            ),
+        ];
+
+        for (name, modifier) in cases {
+            let mut doc = base_doc();
+            assert!(mongo_update(&mut doc, &modifier).is_err(), "{}", name);
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::cmp::Ordering;
+
+use mongodb::bson::{Bson, Document};
+
+/**
+ * Evaluate a mongo query selector against a document, in the same way that MongoDB would.
+ * Only a subset of the query operators are supported, an error is returned if an unsupported one is encountered
+ */
+pub fn mongo_where(doc: &Document, selector: &Document) -> Result<bool, String> {
+    for (key, condition) in selector {
+        let matched = match key.as_str() {
+            "$and" => {
+                let mut matched = true;
+                for sub_selector in get_selector_array(key, condition)? {
+                    if !mongo_where(doc, sub_selector)? {
+                        matched = false;
+                        break;
+                    }
+                }
+                matched
+            }
+            "$or" => {
+                let mut matched = false;
+                for sub_selector in get_selector_array(key, condition)? {
+                    if mongo_where(doc, sub_selector)? {
+                        matched = true;
+                        break;
+                    }
+                }
+                matched
+            }
+            "$nor" => {
+                let mut matched = true;
+                for sub_selector in get_selector_array(key, condition)? {
+                    if mongo_where(doc, sub_selector)? {
+                        matched = false;
+                        break;
+                    }
+                }
+                matched
+            }
+            _ if key.starts_with('$') => {
+                return Err(format!("Unsupported query operator \"{}\"", key));
+            }
+            _ => {
+                let mut values = Vec::new();
+                lookup_path(doc, &key.split('.').collect::<Vec<_>>(), &mut values);
+
+                matches_condition(&values, condition)?
+            }
+        };
+
+        if !matched {
+            return Ok(false);
+        }
+    }
+
+    Ok(true)
+}
+
+fn get_selector_array<'a>(key: &str, condition: &'a Bson) -> Result<Vec<&'a Document>, String> {
+    match condition {
+        Bson::Array(entries) => entries
+            .iter()
+            .map(|entry| match entry {
+                Bson::Document(sub_selector) => Ok(sub_selector),
+                _ => Err(format!("\"{}\" must be an array of selectors", key)),
+            })
+            .collect(),
+        _ => Err(format!("\"{}\" must be an array", key)),
+    }
+}
+
+/**
+ * Find all the values at the specified path. This will traverse through arrays, and so may match multiple values
+ */
+pub fn lookup_path<'a>(doc: &'a Document, path: &[&str], values: &mut Vec<&'a Bson>) {
+    if let Some((first, rest)) = path.split_first() {
+        if let Some(value) = doc.get(first) {
+            lookup_value_path(value, rest, values);
+        }
+    }
+}
+
+fn lookup_value_path<'a>(value: &'a Bson, path: &[&str], values: &mut Vec<&'a Bson>) {
+    if path.is_empty() {
+        values.push(value);
+        return;
+    }
+
+    match value {
+        Bson::Document(doc) => lookup_path(doc, path, values),
+        Bson::Array(entries) => {
+            if let Ok(index) = path[0].parse::<usize>() {
+                if let Some(entry) = entries.get(index) {
+                    lookup_value_path(entry, &path[1..], values);
+                }
+            } else {
+                for entry in entries {
+                    if let Bson::Document(doc) = entry {
+                        lookup_path(doc, path, values);
+                    }
+                }
+            }
+        }
+        _ => {
+            // Can't traverse any deeper
+        }
+    }
+}
+
+fn is_operator_document(condition: &Bson) -> Option<&Document> {
+    match condition {
+        Bson::Document(doc) if doc.keys().next().map_or(false, |k| k.starts_with('$')) => Some(doc),
+        _ => None,
+    }
+}
+
+fn matches_condition(values: &[&Bson], condition: &Bson) -> Result<bool, String> {
+    if let Some(operators) = is_operator_document(condition) {
+        for (operator, operand) in operators {
+            let matched = match operator.as_str() {
+                "$eq" => matches_equal(values, operand),
+                "$ne" => !matches_equal(values, operand),
+                "$in" => matches_any(values, operator, operand)?,
+                "$nin" => !matches_any(values, operator, operand)?,
+                "$exists" => is_truthy(operand) == !values.is_empty(),
+                "$gt" => matches_comparison(values, operand, |o| o == Ordering::Greater),
+                "$gte" => matches_comparison(values, operand, |o| o != Ordering::Less),
+                "$lt" => matches_comparison(values, operand, |o| o == Ordering::Less),
+                "$lte" => matches_comparison(values, operand, |o| o != Ordering::Greater),
+                _ => return Err(format!("Unsupported query operator \"{}\"", operator)),
+            };
+
+            if !matched {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    } else {
+        Ok(matches_equal(values, condition))
+    }
+}
+
+fn matches_any(values: &[&Bson], operator: &str, operand: &Bson) -> Result<bool, String> {
+    match operand {
+        Bson::Array(candidates) => Ok(candidates
+            .iter()
+            .any(|candidate| matches_equal(values, candidate))),
+        _ => Err(format!("\"{}\" needs an array", operator)),
+    }
+}
+
+fn matches_equal(values: &[&Bson], expected: &Bson) -> bool {
+    if values.is_empty() {
+        // A missing field is considered equal to null
+        return expected == &Bson::Null;
+    }
+
+    values.iter().any(|value| {
+        bson_equals(value, expected)
+            || match value {
+                Bson::Array(entries) => entries.iter().any(|entry| bson_equals(entry, expected)),
+                _ => false,
+            }
+    })
+}
+
+fn matches_comparison<F: Fn(Ordering) -> bool>(values: &[&Bson], operand: &Bson, check: F) -> bool {
+    values
+        .iter()
+        .any(|value| compare_bson(value, operand).map_or(false, &check))
+}
+
+fn is_truthy(value: &Bson) -> bool {
+    match value {
+        Bson::Boolean(val) => *val,
+        Bson::Null | Bson::Undefined => false,
+        Bson::Int32(val) => *val != 0,
+        Bson::Int64(val) => *val != 0,
+        Bson::Double(val) => *val != 0.0,
+        _ => true,
+    }
+}
+
+fn as_number(value: &Bson) -> Option<f64> {
+    match value {
+        Bson::Int32(val) => Some(*val as f64),
+        Bson::Int64(val) => Some(*val as f64),
+        Bson::Double(val) => Some(*val),
+        _ => None,
+    }
+}
+
+/**
+ * Compare two values, if they are of a comparable type
+ */
+pub fn compare_bson(a: &Bson, b: &Bson) -> Option<Ordering> {
+    if let (Some(a), Some(b)) = (as_number(a), as_number(b)) {
+        return a.partial_cmp(&b);
+    }
+
+    match (a, b) {
+        (Bson::String(a), Bson::String(b)) => Some(a.cmp(b)),
+        (Bson::Boolean(a), Bson::Boolean(b)) => Some(a.cmp(b)),
+        (Bson::DateTime(a), Bson::DateTime(b)) => Some(a.cmp(b)),
+        _ => None,
+    }
+}
+
+/**
+ * Check if two values are equal, treating the different number types as interchangeable
+ */
+pub fn bson_equals(a: &Bson, b: &Bson) -> bool {
+    if let (Some(a), Some(b)) = (as_number(a), as_number(b)) {
+        return a == b;
+    }
+
+    match (a, b) {
+        (Bson::Array(a), Bson::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| bson_equals(a, b))
+        }
+        (Bson::Document(a), Bson::Document(b)) => {
+            a.len() == b.len()
+                && a.iter()
+                    .zip(b.iter())
+                    .all(|((ka, va), (kb, vb))| ka == kb && bson_equals(va, vb))
+        }
+        _ => a == b,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::{doc, Document};
+
+    use super::*;
+
+    fn base_doc() -> Document {
+        doc! {
+            "_id": "doc0",
+            "rank": 2,
+            "name": "first",
+            "hidden": false,
+            "empty": null,
+            "timings": { "take": 100, "plannedStarted": 90.5 },
+            "tags": ["a", "b"],
+            "pieces": [
+                { "id": "piece0", "layer": "vt" },
+                { "id": "piece1", "layer": "gfx" },
+            ],
+        }
+    }
+
+    #[test]
+    fn evaluate_selectors() {
+        let cases: Vec<(&str, Document, bool)> = vec![
+            ("empty selector", doc! {}, true),
+            ("equality", doc! { "name": "first" }, true),
+            ("equality mismatch", doc! { "name": "second" }, false),
+            ("equality across number types", doc! { "rank": 2.0 }, true),
+            ("equality with array entry", doc! { "tags": "b" }, true),
+            (
+                "equality with whole array",
+                doc! { "tags": ["a", "b"] },
+                true,
+            ),
+            (
+                "equality with object",
+                doc! { "timings": { "take": 100, "plannedStarted": 90.5 } },
+                true,
+            ),
+            ("missing equals null", doc! { "missing": null }, true),
+            ("null equals null", doc! { "empty": null }, true),
+            ("$eq", doc! { "name": { "$eq": "first" } }, true),
+            ("$ne", doc! { "name": { "$ne": "first" } }, false),
+            ("$ne missing", doc! { "missing": { "$ne": "first" } }, true),
+            ("$ne array entry", doc! { "tags": { "$ne": "a" } }, false),
+            ("$in", doc! { "name": { "$in": ["second", "first"] } }, true),
+            (
+                "$in mismatch",
+                doc! { "name": { "$in": ["second", "third"] } },
+                false,
+            ),
+            (
+                "$in array entry",
+                doc! { "tags": { "$in": ["c", "b"] } },
+                true,
+            ),
+            (
+                "$in null matches missing",
+                doc! { "missing": { "$in": [null] } },
+                true,
+            ),
+            (
+                "$nin",
+                doc! { "name": { "$nin": ["second", "third"] } },
+                true,
+            ),
+            (
+                "$nin mismatch",
+                doc! { "name": { "$nin": ["first"] } },
+                false,
+            ),
+            (
+                "$nin missing",
+                doc! { "missing": { "$nin": ["first"] } },
+                true,
+            ),
+            ("$exists true", doc! { "name": { "$exists": true } }, true),
+            (
+                "$exists false",
+                doc! { "name": { "$exists": false } },
+                false,
+            ),
+            (
+                "$exists false missing",
+                doc! { "missing": { "$exists": false } },
+                true,
+            ),
+            (
+                "$exists null value",
+                doc! { "empty": { "$exists": true } },
+                true,
+            ),
+            ("$gt", doc! { "rank": { "$gt": 1 } }, true),
+            ("$gte", doc! { "rank": { "$gte": 2 } }, true),
+            ("$lt", doc! { "rank": { "$lt": 2 } }, false),
+            ("$lte", doc! { "rank": { "$lte": 2_i64 } }, true),
+            ("$gt different type", doc! { "name": { "$gt": 1 } }, false),
+            ("range", doc! { "rank": { "$gt": 1, "$lt": 3 } }, true),
+            ("dotted path", doc! { "timings.take": 100 }, true),
+            ("dotted path mismatch", doc! { "timings.take": 101 }, false),
+            (
+                "dotted path missing",
+                doc! { "timings.missing": { "$exists": false } },
+                true,
+            ),
+            ("dotted path array index", doc! { "tags.1": "b" }, true),
+            (
+                "dotted path through array",
+                doc! { "pieces.layer": "gfx" },
+                true,
+            ),
+            (
+                "dotted path through array index",
+                doc! { "pieces.0.layer": "gfx" },
+                false,
+            ),
+            ("implicit and", doc! { "name": "first", "rank": 3 }, false),
+            (
+                "$and",
+                doc! { "$and": [{ "name": "first" }, { "rank": 2 }] },
+                true,
+            ),
+            (
+                "$and mismatch",
+                doc! { "$and": [{ "name": "first" }, { "rank": 3 }] },
+                false,
+            ),
+            (
+                "$or",
+                doc! { "$or": [{ "name": "second" }, { "rank": 2 }] },
+                true,
+            ),
+            (
+                "$or mismatch",
+                doc! { "$or": [{ "name": "second" }, { "rank": 3 }] },
+                false,
+            ),
+            (
+                "$nor",
+                doc! { "$nor": [{ "name": "second" }, { "rank": 3 }] },
+                true,
+            ),
+            (
+                "nested $or in $and",
+                doc! { "$and": [{ "$or": [{ "missing": { "$exists": true } }, { "hidden": false }] }, { "tags": "a" }] },
+                true,
+            ),
+        ];
+
+        for (name, selector, expected) in cases {
+            assert_eq!(
+                mongo_where(&base_doc(), &selector),
+                Ok(expected),
+                "{}",
+                name
+            );
+        }
+    }
+
+    #[test]
+    fn reject_invalid_selectors() {
+        let cases: Vec<(&str, Document)> = vec![
+            ("unsupported top level operator", doc! { "$where": "true" }),
+            (
+                "unsupported field operator",
+                doc! { "name": { "$regex": "^f" } },
+            ),
+            (
+                "unsupported nested operator",
+                doc! { "$or": [{ "rank": { "$mod": [2, 0] } }] },
+            ),
+            ("$or not an array", doc! { "$or": { "name": "first" } }),
+            ("$and entry not a selector", doc! { "$and": ["first"] }),
+            ("$in not an array", doc! { "name": { "$in": "first" } }),
+            ("$nin not an array", doc! { "name": { "$nin": "first" } }),
+        ];
+
+        for (name, selector) in cases {
+            assert!(mongo_where(&base_doc(), &selector).is_err(), "{}", name);
+        }
+    }
+}
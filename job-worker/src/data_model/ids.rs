@@ -212,3 +212,35 @@ impl ProtectedId for ShowStyleVariantId {
         self.0
     }
 }
+
+#[derive(PartialEq, Deserialize, Serialize, Clone, Debug, Eq, Hash)]
+pub struct StudioId(String);
+impl StudioId {
+    pub fn new_from(str: String) -> StudioId {
+        StudioId(str)
+    }
+}
+impl ProtectedId for StudioId {
+    fn unprotect(&self) -> &str {
+        &self.0
+    }
+    fn unprotect_move(self) -> String {
+        self.0
+    }
+}
+
+#[derive(PartialEq, Deserialize, Serialize, Clone, Debug, Eq, Hash)]
+pub struct PeripheralDeviceCommandId(String);
+impl PeripheralDeviceCommandId {
+    pub fn new_from(str: String) -> PeripheralDeviceCommandId {
+        PeripheralDeviceCommandId(str)
+    }
+}
+impl ProtectedId for PeripheralDeviceCommandId {
+    fn unprotect(&self) -> &str {
+        &self.0
+    }
+    fn unprotect_move(self) -> String {
+        self.0
+    }
+}
@@ -2,9 +2,12 @@ pub mod extra;
 pub mod ids;
 pub mod part;
 pub mod part_instance;
+pub mod peripheral_device_command;
 pub mod piece;
 pub mod piece_instance;
+pub mod playlist_lock;
 pub mod rundown;
 pub mod rundown_playlist;
 pub mod segment;
 pub mod show_style_base;
+pub mod timeline;
@@ -23,6 +23,22 @@ pub struct PartInstanceTimings {
     )]
     #[serde(skip_serializing_if = "Option::is_none")]
     pub planned_stopped_playback: Option<DateTime<Utc>>,
+    #[serde_as(
+        as = "Option<serde_with::TimestampMilliSeconds<i64, serde_with::formats::Flexible>>"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_started_playback: Option<DateTime<Utc>>,
+    #[serde_as(
+        as = "Option<serde_with::TimestampMilliSeconds<i64, serde_with::formats::Flexible>>"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reported_stopped_playback: Option<DateTime<Utc>>,
+    /** How long the PartInstance was played for, once it has stopped */
+    #[serde_as(
+        as = "Option<serde_with::DurationMilliSeconds<i64, serde_with::formats::Flexible>>"
+    )]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<Duration>,
 
     #[serde_as(as = "serde_with::TimestampMilliSeconds<i64, serde_with::formats::Flexible>")]
     pub set_as_next: DateTime<Utc>,
@@ -0,0 +1,36 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::cache::doc::DocWithId;
+
+use super::ids::PeripheralDeviceCommandId;
+
+/**
+ * A request for a PeripheralDevice to execute a function.
+ * The device picks these up from the collection, and writes the reply back to the document if one was requested
+ */
+#[serde_as]
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PeripheralDeviceCommand {
+    #[serde(rename = "_id")]
+    pub id: PeripheralDeviceCommandId,
+
+    pub device_id: String, // TODO - type
+
+    #[serde_as(as = "serde_with::TimestampMilliSeconds<i64, serde_with::formats::Flexible>")]
+    pub time: DateTime<Utc>,
+
+    pub function_name: String,
+    pub args: Vec<serde_json::Value>,
+
+    /** Whether the device has replied to the command */
+    pub has_reply: bool,
+}
+
+impl<'a> DocWithId<'a, PeripheralDeviceCommandId> for PeripheralDeviceCommand {
+    fn doc_id(&'a self) -> &'a PeripheralDeviceCommandId {
+        &self.id
+    }
+}
@@ -60,7 +60,8 @@ pub enum PieceLifespan {
 }
 impl Into<bson::Bson> for PieceLifespan {
     fn into(self) -> bson::Bson {
-        bson::Bson::Int32(self as i32)
+        // Use the serialized form, so that queries match the stored documents
+        bson::to_bson(&self).expect("PieceLifespan is always serializable")
     }
 }
 
@@ -146,3 +147,24 @@ impl<'a> DocWithId<'a, PieceId> for Piece {
         &self.id
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn piece_lifespan_into_bson_matches_serialized() {
+        for lifespan in [
+            PieceLifespan::WithinPart,
+            PieceLifespan::OutOnSegmentChange,
+            PieceLifespan::OutOnSegmentEnd,
+            PieceLifespan::OutOnRundownChange,
+            PieceLifespan::OutOnRundownEnd,
+            PieceLifespan::OutOnShowStyleEnd,
+        ] {
+            let value: bson::Bson = lifespan.into();
+            assert_eq!(value, bson::to_bson(&lifespan).unwrap());
+            assert!(matches!(value, bson::Bson::String(_)));
+        }
+    }
+}
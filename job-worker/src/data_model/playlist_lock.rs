@@ -0,0 +1,31 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::cache::doc::DocWithId;
+
+use super::ids::RundownPlaylistId;
+
+/**
+ * A lease on a RundownPlaylist, held by a worker while it is running a playout job for that playlist.
+ * This ensures that only one worker process operates on a playlist at a time
+ */
+#[serde_as]
+#[derive(Clone, Deserialize, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PlaylistLock {
+    #[serde(rename = "_id")]
+    pub id: RundownPlaylistId,
+
+    /** Id of the worker holding the lease */
+    pub owner: String,
+    /** When the lease expires, if it is not renewed */
+    #[serde_as(as = "serde_with::TimestampMilliSeconds<i64, serde_with::formats::Flexible>")]
+    pub expires_at: DateTime<Utc>,
+}
+
+impl<'a> DocWithId<'a, RundownPlaylistId> for PlaylistLock {
+    fn doc_id(&'a self) -> &'a RundownPlaylistId {
+        &self.id
+    }
+}
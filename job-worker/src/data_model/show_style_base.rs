@@ -1,6 +1,7 @@
 use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
 
 use crate::{cache::doc::DocWithId, object_with_overrides::ObjectWithOverrides};
 
@@ -13,6 +14,34 @@ pub struct SourceLayer {
 }
 pub type SourceLayers = HashMap<String, SourceLayer>;
 
+/**
+ * How the objects of upcoming pieces are looked ahead to on a layer
+ */
+#[derive(Clone, Copy, PartialEq, Eq, Deserialize_repr, Serialize_repr, Default, Debug)]
+#[repr(u8)]
+pub enum LookaheadMode {
+    #[default]
+    None = 0,
+    /** The objects are played on a separate layer, so that the device can preload them */
+    Preload = 1,
+    /** The objects are played on the layer whenever nothing else is playing on it */
+    WhenClear = 3,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutputLayer {
+    #[serde(default)]
+    pub lookahead: LookaheadMode,
+    /** How many objects to lookahead for. Defaults to 1 */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookahead_depth: Option<usize>,
+    /** How many parts to search through for objects. Defaults to LOOKAHEAD_DEFAULT_SEARCH_DISTANCE */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookahead_max_search_distance: Option<usize>,
+}
+pub type OutputLayers = HashMap<String, OutputLayer>;
+
 #[derive(Clone, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct DBShowStyleBase {
@@ -20,6 +49,7 @@ pub struct DBShowStyleBase {
     pub id: ShowStyleBaseId,
 
     pub source_layers_with_overrides: ObjectWithOverrides<SourceLayers>,
+    pub output_layers_with_overrides: ObjectWithOverrides<OutputLayers>,
 }
 impl<'a> DocWithId<'a, ShowStyleBaseId> for DBShowStyleBase {
     fn doc_id(&'a self) -> &'a ShowStyleBaseId {
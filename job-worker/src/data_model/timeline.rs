@@ -0,0 +1,112 @@
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use serde_with::serde_as;
+
+use crate::cache::doc::DocWithId;
+
+use super::ids::{PartInstanceId, PieceInstanceId, StudioId};
+
+/**
+ * A point in time on the timeline. This is either a number of milliseconds, or an expression referencing other objects such as `#obj.start + 100`
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum TimelineEnableValue {
+    Time(i64),
+    Expression(String),
+}
+impl TimelineEnableValue {
+    pub fn from_duration(duration: Duration) -> TimelineEnableValue {
+        TimelineEnableValue::Time(duration.num_milliseconds())
+    }
+    pub fn from_date(date: DateTime<Utc>) -> TimelineEnableValue {
+        TimelineEnableValue::Time(date.timestamp_millis())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub struct TimelineEnable {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub start: Option<TimelineEnableValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<TimelineEnableValue>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration: Option<TimelineEnableValue>,
+    #[serde(rename = "while")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub while_: Option<TimelineEnableValue>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize, Serialize)]
+pub enum TimelineObjType {
+    /** Objects played in a rundown */
+    #[default]
+    #[serde(rename = "rundown")]
+    Rundown,
+}
+
+/**
+ * An object on the timeline, as consumed by the playout gateway
+ */
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineObjGenerated {
+    pub id: String,
+    #[serde(default)]
+    pub object_type: TimelineObjType,
+
+    pub enable: TimelineEnable,
+    pub layer: String,
+    #[serde(default)]
+    pub priority: i64,
+
+    pub content: serde_json::Value,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub classes: Vec<String>,
+
+    #[serde(default)]
+    pub is_group: bool,
+    /** Id of the group this object is inside of */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub in_group: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub part_instance_id: Option<PartInstanceId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub piece_instance_id: Option<PieceInstanceId>,
+
+    #[serde(default)]
+    pub is_lookahead: bool,
+    /** For lookahead objects on a separate layer, the layer they are preloading for */
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookahead_for_layer: Option<String>,
+
+    /** Any other properties defined by the blueprints, such as keyframes */
+    #[serde(flatten)]
+    pub other: serde_json::Map<String, serde_json::Value>,
+}
+
+/**
+ * The complete timeline of a studio
+ */
+#[serde_as]
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimelineComplete {
+    #[serde(rename = "_id")]
+    pub id: StudioId,
+
+    /** A unique id for this generation of the timeline, to allow tracking which timeline the gateway is playing */
+    pub timeline_hash: String,
+    #[serde_as(as = "serde_with::TimestampMilliSeconds<i64, serde_with::formats::Flexible>")]
+    pub generated: DateTime<Utc>,
+
+    /** The objects of the timeline, serialized as JSON. This avoids mongo having to parse the objects */
+    pub timeline_blob: String,
+}
+impl<'a> DocWithId<'a, StudioId> for TimelineComplete {
+    fn doc_id(&'a self) -> &'a StudioId {
+        &self.id
+    }
+}
@@ -0,0 +1,237 @@
+use std::{collections::HashMap, fmt::Display};
+
+use crate::{
+    cache::{collection::CacheCollectionError, object::CacheObjectError, save::CacheSaveError},
+    data_model::ids::{
+        PartId, PartInstanceId, PieceInstanceId, ProtectedId, RundownId, RundownPlaylistId,
+        SegmentId, ShowStyleBaseId,
+    },
+};
+
+/**
+ * The errors which can be reported to the user. Each has a message which can be translated
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UserErrorMessage {
+    InactiveRundown,
+    NoCurrentPart,
+    NoCurrentOrNextPart,
+    DuringHold,
+    PartNotFound,
+    PartNotPlayable,
+    PartAlreadyCurrent,
+    RundownAlreadyActiveNames,
+    RundownResetWhileActive,
+    TakeNoNextPart,
+    TakeBlockedDuration,
+    TakeDuringTransition,
+    TakeCloseToAutonext,
+    TakeFromIncorrectPart,
+    ActionsNotAllowedOnNonCurrentPartInstance,
+    HoldNotCancelable,
+    HoldNeedsNextPart,
+    HoldIncompatibleParts,
+    HoldAlreadyActive,
+    HoldAfterAdlib,
+}
+impl UserErrorMessage {
+    /**
+     * The untranslated message, which is used as the key for translations
+     */
+    pub fn key(&self) -> &'static str {
+        match self {
+            UserErrorMessage::InactiveRundown => "Rundown must be active!",
+            UserErrorMessage::NoCurrentPart => "Rundown must be playing!",
+            UserErrorMessage::NoCurrentOrNextPart => "There is no Current or Next part!",
+            UserErrorMessage::DuringHold => "Can't do this during hold!",
+            UserErrorMessage::PartNotFound => "The selected part does not exist",
+            UserErrorMessage::PartNotPlayable => "The selected part cannot be played",
+            UserErrorMessage::PartAlreadyCurrent => {
+                "The selected part is already playing, it can only be set as next manually"
+            }
+            UserErrorMessage::RundownAlreadyActiveNames => {
+                "Rundown Playlist cannot be activated, another Rundown Playlist is active: {{names}}"
+            }
+            UserErrorMessage::RundownResetWhileActive => {
+                "Rundown Playlist cannot be reset while it is on air"
+            }
+            UserErrorMessage::TakeNoNextPart => {
+                "No Next point found, please set a part as Next before doing a TAKE."
+            }
+            UserErrorMessage::TakeBlockedDuration => "Cannot take shortly after another take",
+            UserErrorMessage::TakeDuringTransition => "Cannot take during a transition",
+            UserErrorMessage::TakeCloseToAutonext => "Cannot take shortly before an autoTake",
+            UserErrorMessage::TakeFromIncorrectPart => {
+                "Ignoring take as playing part has changed since TAKE was requested."
+            }
+            UserErrorMessage::ActionsNotAllowedOnNonCurrentPartInstance => {
+                "Actions can only be performed on the currently playing part"
+            }
+            UserErrorMessage::HoldNotCancelable => "Hold can only be cancelled before it has started",
+            UserErrorMessage::HoldNeedsNextPart => "Hold needs a next part to hold into",
+            UserErrorMessage::HoldIncompatibleParts => {
+                "Hold is only possible from a hold FROM part into the directly following hold TO part"
+            }
+            UserErrorMessage::HoldAlreadyActive => "Hold is already active",
+            UserErrorMessage::HoldAfterAdlib => {
+                "Hold is not possible after an adlib has been taken in the current part"
+            }
+        }
+    }
+}
+
+/**
+ * A message which can be translated by the client, with the arguments to be interpolated into it
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TranslatableMessage {
+    pub key: String,
+    pub args: HashMap<String, String>,
+}
+
+/**
+ * An error which was caused by something the user did, and so should be reported back to them
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserError {
+    pub message: UserErrorMessage,
+    pub args: HashMap<String, String>,
+}
+impl UserError {
+    pub fn create(message: UserErrorMessage) -> UserError {
+        UserError {
+            message,
+            args: HashMap::new(),
+        }
+    }
+
+    pub fn with_arg(mut self, name: &str, value: String) -> Self {
+        self.args.insert(name.to_string(), value);
+        self
+    }
+
+    pub fn to_translatable_message(&self) -> TranslatableMessage {
+        TranslatableMessage {
+            key: self.message.key().to_string(),
+            args: self.args.clone(),
+        }
+    }
+}
+impl Display for UserError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Interpolate the arguments, as the translations would
+        let mut message = self.message.key().to_string();
+        for (name, value) in &self.args {
+            message = message.replace(&format!("{{{{{}}}}}", name), value);
+        }
+
+        write!(f, "{}", message)
+    }
+}
+
+/**
+ * The id of a document which could not be found
+ */
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DocumentId {
+    RundownPlaylist(RundownPlaylistId),
+    Rundown(RundownId),
+    Segment(SegmentId),
+    Part(PartId),
+    PartInstance(PartInstanceId),
+    PieceInstance(PieceInstanceId),
+    ShowStyleBase(ShowStyleBaseId),
+}
+impl Display for DocumentId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DocumentId::RundownPlaylist(id) => write!(f, "RundownPlaylist \"{}\"", id.unprotect()),
+            DocumentId::Rundown(id) => write!(f, "Rundown \"{}\"", id.unprotect()),
+            DocumentId::Segment(id) => write!(f, "Segment \"{}\"", id.unprotect()),
+            DocumentId::Part(id) => write!(f, "Part \"{}\"", id.unprotect()),
+            DocumentId::PartInstance(id) => write!(f, "PartInstance \"{}\"", id.unprotect()),
+            DocumentId::PieceInstance(id) => write!(f, "PieceInstance \"{}\"", id.unprotect()),
+            DocumentId::ShowStyleBase(id) => write!(f, "ShowStyleBase \"{}\"", id.unprotect()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum JobError {
+    /** The job was refused because of something the user did */
+    UserError(UserError),
+    /** A document needed by the job does not exist */
+    NotFound(DocumentId),
+    /** A query or write to the database failed */
+    Database(String),
+    /** A write to the database failed in a way which may succeed if retried, such as a write conflict inside of a transaction */
+    DatabaseTransient(String),
+    /** A write to the database failed as a document with the same unique key, such as the `_id`, already exists */
+    DuplicateKey(String),
+    /** An operation on a cache collection failed. The ids are unprotected, as the collections are of different types */
+    CacheCollection(CacheCollectionError<String>),
+    CacheObject(CacheObjectError),
+    CacheSave(CacheSaveError),
+    /** The data is in a state that the job cannot handle */
+    Internal(String),
+}
+impl JobError {
+    /**
+     * The message to present to the user, if this error was caused by them
+     */
+    pub fn user_message(&self) -> Option<TranslatableMessage> {
+        match self {
+            JobError::UserError(err) => Some(err.to_translatable_message()),
+            _ => None,
+        }
+    }
+}
+impl Display for JobError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            JobError::UserError(err) => write!(f, "{}", err),
+            JobError::NotFound(id) => write!(f, "{} was not found", id),
+            JobError::Database(message) => write!(f, "{}", message),
+            JobError::DatabaseTransient(message) => write!(f, "{}", message),
+            JobError::DuplicateKey(message) => write!(f, "{}", message),
+            JobError::CacheCollection(err) => write!(f, "{}", err),
+            JobError::CacheObject(err) => write!(f, "{}", err),
+            JobError::CacheSave(err) => write!(f, "{}", err),
+            JobError::Internal(message) => write!(f, "{}", message),
+        }
+    }
+}
+impl From<UserError> for JobError {
+    fn from(err: UserError) -> Self {
+        JobError::UserError(err)
+    }
+}
+impl<Id: Clone + ProtectedId> From<CacheCollectionError<Id>> for JobError {
+    fn from(err: CacheCollectionError<Id>) -> Self {
+        JobError::CacheCollection(match err {
+            CacheCollectionError::NotImplemented => CacheCollectionError::NotImplemented,
+            CacheCollectionError::IsToBeRemoved(method) => {
+                CacheCollectionError::IsToBeRemoved(method)
+            }
+            CacheCollectionError::AlreadyExists(id) => {
+                CacheCollectionError::AlreadyExists(id.unprotect_move())
+            }
+            CacheCollectionError::NotFound(id) => {
+                CacheCollectionError::NotFound(id.unprotect_move())
+            }
+            CacheCollectionError::IdMismatch(id) => {
+                CacheCollectionError::IdMismatch(id.unprotect_move())
+            }
+        })
+    }
+}
+impl From<CacheObjectError> for JobError {
+    fn from(err: CacheObjectError) -> Self {
+        JobError::CacheObject(err)
+    }
+}
+impl From<CacheSaveError> for JobError {
+    fn from(err: CacheSaveError) -> Self {
+        JobError::CacheSave(err)
+    }
+}
@@ -0,0 +1,266 @@
+use chrono::Utc;
+use log::{error, warn};
+use mongodb::bson::doc;
+use serde_json::json;
+use sofie_rust_experiment::get_random_id;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::{
+    context::context::JobContext,
+    data_model::{
+        ids::{PeripheralDeviceCommandId, ProtectedId, RundownId},
+        peripheral_device_command::PeripheralDeviceCommand,
+    },
+    error::JobError,
+};
+
+/**
+ * Low priority jobs, which are run after the playout jobs which triggered them have completed
+ */
+#[derive(Debug, Clone)]
+pub enum EventsJob {
+    NotifyCurrentlyPlayingPart {
+        rundown_id: RundownId,
+        is_rehearsal: bool,
+        /** The part now playing, or None if nothing is */
+        part_external_id: Option<String>,
+    },
+}
+
+/**
+ * A handle for queueing EventsJobs, which can be cloned into functions deferred until after a save
+ */
+#[derive(Clone)]
+pub struct EventsQueue {
+    sender: UnboundedSender<EventsJob>,
+}
+impl EventsQueue {
+    pub fn create() -> (EventsQueue, UnboundedReceiver<EventsJob>) {
+        let (sender, receiver) = unbounded_channel();
+
+        (EventsQueue { sender }, receiver)
+    }
+
+    pub fn queue(&self, job: EventsJob) -> Result<(), JobError> {
+        self.sender
+            .send(job)
+            .map_err(|err| JobError::Internal(format!("Failed to queue {:?}", err.0)))
+    }
+}
+
+/**
+ * Run the queued EventsJobs, until the queue is closed
+ */
+pub async fn run_events_worker(context: &JobContext, mut receiver: UnboundedReceiver<EventsJob>) {
+    while let Some(job) = receiver.recv().await {
+        let result = match &job {
+            EventsJob::NotifyCurrentlyPlayingPart {
+                rundown_id,
+                is_rehearsal,
+                part_external_id,
+            } => {
+                handle_notify_currently_playing_part(
+                    context,
+                    rundown_id,
+                    *is_rehearsal,
+                    part_external_id.as_deref(),
+                )
+                .await
+            }
+        };
+
+        if let Err(err) = result {
+            error!("EventsJob {:?} failed: {}", job, err);
+        }
+    }
+}
+
+/**
+ * Inform the NRCS of the part which is currently playing, unless it has already been told
+ */
+pub async fn handle_notify_currently_playing_part(
+    context: &JobContext,
+    rundown_id: &RundownId,
+    is_rehearsal: bool,
+    part_external_id: Option<&str>,
+) -> Result<(), JobError> {
+    let rundown = context
+        .direct_collections()
+        .rundowns
+        .find_one_by_id(rundown_id, None)
+        .await?;
+    let rundown = match rundown {
+        Some(rundown) => rundown,
+        None => {
+            warn!(
+                "Rundown \"{}\" is missing. Skipping notifyCurrentPlayingPart",
+                rundown_id.unprotect()
+            );
+            return Ok(());
+        }
+    };
+
+    // When in rehearsal, we don't want to notify the NRCS
+    let part_external_id = if is_rehearsal {
+        None
+    } else {
+        part_external_id.map(|id| id.to_string())
+    };
+
+    if rundown.notified_current_playing_part_external_id == part_external_id {
+        // The NRCS already knows
+        return Ok(());
+    }
+
+    let device_id = match &rundown.peripheral_device_id {
+        Some(device_id) => device_id,
+        None => {
+            // The rundown wasn't created by an ingest device, so there is nothing to notify
+            return Ok(());
+        }
+    };
+
+    // Ask the ingest device to notify the NRCS. This doesn't wait for the reply, any failure is for the device to report
+    context
+        .direct_collections()
+        .peripheral_device_commands
+        .insert_one(&PeripheralDeviceCommand {
+            id: PeripheralDeviceCommandId::new_from(get_random_id()),
+            device_id: device_id.clone(),
+            time: Utc::now(),
+            function_name: "notifyCurrentPlayingPart".to_string(),
+            args: vec![json!(rundown.external_id), json!(part_external_id)],
+            has_reply: false,
+        })
+        .await?;
+
+    // This doesn't need a rundown lock, as this events worker is the only writer of this field and processes its jobs
+    // sequentially. The single field update leaves anything written to the rest of the rundown in the meantime intact
+    let modifier = match &part_external_id {
+        Some(part_external_id) => {
+            doc! { "$set": { "notifiedCurrentPlayingPartExternalId": part_external_id } }
+        }
+        None => doc! { "$unset": { "notifiedCurrentPlayingPartExternalId": 1 } },
+    };
+    context
+        .direct_collections()
+        .rundowns
+        .update_one(doc! { "_id": rundown_id.unprotect() }, modifier)
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::direct_collections::{DirectCollections, InMemoryCollectionsData},
+        data_model::rundown::Rundown,
+        playout::fixtures::{create_context, create_rundown},
+    };
+
+    fn rundown_id() -> RundownId {
+        RundownId::new_from("rundown0".to_string())
+    }
+
+    fn create_collections(peripheral_device_id: Option<&str>) -> std::rc::Rc<DirectCollections> {
+        let mut rundown = create_rundown("rundown0", "showstyle0");
+        rundown.peripheral_device_id = peripheral_device_id.map(|id| id.to_string());
+
+        DirectCollections::create_in_memory(InMemoryCollectionsData {
+            rundowns: vec![rundown],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    async fn fetch_commands(collections: &DirectCollections) -> Vec<PeripheralDeviceCommand> {
+        collections
+            .peripheral_device_commands
+            .find_fetch(doc! {}, None)
+            .await
+            .unwrap()
+    }
+
+    async fn fetch_rundown(collections: &DirectCollections) -> Rundown {
+        collections
+            .rundowns
+            .find_one_by_id(&rundown_id(), None)
+            .await
+            .unwrap()
+            .expect("rundown is missing")
+    }
+
+    #[tokio::test]
+    async fn notify_currently_playing_part_once() {
+        let collections = create_collections(Some("device0"));
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_notify_currently_playing_part(&context, &rundown_id(), false, Some("part0"))
+            .await
+            .unwrap();
+
+        let commands = fetch_commands(&collections).await;
+        assert_eq!(commands.len(), 1);
+        assert_eq!(commands[0].device_id, "device0");
+        assert_eq!(commands[0].function_name, "notifyCurrentPlayingPart");
+        assert_eq!(commands[0].args, vec![json!("rundown0"), json!("part0")]);
+        assert_eq!(
+            fetch_rundown(&collections)
+                .await
+                .notified_current_playing_part_external_id,
+            Some("part0".to_string())
+        );
+
+        // The NRCS already knows about this part
+        handle_notify_currently_playing_part(&context, &rundown_id(), false, Some("part0"))
+            .await
+            .unwrap();
+        assert_eq!(fetch_commands(&collections).await.len(), 1);
+
+        // Stopping playback is notified too
+        handle_notify_currently_playing_part(&context, &rundown_id(), false, None)
+            .await
+            .unwrap();
+
+        let commands = fetch_commands(&collections).await;
+        assert_eq!(commands.len(), 2);
+        assert!(commands
+            .iter()
+            .any(|command| command.args == vec![json!("rundown0"), json!(null)]));
+        assert_eq!(
+            fetch_rundown(&collections)
+                .await
+                .notified_current_playing_part_external_id,
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn notify_currently_playing_part_skipped() {
+        // Rehearsals are not reported to the NRCS
+        let collections = create_collections(Some("device0"));
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_notify_currently_playing_part(&context, &rundown_id(), true, Some("part0"))
+            .await
+            .unwrap();
+        assert!(fetch_commands(&collections).await.is_empty());
+
+        // A rundown without an ingest device has nothing to notify
+        let collections = create_collections(None);
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_notify_currently_playing_part(&context, &rundown_id(), false, Some("part0"))
+            .await
+            .unwrap();
+        assert!(fetch_commands(&collections).await.is_empty());
+        assert_eq!(
+            fetch_rundown(&collections)
+                .await
+                .notified_current_playing_part_external_id,
+            None
+        );
+    }
+}
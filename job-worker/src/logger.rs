@@ -0,0 +1,37 @@
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+/**
+ * A minimal logger, which writes the messages to stderr
+ */
+struct StderrLogger;
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!(
+                "{} {:<5} {}",
+                Utc::now().to_rfc3339(),
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/**
+ * Install the logger, for the messages at or above the level
+ */
+pub fn init(level: LevelFilter) -> Result<(), SetLoggerError> {
+    log::set_logger(&LOGGER)?;
+    log::set_max_level(level);
+
+    Ok(())
+}
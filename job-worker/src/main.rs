@@ -1,30 +1,36 @@
-use std::time::{Duration, Instant};
-
-use chrono::Utc;
+use std::rc::Rc;
 
 use mongodb::{bson::doc, options::ClientOptions, Client};
-use tokio::time::sleep;
 
 use crate::{
     context::{
         context::JobContext,
-        direct_collections::{DirectCollections, MongoReadOnlyCollection},
+        direct_collections::DirectCollections,
+    },
+    events::{run_events_worker, EventsQueue},
+    playout::{
+        autonext::AutonextScheduler,
+        cache::{CacheWriteMode, PlayoutCache},
+        lock::PlaylistLockManager,
     },
-    data_model::ids::{ProtectedId},
-    playout::{cache::PlayoutCache, take::take_next_part_inner},
 };
 
 pub mod cache;
 mod constants;
 pub mod context;
 pub mod data_model;
+pub mod error;
+pub mod events;
 pub mod ingest;
 pub mod lib;
+mod logger;
 pub mod object_with_overrides;
 pub mod playout;
 
 #[tokio::main]
 async fn main() {
+    logger::init(log::LevelFilter::Info).expect("Failed to install logger");
+
     println!("Hello, world!");
 
     // Parse a connection string into an options struct.
@@ -38,10 +44,13 @@ async fn main() {
     // Get a handle to the deployment.
     let client = Client::with_options(client_options).unwrap();
 
-    // Get a handle to a database.
-    let db = client.database("meteor");
+    let collections = DirectCollections::create(&client, "meteor");
 
-    let collections = DirectCollections::create(&db);
+    let write_mode = if collections.supports_transactions().await.unwrap() {
+        CacheWriteMode::Transaction
+    } else {
+        CacheWriteMode::BestEffort
+    };
 
     let playlist = collections
         .rundown_playlists
@@ -59,47 +68,24 @@ async fn main() {
 
     println!("Found playlist {:?}", playlist.id);
 
-    loop {
-        let playlist = collections
-            .rundown_playlists
-            .find_one_by_id(&playlist.id, None)
-            .await
-            .unwrap()
-            .expect("Playlist disappeared");
-
-        if playlist.next_part_instance_id.is_none() {
-            break;
-        }
-
-        let part_instance = collections
-            .part_instances
-            .find_one_by_id(&playlist.next_part_instance_id.unwrap(), None)
-            .await
-            .unwrap()
-            .unwrap();
-
-        let before = Instant::now();
+    let (events_queue, events_receiver) = EventsQueue::create();
 
-        let now = Utc::now();
+    let context = JobContext::create(
+        collections.clone(),
+        Rc::new(PlaylistLockManager::create()),
+        Rc::new(AutonextScheduler::create()),
+        events_queue,
+        write_mode,
+    );
 
-        let mut cache = PlayoutCache::create(&collections, &playlist.id)
-            .await
-            .unwrap();
-
-        let context = JobContext::create(collections.clone());
-
-        take_next_part_inner(context, &mut cache, now)
-            .await
-            .unwrap();
-
-        cache.write_to_database(&collections).await.unwrap();
-
-        println!(
-            "{},{:.2?}",
-            part_instance.part.id.unprotect(),
-            before.elapsed().as_millis()
-        );
+    // Schedule the autonext of the active playlist, any jobs will reschedule it from then on
+    let cache = PlayoutCache::create(&collections, &playlist.id)
+        .await
+        .unwrap();
+    context.autonext_scheduler().update_from_cache(&cache);
 
-        sleep(Duration::from_millis(1000)).await;
-    }
+    tokio::join!(
+        context.autonext_scheduler().run(&context),
+        run_events_worker(&context, events_receiver),
+    );
 }
@@ -0,0 +1,490 @@
+use std::collections::HashSet;
+
+use chrono::Utc;
+use itertools::Itertools;
+use log::{error, info};
+use mongodb::bson::doc;
+use sofie_rust_experiment::get_random_id;
+
+use crate::{
+    cache::{
+        collection::{DbCacheReadCollection, DbCacheWriteCollection},
+        object::{DbCacheReadObject, DbCacheWriteObject},
+    },
+    context::context::JobContext,
+    data_model::{
+        ids::{ProtectedId, RundownPlaylistActivationId, RundownPlaylistId},
+        rundown::Rundown,
+        rundown_playlist::{RundownHoldState, RundownPlaylist},
+    },
+    error::{JobError, UserError, UserErrorMessage},
+    events::EventsJob,
+};
+
+use super::{
+    cache::PlayoutCache,
+    lib::reset_rundown_playlist,
+    lock::run_job_with_playout_cache,
+    select_next_part::select_next_part,
+    set_next_part::{setNextPart, SetNextPartTarget},
+    timeline::update_timeline,
+};
+
+/**
+ * Activate a RundownPlaylist, in either rehearsal or live mode
+ */
+pub async fn handle_activate_rundown_playlist(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    rehearsal: bool,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, |context, cache| {
+        Box::pin(activate_rundown_playlist(context, cache, rehearsal))
+    })
+    .await
+}
+
+/**
+ * Deactivate a RundownPlaylist
+ */
+pub async fn handle_deactivate_rundown_playlist(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, |context, cache| {
+        Box::pin(async move {
+            if cache.playlist.doc().activation_id.is_none() {
+                return Err(UserError::create(UserErrorMessage::InactiveRundown).into());
+            }
+
+            deactivate_rundown_playlist(context, cache).await?;
+            Ok(())
+        })
+    })
+    .await
+}
+
+/**
+ * Reset a RundownPlaylist, so that it can be played from the beginning
+ */
+pub async fn handle_reset_rundown_playlist(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, |context, cache| {
+        Box::pin(async move {
+            let playlist = cache.playlist.doc();
+            if playlist.activation_id.is_some() && !playlist.rehearsal {
+                return Err(UserError::create(UserErrorMessage::RundownResetWhileActive).into());
+            }
+
+            reset_rundown_playlist(context, cache).await?;
+
+            if cache.playlist.doc().activation_id.is_some() {
+                update_timeline(context, cache, None).await?;
+            }
+
+            Ok(())
+        })
+    })
+    .await
+}
+
+pub async fn activate_rundown_playlist(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    rehearsal: bool,
+) -> Result<(), JobError> {
+    info!(
+        "Activating rundown {}{}",
+        cache.playlist.doc_id().unprotect(),
+        if rehearsal { " (Rehearsal)" } else { "" }
+    );
+
+    // Only one playlist can be active in a studio at a time
+    let other_active_playlists = find_other_active_playlists(context, cache.playlist.doc()).await?;
+    if !other_active_playlists.is_empty() {
+        return Err(
+            UserError::create(UserErrorMessage::RundownAlreadyActiveNames)
+                .with_arg(
+                    "names",
+                    other_active_playlists
+                        .iter()
+                        .map(|playlist| &playlist.name)
+                        .join(", "),
+                )
+                .into(),
+        );
+    }
+
+    if cache.playlist.doc().activation_id.is_none() {
+        // Reset the playlist if it wasnt already active
+        reset_rundown_playlist(context, cache).await?;
+    }
+
+    let new_activation_id = RundownPlaylistActivationId::new_from(get_random_id());
+    cache.playlist.update(|doc| {
+        let mut res = doc.clone();
+
+        res.activation_id = Some(new_activation_id.clone());
+        res.rehearsal = rehearsal;
+
+        Some(res)
+    })?;
+
+    let mut rundown: Option<Rundown> = None;
+
+    let current_part_instance = cache.get_current_part_instance();
+    if current_part_instance.map_or(true, |instance| instance.reset) {
+        cache.playlist.update(|doc| {
+            let mut res = doc.clone();
+
+            res.current_part_instance_id = None;
+            res.next_part_instance_id = None;
+            res.previous_part_instance_id = None;
+
+            Some(res)
+        })?;
+
+        // If we are not playing anything, then regenerate the next part
+        let first_part = select_next_part(
+            cache.playlist.doc(),
+            None,
+            None,
+            cache.get_ordered_segments_and_parts(),
+            true,
+        );
+
+        if let Some(first_part) = &first_part {
+            rundown = cache
+                .parts
+                .find_one_by_id(&first_part.part_id)
+                .and_then(|part| cache.rundowns.find_one_by_id(&part.rundown_id));
+        }
+
+        setNextPart(
+            context,
+            cache,
+            first_part.map(SetNextPartTarget::Part),
+            false,
+            None,
+        )
+        .await?;
+    } else {
+        // Otherwise preserve the active partInstances
+        let part_instances_to_preserve = {
+            let playlist = cache.playlist.doc();
+            [
+                playlist.next_part_instance_id.clone(),
+                playlist.current_part_instance_id.clone(),
+                playlist.previous_part_instance_id.clone(),
+            ]
+            .into_iter()
+            .flatten()
+            .collect::<HashSet<_>>()
+        };
+
+        cache.part_instances.update_all(|doc| {
+            if part_instances_to_preserve.contains(&doc.id) {
+                let mut res = doc.clone();
+                res.playlist_activation_id = new_activation_id.clone();
+                Some(res)
+            } else {
+                None
+            }
+        })?;
+        cache.piece_instances.update_all(|doc| {
+            if part_instances_to_preserve.contains(&doc.part_instance_id) {
+                let mut res = doc.clone();
+                res.playlist_activation_id = new_activation_id.clone();
+                Some(res)
+            } else {
+                None
+            }
+        })?;
+
+        if cache.playlist.doc().next_part_instance_id.is_some() {
+            let next_part_instance = cache
+                .get_next_part_instance()
+                .ok_or_else(|| JobError::Internal("Could not find nextPartInstance".to_string()))?;
+
+            rundown = cache
+                .rundowns
+                .find_one_by_id(&next_part_instance.rundown_id);
+        }
+    }
+
+    update_timeline(context, cache, None).await?;
+
+    if let Some(_rundown) = rundown {
+        // TODO - blueprint.onRundownActivate
+    }
+
+    Ok(())
+}
+
+/**
+ * Find any other playlists in the studio of the playlist which are active
+ */
+pub async fn find_other_active_playlists(
+    context: &JobContext,
+    playlist: &RundownPlaylist,
+) -> Result<Vec<RundownPlaylist>, JobError> {
+    context
+        .direct_collections()
+        .rundown_playlists
+        .find_fetch(
+            doc! {
+                "studioId": &playlist.studio_id,
+                // An inactive playlist has a null activationId
+                "activationId": { "$ne": null },
+                "_id": { "$ne": playlist.id.unprotect() },
+            },
+            None,
+        )
+        .await
+}
+
+pub async fn deactivate_rundown_playlist(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+) -> Result<Option<Rundown>, JobError> {
+    let rundown = deactivate_rundown_playlist_inner(context, cache).await?;
+
+    // The playlist is no longer active, so this clears the timeline
+    update_timeline(context, cache, None).await?;
+
+    // TODO - blueprint.onRundownDeActivate
+
+    Ok(rundown)
+}
+
+pub async fn deactivate_rundown_playlist_inner(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+) -> Result<Option<Rundown>, JobError> {
+    info!(
+        "Deactivating rundown playlist \"{}\"",
+        cache.playlist.doc_id().unprotect()
+    );
+
+    let current_part_instance = cache.get_current_part_instance();
+    let next_part_instance = cache.get_next_part_instance();
+
+    let rundown = if let Some(current_part_instance) = &current_part_instance {
+        let rundown = cache
+            .rundowns
+            .find_one_by_id(&current_part_instance.rundown_id);
+
+        if let Some(rundown) = &rundown {
+            let events_queue = context.events_queue().clone();
+            let job = EventsJob::NotifyCurrentlyPlayingPart {
+                rundown_id: rundown.id.clone(),
+                is_rehearsal: cache.playlist.doc().rehearsal,
+                part_external_id: None,
+            };
+
+            cache.defer_after_save(move |_collections| {
+                Box::pin(async move {
+                    // Tell the NRCS that nothing is playing, once the deactivation has been saved
+                    if let Err(err) = events_queue.queue(job) {
+                        error!("Failed to queue NotifyCurrentlyPlayingPart job: {}", err);
+                    }
+
+                    Ok(())
+                })
+            });
+        }
+
+        rundown
+    } else if let Some(next_part_instance) = &next_part_instance {
+        cache
+            .rundowns
+            .find_one_by_id(&next_part_instance.rundown_id)
+    } else {
+        None
+    };
+
+    cache.playlist.update(|doc| {
+        let mut res = doc.clone();
+
+        res.previous_part_instance_id = None;
+        res.current_part_instance_id = None;
+        res.hold_state = RundownHoldState::NONE;
+        res.activation_id = None;
+        res.next_segment_id = None;
+
+        Some(res)
+    })?;
+
+    setNextPart(context, cache, None, false, None).await?;
+
+    if let Some(current_part_instance) = &current_part_instance {
+        let now = Utc::now();
+
+        // Set the current PartInstance as stopped
+        cache
+            .part_instances
+            .update_one(&current_part_instance.id, |doc| {
+                if doc.timings.planned_started_playback.is_some()
+                    && doc.timings.planned_stopped_playback.is_none()
+                {
+                    let mut res = doc.clone();
+                    res.timings.planned_stopped_playback = Some(now);
+                    Some(res)
+                } else {
+                    None
+                }
+            })?;
+    }
+
+    Ok(rundown)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::direct_collections::DirectCollections, data_model::ids::PartId,
+        playout::fixtures::*,
+    };
+
+    /** Another playlist in the same studio, which has no rundowns */
+    fn create_other_playlist(active: bool) -> RundownPlaylist {
+        let mut playlist = create_playlist(&[]);
+        playlist.id = RundownPlaylistId::new_from("playlist1".to_string());
+        playlist.name = "playlist1".to_string();
+        if !active {
+            playlist.activation_id = None;
+        }
+        playlist
+    }
+
+    #[tokio::test]
+    async fn activate_selects_first_part() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+        data.rundown_playlists.push(create_other_playlist(false));
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_activate_rundown_playlist(&context, &playlist_id(), true)
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert!(playlist.activation_id.is_some());
+        assert!(playlist.rehearsal);
+        assert_eq!(playlist.current_part_instance_id, None);
+
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        let next = fetch_part_instance(&collections, &next_id).await;
+        assert_eq!(next.part.id, PartId::new_from("part0".to_string()));
+        assert_eq!(Some(next.playlist_activation_id), playlist.activation_id);
+    }
+
+    #[tokio::test]
+    async fn activate_is_rejected_when_another_playlist_is_active() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+        data.rundown_playlists.push(create_other_playlist(true));
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_activate_rundown_playlist(&context, &playlist_id(), false).await,
+            UserErrorMessage::RundownAlreadyActiveNames,
+        );
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.activation_id, None);
+        assert_eq!(playlist.next_part_instance_id, None);
+    }
+
+    #[tokio::test]
+    async fn deactivate_is_rejected_for_inactive_playlist() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_deactivate_rundown_playlist(&context, &playlist_id()).await,
+            UserErrorMessage::InactiveRundown,
+        );
+    }
+
+    #[tokio::test]
+    async fn deactivate_stops_playback() {
+        let mut data = create_playlist_data();
+        let (current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, mut events_receiver) = create_context(&collections);
+
+        handle_deactivate_rundown_playlist(&context, &playlist_id())
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.activation_id, None);
+        assert_eq!(playlist.current_part_instance_id, None);
+        assert_eq!(playlist.next_part_instance_id, None);
+
+        let current = fetch_part_instance(&collections, &current.id).await;
+        assert!(current.timings.planned_stopped_playback.is_some());
+        // The next PartInstance was never taken, so is no longer needed
+        assert_part_instance_removed(&collections, &next.id).await;
+
+        // The NRCS is told that nothing is playing
+        match events_receiver.try_recv() {
+            Ok(EventsJob::NotifyCurrentlyPlayingPart {
+                part_external_id, ..
+            }) => assert_eq!(part_external_id, None),
+            job => panic!("Unexpected events job {:?}", job),
+        }
+    }
+
+    #[tokio::test]
+    async fn reset_inactive_playlist() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+        let (current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_reset_rundown_playlist(&context, &playlist_id())
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert!(playlist.reset_time.is_some());
+        assert_eq!(playlist.activation_id, None);
+        assert_eq!(playlist.current_part_instance_id, None);
+        assert_eq!(playlist.next_part_instance_id, None);
+        assert!(fetch_part_instance(&collections, &current.id).await.reset);
+        assert_part_instance_removed(&collections, &next.id).await;
+    }
+
+    #[tokio::test]
+    async fn reset_is_rejected_while_on_air() {
+        let mut data = create_playlist_data();
+        let (current, _next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_reset_rundown_playlist(&context, &playlist_id()).await,
+            UserErrorMessage::RundownResetWhileActive,
+        );
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.current_part_instance_id, Some(current.id.clone()));
+        assert!(!fetch_part_instance(&collections, &current.id).await.reset);
+    }
+}
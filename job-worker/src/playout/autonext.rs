@@ -0,0 +1,160 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use chrono::{DateTime, Duration, Utc};
+use log::error;
+use tokio::{sync::Notify, time::sleep};
+
+use crate::{
+    cache::object::DbCacheReadObject,
+    context::context::JobContext,
+    data_model::{
+        ids::{PartInstanceId, ProtectedId, RundownPlaylistId},
+        part_instance::PartInstance,
+        rundown_playlist::RundownHoldState,
+    },
+};
+
+use super::{cache::PlayoutCache, take::handle_take_next_part};
+
+/**
+ * An autonext which is waiting to be performed
+ */
+#[derive(Clone, PartialEq, Eq)]
+pub struct ScheduledAutonext {
+    /** The PartInstance which must still be current for the take to be performed */
+    pub part_instance_id: PartInstanceId,
+    pub at: DateTime<Utc>,
+}
+
+/**
+ * Performs the take for any parts which autonext, once their time has run out.
+ * There is at most one autonext scheduled for each playlist, which is replaced whenever a job changes the playlist
+ */
+pub struct AutonextScheduler {
+    scheduled: RefCell<HashMap<RundownPlaylistId, ScheduledAutonext>>,
+    changed: Notify,
+}
+impl AutonextScheduler {
+    pub fn create() -> AutonextScheduler {
+        AutonextScheduler {
+            scheduled: RefCell::new(HashMap::new()),
+            changed: Notify::new(),
+        }
+    }
+
+    /**
+     * Schedule, reschedule or cancel the autonext for the playlist of the cache.
+     * This should be called once the cache has been written to the database
+     */
+    pub fn update_from_cache(&self, cache: &PlayoutCache) {
+        let playlist_id = cache.playlist.doc_id();
+        let autonext = calculate_autonext(cache);
+
+        let changed = {
+            let mut scheduled = self.scheduled.borrow_mut();
+            match autonext {
+                Some(autonext) => {
+                    scheduled.insert(playlist_id.clone(), autonext.clone()) != Some(autonext)
+                }
+                None => scheduled.remove(playlist_id).is_some(),
+            }
+        };
+
+        if changed {
+            self.changed.notify_one();
+        }
+    }
+
+    fn get_first_due(&self) -> Option<(RundownPlaylistId, ScheduledAutonext)> {
+        self.scheduled
+            .borrow()
+            .iter()
+            .min_by_key(|(_, autonext)| autonext.at)
+            .map(|(id, autonext)| (id.clone(), autonext.clone()))
+    }
+
+    /**
+     * Perform the autonexts as they become due. This never returns
+     */
+    pub async fn run(&self, context: &JobContext) {
+        loop {
+            match self.get_first_due() {
+                Some((playlist_id, autonext)) => {
+                    let delay = (autonext.at - Utc::now())
+                        .max(Duration::zero())
+                        .to_std()
+                        .unwrap_or_default();
+
+                    tokio::select! {
+                        _ = sleep(delay) => {
+                            // The playlist may have been rescheduled while we were waiting
+                            let is_still_scheduled =
+                                self.scheduled.borrow().get(&playlist_id) == Some(&autonext);
+                            if is_still_scheduled {
+                                self.scheduled.borrow_mut().remove(&playlist_id);
+
+                                if let Err(err) = handle_take_next_part(
+                                    context,
+                                    &playlist_id,
+                                    Some(autonext.part_instance_id),
+                                )
+                                .await
+                                {
+                                    error!(
+                                        "Autonext of playlist \"{}\" failed: {}",
+                                        playlist_id.unprotect(),
+                                        err
+                                    );
+                                }
+                            }
+                        }
+                        _ = self.changed.notified() => {}
+                    }
+                }
+                None => self.changed.notified().await,
+            }
+        }
+    }
+}
+
+fn calculate_autonext(cache: &PlayoutCache) -> Option<ScheduledAutonext> {
+    let playlist = cache.playlist.doc();
+    if playlist.activation_id.is_none()
+        || playlist.next_part_instance_id.is_none()
+        || (playlist.hold_state != RundownHoldState::NONE
+            && playlist.hold_state != RundownHoldState::COMPLETE)
+    {
+        return None;
+    }
+
+    let current_part_instance = cache.get_current_part_instance()?;
+    calculate_autonext_time(&current_part_instance).map(|at| ScheduledAutonext {
+        part_instance_id: current_part_instance.id,
+        at,
+    })
+}
+
+/**
+ * Calculate when a PartInstance should autonext, if it does
+ */
+pub fn calculate_autonext_time(part_instance: &PartInstance) -> Option<DateTime<Utc>> {
+    if !part_instance.part.autonext {
+        return None;
+    }
+
+    let start = part_instance.timings.planned_started_playback?;
+    let expected_duration = part_instance.part.expected_duration?;
+
+    // The content of the part is delayed by any transition into it
+    let to_part_delay = part_instance
+        .part_playout_timings
+        .as_ref()
+        .map_or(Duration::zero(), |timings| timings.to_part_delay);
+    // The next part is started early by the overlap, so that they play together
+    let overlap = part_instance
+        .part
+        .autonext_overlap
+        .unwrap_or_else(Duration::zero);
+
+    Some(start + to_part_delay + expected_duration - overlap)
+}
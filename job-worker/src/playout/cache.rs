@@ -1,21 +1,27 @@
 use std::{collections::HashMap, rc::Rc};
 
-use futures::TryFutureExt;
+use chrono::{Duration, Utc};
+use futures::{future::LocalBoxFuture, TryFutureExt};
 use itertools::Itertools;
-use mongodb::bson::doc;
+use mongodb::{
+    bson::doc,
+    error::{TRANSIENT_TRANSACTION_ERROR, UNKNOWN_TRANSACTION_COMMIT_RESULT},
+    ClientSession,
+};
 use tokio::join;
 
 use crate::{
     cache::{
         collection::{DbCacheReadCollection, DbCacheWriteCollectionImpl},
         doc::DocWithId,
-        object::{DbCacheReadObject, DbCacheWriteObjectImpl},
+        object::{ConcurrencyGuard, DbCacheReadObject, DbCacheWriteObjectImpl},
+        save::{CacheSaveError, PendingChange, PendingSave},
     },
-    context::direct_collections::{DirectCollections, MongoReadOnlyCollection},
+    context::direct_collections::{DirectCollections, FindOptions, MongoBulkWriteOperation},
     data_model::{
         ids::{
             unprotect_optional, PartId, PartInstanceId, PieceInstanceId, ProtectedId, RundownId,
-            RundownPlaylistActivationId, RundownPlaylistId, SegmentId, ShowStyleBaseId,
+            RundownPlaylistActivationId, RundownPlaylistId, SegmentId, ShowStyleBaseId, StudioId,
         },
         part::Part,
         part_instance::PartInstance,
@@ -23,7 +29,9 @@ use crate::{
         rundown::Rundown,
         rundown_playlist::RundownPlaylist,
         segment::Segment,
+        timeline::TimelineComplete,
     },
+    error::{DocumentId, JobError},
     playout::playlist::sort_segments_in_rundowns,
 };
 
@@ -39,24 +47,45 @@ impl<'a> DocWithId<'a, RundownPlaylistActivationId> for FakeDoc {
     }
 }
 
+/** How many times a transaction, or the commit of one, is attempted before giving up on a transient error */
+const MAX_TRANSACTION_ATTEMPTS: usize = 5;
+
+/**
+ * How the changes in a cache should be written to the database
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheWriteMode {
+    /** Write everything inside a transaction. This requires the database to be a replica set */
+    Transaction,
+    /** Write each collection in turn, reporting which were saved if one fails */
+    BestEffort,
+}
+
+/**
+ * A function to be run once the cache has been written to the database
+ */
+pub type DeferredAfterSaveFn =
+    Box<dyn for<'a> FnOnce(&'a Rc<DirectCollections>) -> LocalBoxFuture<'a, Result<(), JobError>>>;
+
 pub struct PlayoutCache {
     pub playlist: DbCacheWriteObjectImpl<RundownPlaylist, RundownPlaylistId>,
 
-    // pub playlist_lock: Rc<>
     pub rundowns: DbCacheWriteCollectionImpl<Rundown, RundownId>,
     pub segments: DbCacheWriteCollectionImpl<Segment, SegmentId>,
     pub parts: DbCacheWriteCollectionImpl<Part, PartId>,
     pub part_instances: DbCacheWriteCollectionImpl<PartInstance, PartInstanceId>,
     pub piece_instances: DbCacheWriteCollectionImpl<PieceInstance, PieceInstanceId>,
     // pub baseline_objects: DbCacheWriteCollectionImpl<FakeDoc, RundownPlaylistActivationId>,
-    // pub timeline: DbCacheWriteObjectImpl<FakeDoc, RundownPlaylistActivationId>,
+    /** The timeline generated by this job, if any. This is written once the rest of the cache has been saved */
+    timeline: Option<TimelineComplete>,
     // pub peripheral_devices: DbCacheWriteCollectionImpl<FakeDoc, RundownPlaylistActivationId>,
+    deferred_after_save: Vec<DeferredAfterSaveFn>,
 }
 impl PlayoutCache {
     pub async fn create(
         collections: &Rc<DirectCollections>,
         playlist_id: &RundownPlaylistId,
-    ) -> Result<PlayoutCache, String> {
+    ) -> Result<PlayoutCache, JobError> {
         let playlist = collections
             .rundown_playlists
             .find_one_by_id(playlist_id, None)
@@ -89,14 +118,20 @@ impl PlayoutCache {
 
             let partInstancesCollection = {
                 // Future: We could optimise away this query if we tracked the segmentIds of these PartInstances on the playlist
-                // TODO - projection
                 collections
                     .part_instances
-                    .find_fetch(doc! { "_id": {"$in": &selectedPartInstanceIds}}, None)
+                    .find_fetch_raw(
+                        doc! { "_id": {"$in": &selectedPartInstanceIds}},
+                        Some(FindOptions {
+                            projection: Some(doc! { "segmentId": 1 }),
+                            ..Default::default()
+                        }),
+                    )
                     .and_then(|aa| {
                         let segmentIds = aa
-                            .into_iter()
-                            .map(|instance| instance.segment_id.unprotect_move())
+                            .iter()
+                            .filter_map(|instance| instance.get_str("segmentId").ok())
+                            .map(|id| id.to_string())
                             .unique()
                             .collect::<Vec<_>>();
 
@@ -179,7 +214,15 @@ impl PlayoutCache {
                 playlist: DbCacheWriteObjectImpl::from_document(
                     "rundownPlaylist".to_string(),
                     playlist,
-                ),
+                )
+                .with_concurrency_guard(ConcurrencyGuard {
+                    field: "modified",
+                    bump: |playlist| {
+                        // Ensure the value changes, even if the clock hasn't moved far enough
+                        playlist.modified =
+                            Utc::now().max(playlist.modified + Duration::milliseconds(1));
+                    },
+                }),
 
                 rundowns: DbCacheWriteCollectionImpl::from_documents(
                     "rundowns".to_string(),
@@ -198,9 +241,14 @@ impl PlayoutCache {
                     "pieceInstances".to_string(),
                     &piece_instances,
                 ),
+
+                timeline: None,
+                deferred_after_save: Vec::new(),
             })
         } else {
-            Err(format!("RundownPlaylist \"{}\" was not found", playlist_id))
+            Err(JobError::NotFound(DocumentId::RundownPlaylist(
+                playlist_id.clone(),
+            )))
         }
     }
 
@@ -260,42 +308,281 @@ impl PlayoutCache {
             .collect()
     }
 
+    /**
+     * Register a function to be run after the cache has been successfully written to the database.
+     * This is intended for low priority work which should not delay the save, or which needs to see the saved documents
+     */
+    pub fn defer_after_save<F>(&mut self, func: F)
+    where
+        F: for<'a> FnOnce(&'a Rc<DirectCollections>) -> LocalBoxFuture<'a, Result<(), JobError>>
+            + 'static,
+    {
+        self.deferred_after_save.push(Box::new(func));
+    }
+
+    /**
+     * Set the timeline to be written when the cache is saved, replacing any previously generated by this job
+     */
+    pub fn set_timeline(&mut self, timeline: TimelineComplete) {
+        self.timeline = Some(timeline);
+    }
+
     pub async fn write_to_database(
         &mut self,
         collections: &Rc<DirectCollections>,
-    ) -> Result<(), String> {
-        let errs = join!(
-            self.playlist
-                .save_into_collection(&collections.rundown_playlists),
-            self.rundowns.save_into_collection(&collections.rundowns),
-            self.segments.save_into_collection(&collections.segments),
-            self.parts.save_into_collection(&collections.parts),
-            self.part_instances
-                .save_into_collection(&collections.part_instances),
-            self.piece_instances
-                .save_into_collection(&collections.piece_instances),
-        );
-
-        let results = [errs.0, errs.1, errs.2, errs.3, errs.4, errs.5];
-
-        let errs = results
-            .into_iter()
-            .filter_map(|r| match r {
-                Ok(_) => None,
-                Err(e) => Some(e),
-            })
-            .collect::<Vec<_>>();
+        mode: CacheWriteMode,
+    ) -> Result<(), CacheSaveError> {
+        match mode {
+            CacheWriteMode::Transaction => self.write_to_database_in_transaction(collections).await,
+            CacheWriteMode::BestEffort => self.write_to_database_best_effort(collections).await,
+        }?;
+
+        // The functions are only run once, even if a later save is made
+        let deferred = std::mem::take(&mut self.deferred_after_save);
+
+        let mut errors = Vec::new();
+        for func in deferred {
+            // Keep going, as the other functions don't depend on each other
+            if let Err(err) = func(collections).await {
+                errors.push(err);
+            }
+        }
 
-        if !errs.is_empty() {
-            let str = errs.join("\n");
-            Err(str)
-        } else {
+        if errors.is_empty() {
             Ok(())
+        } else {
+            Err(CacheSaveError::AfterSave(errors))
+        }
+    }
+
+    /**
+     * Determine the write needed to save the generated timeline, if there is one
+     */
+    fn prepare_timeline_save(&self, name: &str) -> PendingSave<TimelineComplete, StudioId> {
+        let mut pending = PendingSave::new(name);
+
+        if let Some(timeline) = &self.timeline {
+            pending.push(
+                MongoBulkWriteOperation::ReplaceOne {
+                    doc: timeline.clone(),
+                    upsert: true,
+                },
+                PendingChange {
+                    ids: vec![timeline.id.clone()],
+                    document: Some(timeline.clone()),
+                },
+            );
+        }
+
+        pending
+    }
+
+    /**
+     * Write all the changes inside of a transaction, so that either everything or nothing is saved.
+     * The transaction is retried if it fails with a transient error, such as a write conflict
+     */
+    async fn write_to_database_in_transaction(
+        &mut self,
+        collections: &Rc<DirectCollections>,
+    ) -> Result<(), CacheSaveError> {
+        let client = collections.client().ok_or_else(|| {
+            CacheSaveError::Failed("Transactions are not supported by the collections".to_string())
+        })?;
+
+        let wrap_error = |err: mongodb::error::Error| {
+            CacheSaveError::Failed(format!("Transaction failed: {}", err))
+        };
+
+        let mut session = client.start_session(None).await.map_err(wrap_error)?;
+
+        let mut attempt = 1;
+        loop {
+            // The operations are consumed by executing them, so they are prepared afresh for each attempt
+            let mut part_instances = self.part_instances.prepare_save();
+            let mut piece_instances = self.piece_instances.prepare_save();
+            let mut segments = self.segments.prepare_save();
+            let mut parts = self.parts.prepare_save();
+            let mut rundowns = self.rundowns.prepare_save();
+            let mut playlist = self.playlist.prepare_save()?;
+            let mut timeline = self.prepare_timeline_save(collections.timelines.name());
+
+            session.start_transaction(None).await.map_err(wrap_error)?;
+
+            let result = async {
+                part_instances
+                    .execute(collections.part_instances.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+                piece_instances
+                    .execute(collections.piece_instances.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+                segments
+                    .execute(collections.segments.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+                parts
+                    .execute(collections.parts.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+                rundowns
+                    .execute(collections.rundowns.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+                playlist
+                    .execute(collections.rundown_playlists.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+                // The timeline references the other documents, so is written last
+                timeline
+                    .execute(collections.timelines.as_ref(), Some(&mut session))
+                    .await
+                    .1?;
+
+                Ok::<(), CacheSaveError>(())
+            }
+            .await;
+
+            if let Err(err) = result {
+                // Nothing was saved, so the cache is left untouched
+                if let Err(abort_err) = session.abort_transaction().await {
+                    return Err(CacheSaveError::Failed(format!(
+                        "{}\nAbort failed: {}",
+                        err, abort_err
+                    )));
+                }
+
+                if err.is_transient() && attempt < MAX_TRANSACTION_ATTEMPTS {
+                    attempt += 1;
+                    continue;
+                }
+                return Err(err);
+            }
+
+            match commit_transaction_with_retry(&mut session).await {
+                Ok(()) => {}
+                Err(err)
+                    if err.contains_label(TRANSIENT_TRANSACTION_ERROR)
+                        && attempt < MAX_TRANSACTION_ATTEMPTS =>
+                {
+                    // The transaction was aborted by the server, so can be attempted again
+                    attempt += 1;
+                    continue;
+                }
+                Err(err) => return Err(wrap_error(err)),
+            }
+
+            // Everything is now in the database
+            let count = part_instances.len();
+            self.part_instances.complete_save(part_instances, count);
+            let count = piece_instances.len();
+            self.piece_instances.complete_save(piece_instances, count);
+            let count = segments.len();
+            self.segments.complete_save(segments, count);
+            let count = parts.len();
+            self.parts.complete_save(parts, count);
+            let count = rundowns.len();
+            self.rundowns.complete_save(rundowns, count);
+            let count = playlist.len();
+            self.playlist.complete_save(playlist, count);
+            self.timeline = None;
+
+            return Ok(());
         }
+    }
+
+    /**
+     * Write the changes one collection at a time, stopping at the first failure.
+     * A conflict on the playlist is detected before anything is written.
+     * The playlist is written after the documents it references, so that a failure doesn't leave it referencing documents which were not written.
+     * The timeline references all of them, so is written last
+     */
+    async fn write_to_database_best_effort(
+        &mut self,
+        collections: &Rc<DirectCollections>,
+    ) -> Result<(), CacheSaveError> {
+        let order = [
+            collections.part_instances.name(),
+            collections.piece_instances.name(),
+            collections.segments.name(),
+            collections.parts.name(),
+            collections.rundowns.name(),
+            collections.rundown_playlists.name(),
+            collections.timelines.name(),
+        ];
+        let partial_failure = |index: usize, err: CacheSaveError| {
+            if index == 0 {
+                // Nothing has been saved
+                err
+            } else {
+                CacheSaveError::Partial {
+                    saved: order[..index].iter().map(|s| s.to_string()).collect(),
+                    not_attempted: order[index + 1..].iter().map(|s| s.to_string()).collect(),
+                    error: Box::new(err),
+                }
+            }
+        };
+
+        // The writes can't be undone, so ensure the playlist hasn't been modified by someone else before anything is written
+        self.playlist
+            .claim_for_save(collections.rundown_playlists.as_ref())
+            .await?;
+
+        self.part_instances
+            .save_into_collection(collections.part_instances.as_ref())
+            .await
+            .map_err(|err| partial_failure(0, err))?;
+        self.piece_instances
+            .save_into_collection(collections.piece_instances.as_ref())
+            .await
+            .map_err(|err| partial_failure(1, err))?;
+        self.segments
+            .save_into_collection(collections.segments.as_ref())
+            .await
+            .map_err(|err| partial_failure(2, err))?;
+        self.parts
+            .save_into_collection(collections.parts.as_ref())
+            .await
+            .map_err(|err| partial_failure(3, err))?;
+        self.rundowns
+            .save_into_collection(collections.rundowns.as_ref())
+            .await
+            .map_err(|err| partial_failure(4, err))?;
+        self.playlist
+            .save_into_collection(collections.rundown_playlists.as_ref())
+            .await
+            .map_err(|err| partial_failure(5, err))?;
+
+        // Keep the timeline if the write fails, so that it is written by a retry of the save
+        let mut timeline = self.prepare_timeline_save(collections.timelines.name());
+        timeline
+            .execute(collections.timelines.as_ref(), None)
+            .await
+            .1
+            .map_err(|err| partial_failure(6, err))?;
+        self.timeline = None;
+
+        Ok(())
+    }
+}
 
-        // // pub baseline_objects: DbCacheWriteCollectionImpl<FakeDoc, RundownPlaylistActivationId>,
-        // // pub timeline: DbCacheWriteObjectImpl<FakeDoc, RundownPlaylistActivationId>,
-        // // pub peripheral_devices: DbCacheWriteCollectionImpl<FakeDoc, RundownPlaylistActivationId>,
+/**
+ * Commit the transaction, retrying if the outcome of the commit is unknown
+ */
+async fn commit_transaction_with_retry(
+    session: &mut ClientSession,
+) -> Result<(), mongodb::error::Error> {
+    let mut attempt = 1;
+    loop {
+        match session.commit_transaction().await {
+            Err(err)
+                if err.contains_label(UNKNOWN_TRANSACTION_COMMIT_RESULT)
+                    && attempt < MAX_TRANSACTION_ATTEMPTS =>
+            {
+                attempt += 1;
+            }
+            result => return result,
+        }
     }
 }
 
@@ -315,3 +602,203 @@ fn get_rundowns_segments_and_parts_from_caches(
 
     SegmentsAndParts { segments, parts }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use mongodb::bson;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        cache::{collection::DbCacheWriteCollection, object::DbCacheWriteObject},
+        context::direct_collections::InMemoryCollectionsData,
+    };
+
+    const PLAYLIST_ID: &str = "playlist0";
+    const PART_INSTANCE_ID: &str = "part0_instance";
+
+    fn create_collections() -> Rc<DirectCollections> {
+        let part = json!({
+            "_id": "part0",
+            "_rank": 0,
+            "rundownId": "rundown0",
+            "segmentId": "segment0",
+            "externalId": "part0",
+            "title": "part0",
+        });
+
+        DirectCollections::create_in_memory(InMemoryCollectionsData {
+            rundown_playlists: vec![serde_json::from_value(json!({
+                "_id": PLAYLIST_ID,
+                "externalId": PLAYLIST_ID,
+                "studioId": "studio0",
+                "name": PLAYLIST_ID,
+                "created": 0,
+                "modified": 1000,
+                "timing": {},
+                "activationId": "activation0",
+                "currentPartInstanceId": null,
+                "nextPartInstanceId": PART_INSTANCE_ID,
+                "previousPartInstanceId": null,
+                "nextSegmentId": null,
+                "rundownIdsInOrder": ["rundown0"],
+            }))
+            .unwrap()],
+            rundowns: vec![serde_json::from_value(json!({
+                "_id": "rundown0",
+                "externalId": "rundown0",
+                "name": "rundown0",
+                "studioId": "studio0",
+                "showStyleBaseId": "showstyle0",
+                "showStyleVariantId": "variant0",
+                "playlistId": PLAYLIST_ID,
+                "created": 0,
+                "modified": 0,
+                "importVersions": {},
+                "timing": {},
+            }))
+            .unwrap()],
+            parts: vec![serde_json::from_value(part.clone()).unwrap()],
+            part_instances: vec![serde_json::from_value(json!({
+                "_id": PART_INSTANCE_ID,
+                "rundownId": "rundown0",
+                "segmentId": "segment0",
+                "playlistActivationId": "activation0",
+                "segmentPlayoutId": "playout0",
+                "part": part,
+                "timings": { "setAsNext": 0 },
+                "takeCount": 0,
+                "rehearsal": false,
+            }))
+            .unwrap()],
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    /** Make a change to both the playlist and a part instance in the cache */
+    fn modify_cache(cache: &mut PlayoutCache, take_count: u64) {
+        cache
+            .part_instances
+            .update_one(
+                &PartInstanceId::new_from(PART_INSTANCE_ID.to_string()),
+                |instance| {
+                    let mut instance = instance.clone();
+                    instance.take_count = take_count;
+                    Some(instance)
+                },
+            )
+            .unwrap();
+        cache
+            .playlist
+            .update(|playlist| {
+                let mut playlist = playlist.clone();
+                playlist.name = format!("take {}", take_count);
+                Some(playlist)
+            })
+            .unwrap();
+    }
+
+    fn fetch_state(collections: &Rc<DirectCollections>) -> (RundownPlaylist, PartInstance) {
+        let playlist = block_on(
+            collections
+                .rundown_playlists
+                .find_one_by_id(&RundownPlaylistId::new_from(PLAYLIST_ID.to_string()), None),
+        )
+        .unwrap()
+        .unwrap();
+        let part_instance = block_on(collections.part_instances.find_one_by_id(
+            &PartInstanceId::new_from(PART_INSTANCE_ID.to_string()),
+            None,
+        ))
+        .unwrap()
+        .unwrap();
+
+        (playlist, part_instance)
+    }
+
+    #[test]
+    fn best_effort_save_bumps_playlist_modified() {
+        let collections = create_collections();
+        let mut cache = block_on(PlayoutCache::create(
+            &collections,
+            &RundownPlaylistId::new_from(PLAYLIST_ID.to_string()),
+        ))
+        .unwrap();
+
+        // Saving repeatedly from the same cache must keep working
+        for take_count in 1..3 {
+            let previous_modified = cache.playlist.doc().modified;
+            modify_cache(&mut cache, take_count);
+
+            block_on(cache.write_to_database(&collections, CacheWriteMode::BestEffort)).unwrap();
+
+            let (playlist, part_instance) = fetch_state(&collections);
+            assert_eq!(part_instance.take_count, take_count);
+            assert_eq!(playlist.name, format!("take {}", take_count));
+            assert!(playlist.modified > previous_modified);
+            // The database only stores the milliseconds, so compare the serialized forms
+            assert_eq!(
+                bson::to_document(&playlist).unwrap(),
+                bson::to_document(cache.playlist.doc()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn stale_playlist_leaves_other_collections_untouched() {
+        let collections = create_collections();
+        let mut cache = block_on(PlayoutCache::create(
+            &collections,
+            &RundownPlaylistId::new_from(PLAYLIST_ID.to_string()),
+        ))
+        .unwrap();
+
+        // Someone else saves the playlist after the cache was loaded
+        block_on(collections.rundown_playlists.update_one(
+            doc! { "_id": PLAYLIST_ID },
+            doc! { "$set": { "modified": 2000_i64, "name": "other" } },
+        ))
+        .unwrap();
+
+        modify_cache(&mut cache, 1);
+        let err = block_on(cache.write_to_database(&collections, CacheWriteMode::BestEffort))
+            .unwrap_err();
+        assert!(matches!(err, CacheSaveError::Conflict { .. }));
+
+        let (playlist, part_instance) = fetch_state(&collections);
+        assert_eq!(part_instance.take_count, 0);
+        assert_eq!(playlist.name, "other");
+        assert_eq!(playlist.modified.timestamp_millis(), 2000);
+
+        // The changes are kept in the cache
+        assert!(cache.playlist.is_modified());
+    }
+
+    #[test]
+    fn removed_documents_are_deleted_on_save() {
+        let collections = create_collections();
+        let mut cache = block_on(PlayoutCache::create(
+            &collections,
+            &RundownPlaylistId::new_from(PLAYLIST_ID.to_string()),
+        ))
+        .unwrap();
+
+        let removed = cache
+            .part_instances
+            .remove_by_filter(|instance| instance.id.unprotect() == PART_INSTANCE_ID)
+            .unwrap();
+        assert_eq!(removed.len(), 1);
+        assert!(cache.part_instances.find_all().is_empty());
+
+        block_on(cache.write_to_database(&collections, CacheWriteMode::BestEffort)).unwrap();
+
+        let part_instance = block_on(collections.part_instances.find_one_by_id(
+            &PartInstanceId::new_from(PART_INSTANCE_ID.to_string()),
+            None,
+        ))
+        .unwrap();
+        assert!(part_instance.is_none());
+    }
+}
@@ -2,15 +2,21 @@ use std::collections::{HashMap, HashSet};
 
 use itertools::Itertools;
 
+use mongodb::bson::{doc, Document};
+
 use crate::{
-    cache::{collection::DbCacheReadCollection, object::DbCacheReadObject},
+    cache::{
+        collection::{DbCacheReadCollection, DbCacheWriteCollection},
+        object::DbCacheReadObject,
+    },
     constants::PRESERVE_UNSYNCED_PLAYING_SEGMENT_CONTENTS,
-    context::context::JobContext,
+    context::{context::JobContext, direct_collections::FindOptions},
     data_model::{
-        ids::{RundownId, SegmentId},
-        part_instance::PartInstanceOrphaned,
+        ids::{unprotect_array, PartId, PartInstanceId, ProtectedId, RundownId, SegmentId},
+        part_instance::{PartInstance, PartInstanceOrphaned},
         segment::SegmentOrphaned,
     },
+    error::JobError,
 };
 
 use super::cache::PlayoutCache;
@@ -19,7 +25,10 @@ use super::cache::PlayoutCache;
  * Cleanup any orphaned (deleted) segments and partinstances once they are no longer being played
  * @param cache
  */
-pub async fn cleanupOrphanedItems(context: &JobContext, cache: &mut PlayoutCache) {
+pub async fn cleanupOrphanedItems(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+) -> Result<(), JobError> {
     let selectedPartInstancesSegmentIds = {
         let mut res = HashSet::new();
 
@@ -111,8 +120,15 @@ pub async fn cleanupOrphanedItems(context: &JobContext, cache: &mut PlayoutCache
 
     // Cleanup any instances from above
     if !removePartInstanceIds.is_empty() {
-        resetPartInstancesWithPieceInstances(context, cache, "AA".to_string()); //{ _id: { $in: removePartInstanceIds } })
+        resetPartInstancesWithPieceInstances(
+            context,
+            cache,
+            PartInstanceSelector::Ids(removePartInstanceIds),
+        )
+        .await?;
     }
+
+    Ok(())
 }
 
 struct AlterOrphanedSegmentIds {
@@ -121,89 +137,271 @@ struct AlterOrphanedSegmentIds {
 }
 
 /**
- * Reset selected or all partInstances with their pieceInstances
- * @param cache
- * @param selector if not provided, all partInstances will be reset
+ * Selects which PartInstances should be reset
+ */
+#[derive(Clone)]
+pub enum PartInstanceSelector {
+    /** All of the PartInstances in the playlist */
+    All,
+    /** The PartInstances with the given ids */
+    Ids(Vec<PartInstanceId>),
+    /** The PartInstances of a Part, excluding the given ids */
+    InstancesOfPart {
+        rundown_id: RundownId,
+        part_id: PartId,
+        exclude_ids: Vec<PartInstanceId>,
+    },
+}
+impl PartInstanceSelector {
+    /**
+     * Check if a PartInstance in the cache is matched by the selector
+     */
+    pub fn matches(&self, part_instance: &PartInstance) -> bool {
+        match self {
+            PartInstanceSelector::All => true,
+            PartInstanceSelector::Ids(ids) => ids.contains(&part_instance.id),
+            PartInstanceSelector::InstancesOfPart {
+                rundown_id,
+                part_id,
+                exclude_ids,
+            } => {
+                &part_instance.rundown_id == rundown_id
+                    && &part_instance.part.id == part_id
+                    && !exclude_ids.contains(&part_instance.id)
+            }
+        }
+    }
+
+    /**
+     * Build a query matching the same PartInstances in the database
+     */
+    pub fn to_query(&self) -> Document {
+        match self {
+            PartInstanceSelector::All => doc! {},
+            PartInstanceSelector::Ids(ids) => doc! { "_id": { "$in": unprotect_array(ids) } },
+            PartInstanceSelector::InstancesOfPart {
+                rundown_id,
+                part_id,
+                exclude_ids,
+            } => doc! {
+                "_id": { "$nin": unprotect_array(exclude_ids) },
+                "rundownId": rundown_id.unprotect(),
+                "part._id": part_id.unprotect(),
+            },
+        }
+    }
+}
+
+/**
+ * Reset selected partInstances with their pieceInstances.
+ * Any which are in the cache are reset immediately, the rest are reset in the database once the cache has been saved
  */
-pub fn resetPartInstancesWithPieceInstances(
-    _context: &JobContext,
-    _cache: &mut PlayoutCache,
-    _selector: String, // MongoQuery<DBPartInstance>
-) {
-    todo!()
-    // const partInstancesToReset = cache.PartInstances.updateAll((p) => {
-    // 	if (!p.reset && (!selector || mongoWhere(p, selector))) {
-    // 		p.reset = true
-    // 		return p
-    // 	} else {
-    // 		return false
-    // 	}
-    // })
-
-    // // Reset any in the cache now
-    // if (partInstancesToReset.length) {
-    // 	cache.PieceInstances.updateAll((p) => {
-    // 		if (!p.reset && partInstancesToReset.includes(p.partInstanceId)) {
-    // 			p.reset = true
-    // 			return p
-    // 		} else {
-    // 			return false
-    // 		}
-    // 	})
-    // }
-
-    // cache.defer_after_save(|cache| {
-    //     //
-    // });
-
-    // // Defer ones which arent loaded
-    // cache.deferAfterSave(async (cache) => {
-    // 	const partInstanceIdsInCache = cache.PartInstances.findAll(null).map((p) => p._id)
-
-    // 	// Find all the partInstances which are not loaded, but should be reset
-    // 	const resetInDb = await context.directCollections.PartInstances.findFetch(
-    // 		{
-    // 			$and: [
-    // 				selector ?? {},
-    // 				{
-    // 					// Not any which are in the cache, as they have already been done if needed
-    // 					_id: { $nin: partInstanceIdsInCache },
-    // 					reset: { $ne: true },
-    // 				},
-    // 			],
-    // 		},
-    // 		{ projection: { _id: 1 } }
-    // 	).then((ps) => ps.map((p) => p._id))
-
-    // 	// Do the reset
-    // 	const allToReset = [...resetInDb, ...partInstancesToReset]
-    // 	await Promise.all([
-    // 		resetInDb.length
-    // 			? context.directCollections.PartInstances.update(
-    // 					{
-    // 						_id: { $in: resetInDb },
-    // 						reset: { $ne: true },
-    // 					},
-    // 					{
-    // 						$set: {
-    // 							reset: true,
-    // 						},
-    // 					}
-    // 			  )
-    // 			: undefined,
-    // 		allToReset.length
-    // 			? context.directCollections.PieceInstances.update(
-    // 					{
-    // 						partInstanceId: { $in: allToReset },
-    // 						reset: { $ne: true },
-    // 					},
-    // 					{
-    // 						$set: {
-    // 							reset: true,
-    // 						},
-    // 					}
-    // 			  )
-    // 			: undefined,
-    // 	])
-    // })
+pub async fn resetPartInstancesWithPieceInstances(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    selector: PartInstanceSelector,
+) -> Result<(), JobError> {
+    // Find the ones which aren't loaded now, as the cache can gain new PartInstances before it is saved
+    let part_instance_ids_in_cache = cache
+        .part_instances
+        .find_all()
+        .into_iter()
+        .map(|p| p.id.unprotect_move())
+        .collect_vec();
+    let rundown_ids = cache
+        .get_rundown_ids_from_cache()
+        .into_iter()
+        .map(|id| id.unprotect_move())
+        .collect_vec();
+
+    let reset_in_db = context
+        .direct_collections()
+        .part_instances
+        .find_fetch_raw(
+            doc! {
+                "$and": [
+                    selector.to_query(),
+                    {
+                        // Not any which are in the cache, as they are done below if needed
+                        "_id": { "$nin": part_instance_ids_in_cache },
+                        "rundownId": { "$in": rundown_ids },
+                        "reset": { "$ne": true },
+                    },
+                ]
+            },
+            Some(FindOptions {
+                projection: Some(doc! { "_id": 1 }),
+                ..FindOptions::default()
+            }),
+        )
+        .await?
+        .into_iter()
+        .filter_map(|p| p.get_str("_id").ok().map(|id| id.to_string()))
+        .collect_vec();
+
+    // Reset any in the cache now
+    let part_instances_to_reset = cache.part_instances.update_all(|p| {
+        if !p.reset && selector.matches(p) {
+            let mut res = p.clone();
+            res.reset = true;
+            Some(res)
+        } else {
+            None
+        }
+    })?;
+
+    if !part_instances_to_reset.is_empty() {
+        cache.piece_instances.update_all(|p| {
+            if !p.reset && part_instances_to_reset.contains(&p.part_instance_id) {
+                let mut res = p.clone();
+                res.reset = true;
+                Some(res)
+            } else {
+                None
+            }
+        })?;
+    }
+
+    // Defer the ones which aren't loaded, along with any PieceInstances not loaded for those in the cache
+    let all_to_reset = reset_in_db
+        .iter()
+        .cloned()
+        .chain(
+            part_instances_to_reset
+                .into_iter()
+                .map(|id| id.unprotect_move()),
+        )
+        .collect_vec();
+    if all_to_reset.is_empty() {
+        return Ok(());
+    }
+
+    cache.defer_after_save(move |collections| {
+        Box::pin(async move {
+            if !reset_in_db.is_empty() {
+                collections
+                    .part_instances
+                    .update_many(
+                        doc! {
+                            "_id": { "$in": reset_in_db },
+                            "reset": { "$ne": true },
+                        },
+                        doc! { "$set": { "reset": true } },
+                    )
+                    .await?;
+            }
+            collections
+                .piece_instances
+                .update_many(
+                    doc! {
+                        "partInstanceId": { "$in": all_to_reset },
+                        "reset": { "$ne": true },
+                    },
+                    doc! { "$set": { "reset": true } },
+                )
+                .await?;
+
+            Ok(())
+        })
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use crate::{
+        context::direct_collections::{DirectCollections, InMemoryCollectionsData},
+        data_model::{
+            ids::{PartInstanceId, ProtectedId, RundownPlaylistActivationId},
+            part_instance::PartInstance,
+        },
+        playout::{
+            active_playlist::{handle_activate_rundown_playlist, handle_reset_rundown_playlist},
+            fixtures::*,
+        },
+    };
+
+    /** Add a PartInstance of part0 played in an earlier activation, which will not be loaded into the cache */
+    fn add_old_part_instance(data: &mut InMemoryCollectionsData) -> PartInstance {
+        let mut old_instance = create_part_instance(&data.parts[0]);
+        old_instance.id = PartInstanceId::new_from("old_instance".to_string());
+        old_instance.playlist_activation_id =
+            RundownPlaylistActivationId::new_from("activation_old".to_string());
+        old_instance.is_taken = true;
+
+        let mut old_piece_instance = create_piece_instance(data.pieces[0].clone(), &old_instance);
+        old_piece_instance.playlist_activation_id = old_instance.playlist_activation_id.clone();
+
+        data.part_instances.push(old_instance.clone());
+        data.piece_instances.push(old_piece_instance);
+
+        old_instance
+    }
+
+    async fn assert_reset(collections: &DirectCollections, old_instance: &PartInstance) {
+        assert!(
+            fetch_part_instance(collections, &old_instance.id)
+                .await
+                .reset
+        );
+        let old_piece_instances = collections
+            .piece_instances
+            .find_fetch(doc! { "partInstanceId": old_instance.id.unprotect() }, None)
+            .await
+            .unwrap();
+        assert_eq!(old_piece_instances.len(), 1);
+        assert!(old_piece_instances[0].reset);
+
+        // The PartInstance created for the new next part must be left untouched
+        let playlist = fetch_playlist(collections).await;
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        assert_ne!(next_id, old_instance.id);
+
+        let next = fetch_part_instance(collections, &next_id).await;
+        assert_eq!(next.part.id, old_instance.part.id);
+        assert!(!next.reset);
+
+        let next_piece_instances = collections
+            .piece_instances
+            .find_fetch(doc! { "partInstanceId": next_id.unprotect() }, None)
+            .await
+            .unwrap();
+        assert_eq!(next_piece_instances.len(), 1);
+        assert!(!next_piece_instances[0].reset);
+    }
+
+    #[tokio::test]
+    async fn activate_resets_only_old_part_instances() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+        let old_instance = add_old_part_instance(&mut data);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_activate_rundown_playlist(&context, &playlist_id(), false)
+            .await
+            .unwrap();
+
+        assert_reset(&collections, &old_instance).await;
+    }
+
+    #[tokio::test]
+    async fn reset_in_rehearsal_resets_only_old_part_instances() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].rehearsal = true;
+        let old_instance = add_old_part_instance(&mut data);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_reset_rundown_playlist(&context, &playlist_id())
+            .await
+            .unwrap();
+
+        assert_reset(&collections, &old_instance).await;
+    }
 }
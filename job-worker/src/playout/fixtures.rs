@@ -0,0 +1,316 @@
+use std::rc::Rc;
+
+use chrono::{Duration, Utc};
+
+use serde_json::json;
+use tokio::sync::mpsc::UnboundedReceiver;
+
+use super::{autonext::AutonextScheduler, cache::CacheWriteMode, lock::PlaylistLockManager};
+use crate::{
+    context::{
+        context::JobContext,
+        direct_collections::{DirectCollections, InMemoryCollectionsData},
+    },
+    data_model::{
+        ids::{
+            PartInstanceId, PieceInstanceInfiniteId, ProtectedId, RundownPlaylistActivationId,
+            RundownPlaylistId,
+        },
+        part::Part,
+        part_instance::PartInstance,
+        piece::{Piece, PieceLifespan},
+        piece_instance::{rewrapPieceToInstance, PieceInstance, PieceInstanceInfinite},
+        rundown::Rundown,
+        rundown_playlist::RundownPlaylist,
+        segment::Segment,
+        show_style_base::DBShowStyleBase,
+    },
+    error::{JobError, UserErrorMessage},
+    events::{EventsJob, EventsQueue},
+};
+
+pub const PLAYLIST_ID: &str = "playlist0";
+pub const ACTIVATION_ID: &str = "activation0";
+
+pub fn playlist_id() -> RundownPlaylistId {
+    RundownPlaylistId::new_from(PLAYLIST_ID.to_string())
+}
+
+/** An active playlist of the given rundowns, with nothing selected */
+pub fn create_playlist(rundown_ids: &[&str]) -> RundownPlaylist {
+    serde_json::from_value(json!({
+        "_id": PLAYLIST_ID,
+        "externalId": PLAYLIST_ID,
+        "studioId": "studio0",
+        "name": PLAYLIST_ID,
+        "created": 0,
+        "modified": 0,
+        "timing": {},
+        "activationId": ACTIVATION_ID,
+        "currentPartInstanceId": null,
+        "nextPartInstanceId": null,
+        "previousPartInstanceId": null,
+        "nextSegmentId": null,
+        "rundownIdsInOrder": rundown_ids,
+    }))
+    .unwrap()
+}
+
+pub fn create_rundown(id: &str, show_style_base_id: &str) -> Rundown {
+    serde_json::from_value(json!({
+        "_id": id,
+        "externalId": id,
+        "name": id,
+        "studioId": "studio0",
+        "showStyleBaseId": show_style_base_id,
+        "showStyleVariantId": "variant0",
+        "playlistId": PLAYLIST_ID,
+        "created": 0,
+        "modified": 0,
+        "importVersions": {},
+        "timing": {},
+    }))
+    .unwrap()
+}
+
+pub fn create_segment(id: &str, rundown_id: &str) -> Segment {
+    serde_json::from_value(json!({
+        "_id": id,
+        "_rank": 0,
+        "rundownId": rundown_id,
+        "externalId": id,
+        "externalModified": 0,
+        "name": id,
+    }))
+    .unwrap()
+}
+
+pub fn create_part(id: &str, segment_id: &str, rundown_id: &str) -> Part {
+    serde_json::from_value(json!({
+        "_id": id,
+        "_rank": 0,
+        "rundownId": rundown_id,
+        "segmentId": segment_id,
+        "externalId": id,
+        "title": id,
+    }))
+    .unwrap()
+}
+
+/** A PartInstance of the part in the current activation, which has been set as next */
+pub fn create_part_instance(part: &Part) -> PartInstance {
+    serde_json::from_value(json!({
+        "_id": format!("{}_instance", part.id.unprotect()),
+        "rundownId": part.rundown_id,
+        "segmentId": part.segment_id,
+        "playlistActivationId": ACTIVATION_ID,
+        "segmentPlayoutId": "playout0",
+        "part": part,
+        "timings": { "setAsNext": 0 },
+        "takeCount": 0,
+        "rehearsal": false,
+    }))
+    .unwrap()
+}
+
+/** A piece starting at the beginning of the part, with no timeline objects */
+pub fn create_piece(id: &str, part: &Part, lifespan: PieceLifespan) -> Piece {
+    serde_json::from_value(json!({
+        "_id": id,
+        "startPartId": part.id,
+        "startSegmentId": part.segment_id,
+        "startRundownId": part.rundown_id,
+        "externalId": id,
+        "name": id,
+        "enable": { "start": 0 },
+        "lifespan": lifespan,
+        "sourceLayerId": "layer0",
+        "outputLayerId": "pgm",
+        "pieceType": "normal",
+        "content": {},
+        "status": 0,
+        "timelineObjectsString": "[]",
+    }))
+    .unwrap()
+}
+
+pub fn create_piece_instance(piece: Piece, part_instance: &PartInstance) -> PieceInstance {
+    rewrapPieceToInstance(
+        piece,
+        RundownPlaylistActivationId::new_from(ACTIVATION_ID.to_string()),
+        part_instance.rundown_id.clone(),
+        part_instance.id.clone(),
+        false,
+    )
+}
+
+/** Create a PieceInstance of an infinite, as it would be when first played in the PartInstance */
+pub fn create_infinite_piece_instance(piece: Piece, part_instance: &PartInstance) -> PieceInstance {
+    let mut instance = create_piece_instance(piece, part_instance);
+    instance.infinite = Some(PieceInstanceInfinite {
+        infinite_instance_id: PieceInstanceInfiniteId::new_from(format!(
+            "{}_infinite",
+            instance.piece.id.unprotect()
+        )),
+        infinite_instance_index: 0,
+        infinite_piece_id: instance.piece.id.clone(),
+        from_previous_part: false,
+        from_previous_playhead: false,
+        from_hold: false,
+    });
+    instance
+}
+
+/** A ShowStyleBase without any layers */
+pub fn create_show_style_base(id: &str) -> DBShowStyleBase {
+    serde_json::from_value(json!({
+        "_id": id,
+        "sourceLayersWithOverrides": { "defaults": {}, "overrides": [] },
+        "outputLayersWithOverrides": { "defaults": {}, "overrides": [] },
+    }))
+    .unwrap()
+}
+
+/**
+ * Create a context for running jobs against the collections.
+ * The receiver of the events queue must be kept alive for as long as jobs may queue events
+ */
+pub fn create_context(
+    collections: &Rc<DirectCollections>,
+) -> (JobContext, UnboundedReceiver<EventsJob>) {
+    let (events_queue, events_receiver) = EventsQueue::create();
+    let context = JobContext::create(
+        collections.clone(),
+        Rc::new(PlaylistLockManager::create()),
+        Rc::new(AutonextScheduler::create()),
+        events_queue,
+        CacheWriteMode::BestEffort,
+    );
+
+    (context, events_receiver)
+}
+
+/**
+ * The documents of an active playlist with nothing selected, of a rundown with segment0 containing part0 and part1,
+ * followed by segment1 containing part2. Each part has a piece
+ */
+pub fn create_playlist_data() -> InMemoryCollectionsData {
+    let mut segment1 = create_segment("segment1", "rundown0");
+    segment1.rank = 1.0;
+
+    let parts = [
+        ("part0", "segment0"),
+        ("part1", "segment0"),
+        ("part2", "segment1"),
+    ]
+    .into_iter()
+    .enumerate()
+    .map(|(rank, (id, segment_id))| {
+        let mut part = create_part(id, segment_id, "rundown0");
+        part.rank = rank as f32;
+        part
+    })
+    .collect::<Vec<_>>();
+    let pieces = parts
+        .iter()
+        .map(|part| {
+            create_piece(
+                &format!("{}_piece", part.id.unprotect()),
+                part,
+                PieceLifespan::WithinPart,
+            )
+        })
+        .collect();
+
+    InMemoryCollectionsData {
+        rundown_playlists: vec![create_playlist(&["rundown0"])],
+        rundowns: vec![create_rundown("rundown0", "showstyle0")],
+        segments: vec![create_segment("segment0", "rundown0"), segment1],
+        parts,
+        pieces,
+        show_style_bases: vec![create_show_style_base("showstyle0")],
+        ..Default::default()
+    }
+}
+
+/**
+ * Add PartInstances to the playlist data, for the part at current_index to be playing and the part at next_index to be next.
+ * Returns the current and next PartInstances
+ */
+pub fn add_selected_part_instances(
+    data: &mut InMemoryCollectionsData,
+    current_index: usize,
+    next_index: usize,
+) -> (PartInstance, PartInstance) {
+    let started = Utc::now() - Duration::seconds(1);
+
+    let mut current = create_part_instance(&data.parts[current_index]);
+    current.is_taken = true;
+    current.timings.take = Some(started);
+    current.timings.planned_started_playback = Some(started);
+    current.timings.reported_started_playback = Some(started);
+    let next = create_part_instance(&data.parts[next_index]);
+
+    for part_instance in [&current, &next] {
+        let piece = data
+            .pieces
+            .iter()
+            .find(|piece| piece.start_part_id == part_instance.part.id)
+            .cloned();
+        if let Some(piece) = piece {
+            data.piece_instances
+                .push(create_piece_instance(piece, part_instance));
+        }
+        data.part_instances.push(part_instance.clone());
+    }
+
+    let playlist = &mut data.rundown_playlists[0];
+    playlist.current_part_instance_id = Some(current.id.clone());
+    playlist.next_part_instance_id = Some(next.id.clone());
+
+    (current, next)
+}
+
+pub async fn fetch_playlist(collections: &DirectCollections) -> RundownPlaylist {
+    collections
+        .rundown_playlists
+        .find_one_by_id(&playlist_id(), None)
+        .await
+        .unwrap()
+        .expect("playlist is missing")
+}
+
+pub async fn fetch_part_instance(
+    collections: &DirectCollections,
+    id: &PartInstanceId,
+) -> PartInstance {
+    collections
+        .part_instances
+        .find_one_by_id(id, None)
+        .await
+        .unwrap()
+        .unwrap_or_else(|| panic!("PartInstance \"{}\" is missing", id.unprotect()))
+}
+
+pub async fn assert_part_instance_removed(collections: &DirectCollections, id: &PartInstanceId) {
+    let part_instance = collections
+        .part_instances
+        .find_one_by_id(id, None)
+        .await
+        .unwrap();
+    assert!(
+        part_instance.is_none(),
+        "PartInstance \"{}\" was not removed",
+        id.unprotect()
+    );
+}
+
+pub fn assert_user_error<T: std::fmt::Debug>(
+    result: Result<T, JobError>,
+    message: UserErrorMessage,
+) {
+    match result {
+        Err(JobError::UserError(err)) => assert_eq!(err.message, message),
+        result => panic!("Expected {:?}, got {:?}", message, result),
+    }
+}
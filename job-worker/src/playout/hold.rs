@@ -0,0 +1,228 @@
+use crate::{
+    cache::{
+        collection::DbCacheReadCollection,
+        object::{DbCacheReadObject, DbCacheWriteObject},
+    },
+    context::context::JobContext,
+    data_model::{
+        ids::RundownPlaylistId,
+        part::PartHoldMode,
+        piece::PieceLifespan,
+        rundown_playlist::{RundownHoldState, RundownPlaylist},
+    },
+    error::{DocumentId, JobError, UserError, UserErrorMessage},
+};
+
+use super::{cache::PlayoutCache, lock::run_job_with_playout_cache, timeline::update_timeline};
+
+/**
+ * Activate a hold, to be started by the next take.
+ * A hold is only possible from a part with a hold mode of FROM, into the directly following part which has a hold mode of TO
+ */
+pub async fn handle_activate_hold(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, |context, cache| {
+        Box::pin(activate_hold(context, cache))
+    })
+    .await
+}
+
+/**
+ * Cancel a hold, if it has not yet been started
+ */
+pub async fn handle_deactivate_hold(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, |context, cache| {
+        Box::pin(deactivate_hold(context, cache))
+    })
+    .await
+}
+
+async fn activate_hold(context: &JobContext, cache: &mut PlayoutCache) -> Result<(), JobError> {
+    validate_can_activate_hold(cache.playlist.doc())?;
+
+    let current_part_instance = cache
+        .get_current_part_instance()
+        .ok_or_else(|| JobError::Internal("currentPartInstance not found!".to_string()))?;
+    let next_part_instance = cache
+        .get_next_part_instance()
+        .ok_or_else(|| JobError::Internal("nextPartInstance not found!".to_string()))?;
+
+    if current_part_instance.part.hold_mode != PartHoldMode::FROM
+        || next_part_instance.part.hold_mode != PartHoldMode::TO
+        || current_part_instance.part.segment_id != next_part_instance.part.segment_id
+    {
+        return Err(UserError::create(UserErrorMessage::HoldIncompatibleParts).into());
+    }
+
+    // The TO part must directly follow the FROM part
+    let ordered_parts = cache.get_ordered_segments_and_parts().parts;
+    let current_index = ordered_parts
+        .iter()
+        .position(|part| part.id == current_part_instance.part.id)
+        .ok_or_else(|| {
+            JobError::NotFound(DocumentId::Part(current_part_instance.part.id.clone()))
+        })?;
+    if ordered_parts.get(current_index + 1).map(|part| &part.id)
+        != Some(&next_part_instance.part.id)
+    {
+        return Err(UserError::create(UserErrorMessage::HoldIncompatibleParts).into());
+    }
+
+    let has_dynamically_inserted = cache
+        .piece_instances
+        .find_one(|p| {
+            p.part_instance_id == current_part_instance.id
+                && p.dynamically_inserted.is_some()
+                && (p.piece.lifespan == PieceLifespan::WithinPart
+                    || p.infinite.as_ref().map_or(false, |inf| {
+                        inf.from_previous_part || inf.from_previous_playhead
+                    }))
+        })
+        .is_some();
+    if has_dynamically_inserted {
+        return Err(UserError::create(UserErrorMessage::HoldAfterAdlib).into());
+    }
+
+    cache.playlist.update(|doc| {
+        let mut res = doc.clone();
+        res.hold_state = RundownHoldState::PENDING;
+        Some(res)
+    })?;
+
+    update_timeline(context, cache, None).await?;
+
+    Ok(())
+}
+
+fn validate_can_activate_hold(playlist: &RundownPlaylist) -> Result<(), JobError> {
+    if playlist.activation_id.is_none() {
+        Err(UserError::create(UserErrorMessage::InactiveRundown).into())
+    } else if playlist.hold_state != RundownHoldState::NONE {
+        Err(UserError::create(UserErrorMessage::HoldAlreadyActive).into())
+    } else if playlist.current_part_instance_id.is_none() {
+        Err(UserError::create(UserErrorMessage::NoCurrentPart).into())
+    } else if playlist.next_part_instance_id.is_none() {
+        Err(UserError::create(UserErrorMessage::HoldNeedsNextPart).into())
+    } else {
+        Ok(())
+    }
+}
+
+async fn deactivate_hold(context: &JobContext, cache: &mut PlayoutCache) -> Result<(), JobError> {
+    // Once the hold has been started by a take, it can only be completed by another take
+    if cache.playlist.doc().hold_state != RundownHoldState::PENDING {
+        return Err(UserError::create(UserErrorMessage::HoldNotCancelable).into());
+    }
+
+    cache.playlist.update(|doc| {
+        let mut res = doc.clone();
+        res.hold_state = RundownHoldState::NONE;
+        Some(res)
+    })?;
+
+    update_timeline(context, cache, None).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::direct_collections::{DirectCollections, InMemoryCollectionsData},
+        playout::fixtures::*,
+    };
+
+    /** The playlist data, with part0 playing as a hold FROM and part1 next as the hold TO */
+    fn create_hold_data() -> InMemoryCollectionsData {
+        let mut data = create_playlist_data();
+        data.parts[0].hold_mode = PartHoldMode::FROM;
+        data.parts[1].hold_mode = PartHoldMode::TO;
+        data
+    }
+
+    #[tokio::test]
+    async fn activate_hold_into_following_part() {
+        let mut data = create_hold_data();
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_activate_hold(&context, &playlist_id())
+            .await
+            .unwrap();
+        assert_eq!(
+            fetch_playlist(&collections).await.hold_state,
+            RundownHoldState::PENDING
+        );
+
+        // It can be cancelled before it is started by a take
+        handle_deactivate_hold(&context, &playlist_id())
+            .await
+            .unwrap();
+        assert_eq!(
+            fetch_playlist(&collections).await.hold_state,
+            RundownHoldState::NONE
+        );
+    }
+
+    #[tokio::test]
+    async fn activate_hold_is_rejected_for_parts_which_are_not_adjacent() {
+        let mut data = create_hold_data();
+        let mut part_between = create_part("part_between", "segment0", "rundown0");
+        part_between.rank = 0.5;
+        data.parts.push(part_between);
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_activate_hold(&context, &playlist_id()).await,
+            UserErrorMessage::HoldIncompatibleParts,
+        );
+        assert_eq!(
+            fetch_playlist(&collections).await.hold_state,
+            RundownHoldState::NONE
+        );
+    }
+
+    #[tokio::test]
+    async fn activate_hold_is_rejected_without_hold_modes() {
+        let mut data = create_playlist_data();
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_activate_hold(&context, &playlist_id()).await,
+            UserErrorMessage::HoldIncompatibleParts,
+        );
+    }
+
+    #[tokio::test]
+    async fn deactivate_hold_is_rejected_once_started() {
+        let mut data = create_hold_data();
+        add_selected_part_instances(&mut data, 0, 1);
+        data.rundown_playlists[0].hold_state = RundownHoldState::ACTIVE;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_deactivate_hold(&context, &playlist_id()).await,
+            UserErrorMessage::HoldNotCancelable,
+        );
+        assert_eq!(
+            fetch_playlist(&collections).await.hold_state,
+            RundownHoldState::ACTIVE
+        );
+    }
+}
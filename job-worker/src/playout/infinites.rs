@@ -393,76 +393,70 @@ fn markPieceInstanceAsContinuation(previousInstance: &PieceInstance, instance: &
 }
 
 pub fn isPiecePotentiallyActiveInPart(
-    _previousPartInstance: Option<&PartInstance>,
-    _partsBeforeThisInSegment: &HashSet<PartId>,
-    _segmentsBeforeThisInRundown: &HashSet<SegmentId>,
-    _rundownsBeforeThisInPlaylist: &[RundownId],
-    _rundownsToShowstyles: &HashMap<RundownId, ShowStyleBaseId>,
-    _rundown: &Rundown,
-    _part: &Part,
-    _pieceToCheck: &Piece,
+    previousPartInstance: Option<&PartInstance>,
+    partsBeforeThisInSegment: &HashSet<PartId>,
+    segmentsBeforeThisInRundown: &HashSet<SegmentId>,
+    rundownsBeforeThisInPlaylist: &[RundownId],
+    rundownsToShowstyles: &HashMap<RundownId, ShowStyleBaseId>,
+    rundown: &Rundown,
+    part: &Part,
+    pieceToCheck: &Piece,
 ) -> bool {
-    // 	// If its from the current part
-    // 	if (pieceToCheck.startPartId === part._id) {
-    // 		return true
-    // 	}
-
-    // 	switch (pieceToCheck.lifespan) {
-    // 		case PieceLifespan.WithinPart:
-    // 			// This must be from another part
-    // 			return false
-    // 		case PieceLifespan.OutOnSegmentEnd:
-    // 			return (
-    // 				pieceToCheck.startSegmentId === part.segmentId && partsBeforeThisInSegment.has(pieceToCheck.startPartId)
-    // 			)
-    // 		case PieceLifespan.OutOnRundownEnd:
-    // 			if (pieceToCheck.startRundownId === part.rundownId) {
-    // 				if (pieceToCheck.startSegmentId === part.segmentId) {
-    // 					return partsBeforeThisInSegment.has(pieceToCheck.startPartId)
-    // 				} else {
-    // 					return segmentsBeforeThisInRundown.has(pieceToCheck.startSegmentId)
-    // 				}
-    // 			} else {
-    // 				return false
-    // 			}
-    // 		case PieceLifespan.OutOnSegmentChange:
-    // 			if (previousPartInstance !== undefined) {
-    // 				// This gets handled by getPlayheadTrackingInfinitesForPart
-    // 				// We will only copy the pieceInstance from the previous, never using the original piece
-    // 				return false
-    // 			} else {
-    // 				// Predicting what will happen at arbitrary point in the future
-    // 				return (
-    // 					pieceToCheck.startSegmentId === part.segmentId &&
-    // 					partsBeforeThisInSegment.has(pieceToCheck.startPartId)
-    // 				)
-    // 			}
-    // 		case PieceLifespan.OutOnRundownChange:
-    // 			if (previousPartInstance !== undefined) {
-    // 				// This gets handled by getPlayheadTrackingInfinitesForPart
-    // 				// We will only copy the pieceInstance from the previous, never using the original piece
-    // 				return false
-    // 			} else {
-    // 				// Predicting what will happen at arbitrary point in the future
-    // 				return (
-    // 					pieceToCheck.startRundownId === part.rundownId &&
-    // 					segmentsBeforeThisInRundown.has(pieceToCheck.startSegmentId)
-    // 				)
-    // 			}
-    // 		case PieceLifespan.OutOnShowStyleEnd:
-    // 			return previousPartInstance && pieceToCheck.lifespan === PieceLifespan.OutOnShowStyleEnd
-    // 				? continueShowStyleEndInfinites(
-    // 						rundownsBeforeThisInPlaylist,
-    // 						rundownsToShowstyles,
-    // 						previousPartInstance.rundownId,
-    // 						rundown
-    // 				  )
-    // 				: false
-    // 		default:
-    // 			assertNever(pieceToCheck.lifespan)
-    // 			return false
-    // 	}
-    todo!();
+    // If its from the current part
+    if pieceToCheck.start_part_id == part.id {
+        return true;
+    }
+
+    match pieceToCheck.lifespan {
+        // This must be from another part
+        PieceLifespan::WithinPart => false,
+        PieceLifespan::OutOnSegmentEnd => {
+            pieceToCheck.start_segment_id == part.segment_id
+                && partsBeforeThisInSegment.contains(&pieceToCheck.start_part_id)
+        }
+        PieceLifespan::OutOnRundownEnd => {
+            if pieceToCheck.start_rundown_id == part.rundown_id {
+                if pieceToCheck.start_segment_id == part.segment_id {
+                    partsBeforeThisInSegment.contains(&pieceToCheck.start_part_id)
+                } else {
+                    segmentsBeforeThisInRundown.contains(&pieceToCheck.start_segment_id)
+                }
+            } else {
+                false
+            }
+        }
+        PieceLifespan::OutOnSegmentChange => {
+            if previousPartInstance.is_some() {
+                // This gets handled by getPlayheadTrackingInfinitesForPart
+                // We will only copy the pieceInstance from the previous, never using the original piece
+                false
+            } else {
+                // Predicting what will happen at arbitrary point in the future
+                pieceToCheck.start_segment_id == part.segment_id
+                    && partsBeforeThisInSegment.contains(&pieceToCheck.start_part_id)
+            }
+        }
+        PieceLifespan::OutOnRundownChange => {
+            if previousPartInstance.is_some() {
+                // This gets handled by getPlayheadTrackingInfinitesForPart
+                // We will only copy the pieceInstance from the previous, never using the original piece
+                false
+            } else {
+                // Predicting what will happen at arbitrary point in the future
+                pieceToCheck.start_rundown_id == part.rundown_id
+                    && segmentsBeforeThisInRundown.contains(&pieceToCheck.start_segment_id)
+            }
+        }
+        PieceLifespan::OutOnShowStyleEnd => match previousPartInstance {
+            Some(previous_part_instance) => continueShowStyleEndInfinites(
+                rundownsBeforeThisInPlaylist,
+                rundownsToShowstyles,
+                &previous_part_instance.rundown_id,
+                rundown,
+            ),
+            None => false,
+        },
+    }
 }
 
 fn doesPieceAStartBeforePieceB(orderedPartIds: &[PartId], pieceA: &Piece, pieceB: &Piece) -> bool {
@@ -500,12 +494,13 @@ struct InfinitePieceSet {
 impl InfinitePieceSet {
     fn set_piece(&mut self, lifespan: PieceLifespan, piece: Piece) {
         match lifespan {
-            PieceLifespan::WithinPart => todo!(),
-            PieceLifespan::OutOnSegmentChange => todo!(),
+            // These are never tracked here, as they are either not infinite or travel with the playhead
+            PieceLifespan::WithinPart
+            | PieceLifespan::OutOnSegmentChange
+            | PieceLifespan::OutOnRundownChange => {}
             PieceLifespan::OutOnSegmentEnd => {
                 self.on_segment_end = Some(piece);
             }
-            PieceLifespan::OutOnRundownChange => todo!(),
             PieceLifespan::OutOnRundownEnd => {
                 self.on_rundown_end = Some(piece);
             }
@@ -1092,24 +1087,545 @@ fn find_piece_instances_on_infinite_layers(
     res
 }
 
+/**
+ * Check whether OutOnShowStyleEnd infinites can continue from the previous rundown into this one.
+ * This requires every rundown from the previous one up to this one to share the same ShowStyleBase
+ */
 fn continueShowStyleEndInfinites(
     rundowns_before_this_in_playlist: &[RundownId],
     rundowns_to_showstyles: &HashMap<RundownId, ShowStyleBaseId>,
     previous_rundown_id: &RundownId,
     rundown: &Rundown,
 ) -> bool {
-    if Some(&rundown.show_style_base_id) != rundowns_to_showstyles.get(previous_rundown_id) {
+    if previous_rundown_id == &rundown.id {
+        // Still in the same rundown, so the showstyle can't have changed
+        true
+    } else if Some(&rundown.show_style_base_id) != rundowns_to_showstyles.get(previous_rundown_id)
+    {
         false
     } else {
+        // Any rundowns skipped over must also be of the same showstyle
         let target_show_style = &rundown.show_style_base_id;
-        let from_index = rundowns_before_this_in_playlist
+        let rundowns_between = rundowns_before_this_in_playlist
             .iter()
             .position(|rd| rd == previous_rundown_id)
-            .unwrap_or(0);
+            .map_or(&[][..], |index| &rundowns_before_this_in_playlist[index..]);
 
-        rundowns_before_this_in_playlist
+        rundowns_between
             .iter()
-            .skip(from_index)
             .all(|rd| rundowns_to_showstyles.get(rd) == Some(target_show_style))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{context::mongo_where::mongo_where, playout::fixtures::*};
+
+    fn get_playhead_tracking_infinites(
+        current_part_instance: &PartInstance,
+        current_piece_instances: &[PieceInstance],
+        segments_before: &[&str],
+        rundown: &Rundown,
+        part: &Part,
+    ) -> Vec<PieceInstance> {
+        let segments_before = segments_before
+            .iter()
+            .map(|id| SegmentId::new_from(id.to_string()))
+            .collect::<HashSet<_>>();
+
+        getPlayheadTrackingInfinitesForPart(
+            &RundownPlaylistActivationId::new_from(ACTIVATION_ID.to_string()),
+            &HashSet::new(),
+            &segments_before,
+            &[],
+            &HashMap::new(),
+            current_part_instance,
+            current_piece_instances,
+            rundown,
+            part,
+            &PartInstanceId::new_from("new_instance".to_string()),
+            true,
+            false,
+        )
+    }
+
+    #[test]
+    fn piece_from_this_part_is_always_active() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let part = create_part("part0", "segment0", "rundown0");
+
+        for lifespan in [
+            PieceLifespan::WithinPart,
+            PieceLifespan::OutOnSegmentChange,
+            PieceLifespan::OutOnSegmentEnd,
+            PieceLifespan::OutOnRundownChange,
+            PieceLifespan::OutOnRundownEnd,
+            PieceLifespan::OutOnShowStyleEnd,
+        ] {
+            let piece = create_piece("piece0", &part, lifespan);
+            assert!(isPiecePotentiallyActiveInPart(
+                None,
+                &HashSet::new(),
+                &HashSet::new(),
+                &[],
+                &HashMap::new(),
+                &rundown,
+                &part,
+                &piece,
+            ));
+        }
+    }
+
+    #[test]
+    fn piece_from_previous_part_by_lifespan() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let previous_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment0", "rundown0");
+        let previous_part_instance = create_part_instance(&previous_part);
+
+        let parts_before = HashSet::from([previous_part.id.clone()]);
+
+        let is_active = |lifespan: PieceLifespan, previous: Option<&PartInstance>| {
+            let piece = create_piece("piece0", &previous_part, lifespan);
+            isPiecePotentiallyActiveInPart(
+                previous,
+                &parts_before,
+                &HashSet::new(),
+                &[],
+                &HashMap::new(),
+                &rundown,
+                &part,
+                &piece,
+            )
+        };
+
+        assert!(!is_active(PieceLifespan::WithinPart, None));
+        assert!(is_active(PieceLifespan::OutOnSegmentEnd, None));
+        assert!(is_active(PieceLifespan::OutOnRundownEnd, None));
+
+        // Without a playing part, the onChange infinites are predicted
+        assert!(is_active(PieceLifespan::OutOnSegmentChange, None));
+        // With a playing part they are copied from it by getPlayheadTrackingInfinitesForPart instead
+        assert!(!is_active(
+            PieceLifespan::OutOnSegmentChange,
+            Some(&previous_part_instance)
+        ));
+        assert!(!is_active(
+            PieceLifespan::OutOnRundownChange,
+            Some(&previous_part_instance)
+        ));
+    }
+
+    #[test]
+    fn piece_from_previous_segment_by_lifespan() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let previous_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment1", "rundown0");
+
+        let segments_before = HashSet::from([previous_part.segment_id.clone()]);
+
+        let is_active = |lifespan: PieceLifespan| {
+            let piece = create_piece("piece0", &previous_part, lifespan);
+            isPiecePotentiallyActiveInPart(
+                None,
+                &HashSet::new(),
+                &segments_before,
+                &[],
+                &HashMap::new(),
+                &rundown,
+                &part,
+                &piece,
+            )
+        };
+
+        assert!(!is_active(PieceLifespan::WithinPart));
+        assert!(!is_active(PieceLifespan::OutOnSegmentEnd));
+        assert!(!is_active(PieceLifespan::OutOnSegmentChange));
+        assert!(is_active(PieceLifespan::OutOnRundownEnd));
+        assert!(is_active(PieceLifespan::OutOnRundownChange));
+        assert!(!is_active(PieceLifespan::OutOnShowStyleEnd));
+    }
+
+    #[test]
+    fn piece_from_previous_rundown_by_lifespan() {
+        let previous_rundown = create_rundown("rundown0", "showstyle0");
+        let rundown = create_rundown("rundown1", "showstyle0");
+        let previous_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment1", "rundown1");
+        let previous_part_instance = create_part_instance(&previous_part);
+
+        let rundowns_before = vec![previous_rundown.id.clone()];
+        let rundowns_to_showstyles = HashMap::from([
+            (
+                previous_rundown.id.clone(),
+                previous_rundown.show_style_base_id.clone(),
+            ),
+            (rundown.id.clone(), rundown.show_style_base_id.clone()),
+        ]);
+
+        let is_active = |lifespan: PieceLifespan, previous: Option<&PartInstance>| {
+            let piece = create_piece("piece0", &previous_part, lifespan);
+            isPiecePotentiallyActiveInPart(
+                previous,
+                &HashSet::new(),
+                &HashSet::from([previous_part.segment_id.clone()]),
+                &rundowns_before,
+                &rundowns_to_showstyles,
+                &rundown,
+                &part,
+                &piece,
+            )
+        };
+
+        assert!(!is_active(PieceLifespan::OutOnRundownEnd, None));
+        assert!(!is_active(PieceLifespan::OutOnRundownChange, None));
+        assert!(!is_active(PieceLifespan::OutOnShowStyleEnd, None));
+        assert!(is_active(
+            PieceLifespan::OutOnShowStyleEnd,
+            Some(&previous_part_instance)
+        ));
+    }
+
+    #[test]
+    fn past_infinite_pieces_query_matches_by_lifespan() {
+        let previous_part = create_part("part0", "segment1", "rundown1");
+        let previous_segment_part = create_part("part1", "segment0", "rundown1");
+        let previous_rundown_part = create_part("part2", "segment2", "rundown0");
+        let part = create_part("part3", "segment1", "rundown1");
+
+        let query = buildPastInfinitePiecesForThisPartQuery(
+            &part,
+            &[previous_part.id.clone()],
+            &[previous_segment_part.segment_id.clone()],
+            &[previous_rundown_part.rundown_id.clone()],
+        )
+        .unwrap();
+
+        let is_matched = |from_part: &Part, lifespan: PieceLifespan| {
+            let piece = create_piece("piece0", from_part, lifespan);
+            mongo_where(&bson::to_document(&piece).unwrap(), &query).unwrap()
+        };
+
+        assert!(!is_matched(&previous_part, PieceLifespan::WithinPart));
+        assert!(is_matched(
+            &previous_part,
+            PieceLifespan::OutOnSegmentChange
+        ));
+        assert!(is_matched(&previous_part, PieceLifespan::OutOnSegmentEnd));
+        assert!(!is_matched(
+            &previous_segment_part,
+            PieceLifespan::OutOnSegmentEnd
+        ));
+        assert!(is_matched(
+            &previous_segment_part,
+            PieceLifespan::OutOnRundownEnd
+        ));
+        assert!(!is_matched(
+            &previous_rundown_part,
+            PieceLifespan::OutOnRundownEnd
+        ));
+        assert!(is_matched(
+            &previous_rundown_part,
+            PieceLifespan::OutOnShowStyleEnd
+        ));
+        assert!(!is_matched(&part, PieceLifespan::OutOnShowStyleEnd));
+    }
+
+    #[test]
+    fn playhead_infinites_continue_within_segment() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let current_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment0", "rundown0");
+        let current_part_instance = create_part_instance(&current_part);
+
+        let piece_instance = create_infinite_piece_instance(
+            create_piece("piece0", &current_part, PieceLifespan::OutOnSegmentChange),
+            &current_part_instance,
+        );
+
+        let result = get_playhead_tracking_infinites(
+            &current_part_instance,
+            &[piece_instance.clone()],
+            &[],
+            &rundown,
+            &part,
+        );
+
+        assert_eq!(result.len(), 1);
+        let continued = &result[0];
+        assert_eq!(
+            continued.id.unprotect(),
+            format!(
+                "new_instance_{}_continue",
+                piece_instance.piece.id.unprotect()
+            )
+        );
+        assert!(continued.piece.enable.start == PieceEnableStart::Offset(Duration::zero()));
+
+        let infinite = continued.infinite.as_ref().unwrap();
+        assert_eq!(infinite.infinite_instance_index, 1);
+        assert!(infinite.from_previous_part);
+        assert!(infinite.from_previous_playhead);
+        assert_eq!(
+            Some(&infinite.infinite_instance_id),
+            piece_instance
+                .infinite
+                .as_ref()
+                .map(|inf| &inf.infinite_instance_id)
+        );
+    }
+
+    #[test]
+    fn playhead_infinites_stop_at_segment_change() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let current_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment1", "rundown0");
+        let current_part_instance = create_part_instance(&current_part);
+
+        let segment_piece = create_infinite_piece_instance(
+            create_piece("piece0", &current_part, PieceLifespan::OutOnSegmentChange),
+            &current_part_instance,
+        );
+        let result = get_playhead_tracking_infinites(
+            &current_part_instance,
+            &[segment_piece],
+            &["segment0"],
+            &rundown,
+            &part,
+        );
+        assert!(result.is_empty());
+
+        // But a rundown infinite carries on into the next segment
+        let rundown_piece = create_infinite_piece_instance(
+            create_piece("piece1", &current_part, PieceLifespan::OutOnRundownChange),
+            &current_part_instance,
+        );
+        let result = get_playhead_tracking_infinites(
+            &current_part_instance,
+            &[rundown_piece],
+            &["segment0"],
+            &rundown,
+            &part,
+        );
+        assert_eq!(result.len(), 1);
+    }
+
+    #[test]
+    fn playhead_infinites_stop_at_rundown_change() {
+        let rundown = create_rundown("rundown1", "showstyle0");
+        let current_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment1", "rundown1");
+        let current_part_instance = create_part_instance(&current_part);
+
+        let piece_instance = create_infinite_piece_instance(
+            create_piece("piece0", &current_part, PieceLifespan::OutOnRundownChange),
+            &current_part_instance,
+        );
+
+        let result = get_playhead_tracking_infinites(
+            &current_part_instance,
+            &[piece_instance],
+            &[],
+            &rundown,
+            &part,
+        );
+        assert!(result.is_empty());
+    }
+
+    /** Continue an adlibbed OutOnShowStyleEnd infinite from rundown0 into rundown2, via rundown1 */
+    fn continue_show_style_end_adlib_across_rundowns(show_style_base_ids: [&str; 3]) -> bool {
+        let rundowns = show_style_base_ids
+            .iter()
+            .enumerate()
+            .map(|(i, show_style_base_id)| {
+                create_rundown(&format!("rundown{}", i), show_style_base_id)
+            })
+            .collect::<Vec<_>>();
+        let current_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part2", "segment2", "rundown2");
+        let current_part_instance = create_part_instance(&current_part);
+
+        let mut piece_instance = create_infinite_piece_instance(
+            create_piece("piece0", &current_part, PieceLifespan::OutOnShowStyleEnd),
+            &current_part_instance,
+        );
+        piece_instance.dynamically_inserted = Some(Utc::now());
+
+        let result = getPlayheadTrackingInfinitesForPart(
+            &RundownPlaylistActivationId::new_from(ACTIVATION_ID.to_string()),
+            &HashSet::new(),
+            &HashSet::new(),
+            &[rundowns[0].id.clone(), rundowns[1].id.clone()],
+            &rundowns
+                .iter()
+                .map(|rd| (rd.id.clone(), rd.show_style_base_id.clone()))
+                .collect(),
+            &current_part_instance,
+            &[piece_instance],
+            &rundowns[2],
+            &part,
+            &PartInstanceId::new_from("new_instance".to_string()),
+            true,
+            false,
+        );
+
+        !result.is_empty()
+    }
+
+    #[test]
+    fn playhead_infinites_continue_show_style_end_across_rundowns() {
+        assert!(continue_show_style_end_adlib_across_rundowns([
+            "showstyle0",
+            "showstyle0",
+            "showstyle0"
+        ]));
+        assert!(!continue_show_style_end_adlib_across_rundowns([
+            "showstyle0",
+            "showstyle0",
+            "showstyle1"
+        ]));
+        // A rundown of a different showstyle in between also ends the infinite
+        assert!(!continue_show_style_end_adlib_across_rundowns([
+            "showstyle0",
+            "showstyle1",
+            "showstyle0"
+        ]));
+    }
+
+    #[test]
+    fn piece_from_previous_rundown_stops_at_show_style_change() {
+        let previous_rundown = create_rundown("rundown0", "showstyle0");
+        let rundown = create_rundown("rundown1", "showstyle1");
+        let previous_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment1", "rundown1");
+        let previous_part_instance = create_part_instance(&previous_part);
+        let piece = create_piece("piece0", &previous_part, PieceLifespan::OutOnShowStyleEnd);
+
+        assert!(!isPiecePotentiallyActiveInPart(
+            Some(&previous_part_instance),
+            &HashSet::new(),
+            &HashSet::new(),
+            &[previous_rundown.id.clone()],
+            &HashMap::from([
+                (
+                    previous_rundown.id.clone(),
+                    previous_rundown.show_style_base_id.clone(),
+                ),
+                (rundown.id.clone(), rundown.show_style_base_id.clone()),
+            ]),
+            &rundown,
+            &part,
+            &piece,
+        ));
+    }
+
+    #[test]
+    fn playhead_infinites_ignore_within_part_and_stopped_pieces() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let current_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment0", "rundown0");
+        let current_part_instance = create_part_instance(&current_part);
+
+        let within_part = create_piece_instance(
+            create_piece("piece0", &current_part, PieceLifespan::WithinPart),
+            &current_part_instance,
+        );
+        let result = get_playhead_tracking_infinites(
+            &current_part_instance,
+            &[within_part],
+            &[],
+            &rundown,
+            &part,
+        );
+        assert!(result.is_empty());
+
+        let mut stopped = create_infinite_piece_instance(
+            create_piece("piece1", &current_part, PieceLifespan::OutOnSegmentChange),
+            &current_part_instance,
+        );
+        stopped.user_duration = Some(json!({ "endRelativeToPart": 1000 }));
+        let result = get_playhead_tracking_infinites(
+            &current_part_instance,
+            &[stopped],
+            &[],
+            &rundown,
+            &part,
+        );
+        assert!(result.is_empty());
+    }
+
+    #[test]
+    fn piece_instances_for_part_handle_every_lifespan() {
+        let rundown = create_rundown("rundown0", "showstyle0");
+        let current_part = create_part("part0", "segment0", "rundown0");
+        let part = create_part("part1", "segment0", "rundown0");
+        let current_part_instance = create_part_instance(&current_part);
+
+        let lifespans = [
+            PieceLifespan::WithinPart,
+            PieceLifespan::OutOnSegmentChange,
+            PieceLifespan::OutOnSegmentEnd,
+            PieceLifespan::OutOnRundownChange,
+            PieceLifespan::OutOnRundownEnd,
+            PieceLifespan::OutOnShowStyleEnd,
+        ];
+        let possible_pieces = lifespans
+            .iter()
+            .enumerate()
+            .map(|(i, lifespan)| {
+                let mut piece = create_piece(&format!("piece{}", i), &current_part, *lifespan);
+                piece.source_layer_id = format!("layer{}", i);
+                piece
+            })
+            .collect::<Vec<_>>();
+        let playing_piece_instances = possible_pieces
+            .iter()
+            .map(|piece| {
+                if piece.lifespan == PieceLifespan::WithinPart {
+                    create_piece_instance(piece.clone(), &current_part_instance)
+                } else {
+                    create_infinite_piece_instance(piece.clone(), &current_part_instance)
+                }
+            })
+            .collect::<Vec<_>>();
+
+        let result = getPieceInstancesForPart2(
+            RundownPlaylistActivationId::new_from(ACTIVATION_ID.to_string()),
+            Some(&current_part_instance),
+            &playing_piece_instances,
+            &rundown,
+            &part,
+            &HashSet::from([current_part.id.clone()]),
+            &HashSet::new(),
+            &[],
+            &HashMap::from([(rundown.id.clone(), rundown.show_style_base_id.clone())]),
+            &possible_pieces,
+            &[current_part.id.clone(), part.id.clone()],
+            PartInstanceId::new_from("new_instance".to_string()),
+            true,
+            false,
+        );
+
+        let mut result_lifespans = result
+            .iter()
+            .map(|p| {
+                assert!(p
+                    .infinite
+                    .as_ref()
+                    .map_or(false, |inf| inf.from_previous_part));
+                lifespans
+                    .iter()
+                    .position(|l| *l == p.piece.lifespan)
+                    .unwrap()
+            })
+            .collect::<Vec<_>>();
+        result_lifespans.sort();
+
+        // Everything but the WithinPart piece continues into the next part of the segment
+        assert_eq!(result_lifespans, vec![1, 2, 3, 4, 5]);
+    }
+}
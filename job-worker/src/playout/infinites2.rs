@@ -3,18 +3,18 @@ use std::{collections::HashSet, future::ready};
 use chrono::{Duration, Utc};
 use futures::future::LocalBoxFuture;
 use itertools::Itertools;
-use mongodb::bson::doc;
+use mongodb::bson::{self, doc, Document};
 use ordered_float::OrderedFloat;
 use tokio::join;
 
 use crate::{
     cache::{
-        collection::{DbCacheReadCollection, DbCacheWriteCollection},
+        collection::{DbCacheReadCollection, DbCacheWriteCollection, DbCacheWriteCollectionImpl},
         object::DbCacheReadObject,
     },
-    context::{context::JobContext, direct_collections::MongoReadOnlyCollection},
+    context::{context::JobContext, mongo_where::mongo_where},
     data_model::{
-        ids::{PartId, PartInstanceId, ProtectedId, RundownId, SegmentId},
+        ids::{PartId, PartInstanceId, PieceId, ProtectedId, RundownId, SegmentId},
         part::Part,
         part_instance::{PartInstance},
         piece::Piece,
@@ -23,6 +23,7 @@ use crate::{
         rundown_playlist::RundownPlaylist,
         segment::Segment,
     },
+    error::{DocumentId, JobError, UserError, UserErrorMessage},
     ingest::cache::IngestCache,
 };
 
@@ -149,7 +150,7 @@ pub async fn fetchPiecesThatMayBeActiveForPart(
     cache: &PlayoutCache,
     unsaved_ingest_cache: Option<&IngestCache>,
     part: &Part,
-) -> Result<Vec<Piece>, String> {
+) -> Result<Vec<Piece>, JobError> {
     let unsaved_ingest_cache = unsaved_ingest_cache.and_then(|cache| {
         if cache.rundown.doc_id() == &part.rundown_id {
             Some(cache)
@@ -159,7 +160,7 @@ pub async fn fetchPiecesThatMayBeActiveForPart(
     });
 
     // Find all the pieces starting in the part
-    let pieces_for_part: LocalBoxFuture<Result<Vec<Piece>, String>> =
+    let pieces_for_part: LocalBoxFuture<Result<Vec<Piece>, JobError>> =
         if let Some(unsaved_ingest_cache) = unsaved_ingest_cache {
             Box::pin(ready(Ok(unsaved_ingest_cache
                 .pieces
@@ -184,8 +185,8 @@ pub async fn fetchPiecesThatMayBeActiveForPart(
             &Vec::new(), // other rundowns don't exist in the ingestCache
         );
         let mut this_rundown_pieces: Vec<Piece> =
-            if let Some(_this_rundown_piece_query) = thisRundownPieceQuery {
-                unsaved_ingest_cache.pieces.find_some(|_p| todo!())
+            if let Some(this_rundown_piece_query) = thisRundownPieceQuery {
+                find_pieces_matching_query(&unsaved_ingest_cache.pieces, &this_rundown_piece_query)?
             } else {
                 Vec::new()
             };
@@ -197,7 +198,7 @@ pub async fn fetchPiecesThatMayBeActiveForPart(
             &Vec::new(), // Only applies to the current rundown
             &ids_before_part.rundowns_before_this_in_playlist,
         );
-        let previous_rundown_pieces: LocalBoxFuture<Result<Vec<Piece>, String>> =
+        let previous_rundown_pieces: LocalBoxFuture<Result<Vec<Piece>, JobError>> =
             if let Some(previous_rundown_piece_query) = previousRundownPieceQuery {
                 context
                     .direct_collections()
@@ -229,7 +230,7 @@ pub async fn fetchPiecesThatMayBeActiveForPart(
             &ids_before_part.rundowns_before_this_in_playlist,
         );
 
-        let infinite_pieces: LocalBoxFuture<Result<Vec<Piece>, String>> =
+        let infinite_pieces: LocalBoxFuture<Result<Vec<Piece>, JobError>> =
             if let Some(infinite_pieces_query) = infinite_pieces_query {
                 context
                     .direct_collections()
@@ -252,30 +253,60 @@ pub async fn fetchPiecesThatMayBeActiveForPart(
     }
 }
 
+/**
+ * Find the pieces in a cache matching a mongo query, evaluating the query in the same way that MongoDB would
+ */
+fn find_pieces_matching_query(
+    pieces: &DbCacheWriteCollectionImpl<Piece, PieceId>,
+    query: &Document,
+) -> Result<Vec<Piece>, JobError> {
+    let mut result = Vec::new();
+    for piece in pieces.find_all() {
+        let doc = bson::to_document(&piece).map_err(|err| {
+            JobError::Database(format!("serialize failed for \"{}\": {}", pieces.name(), err))
+        })?;
+        let matched = mongo_where(&doc, query).map_err(|err| {
+            JobError::Database(format!("query failed for \"{}\": {}", pieces.name(), err))
+        })?;
+
+        if matched {
+            result.push(piece);
+        }
+    }
+
+    Ok(result)
+}
+
 pub async fn syncPlayheadInfinitesForNextPartInstance(
     context: &JobContext,
     cache: &mut PlayoutCache,
-) -> Result<(), String> {
+) -> Result<(), JobError> {
     let next_part_instance = cache.get_next_part_instance();
     let current_part_instance = cache.get_current_part_instance();
 
     match (next_part_instance, current_part_instance) {
         (Some(next_part_instance), Some(current_part_instance)) => {
-            let activation_id = cache.playlist.doc().activation_id.clone().ok_or_else(|| {
-                format!(
-                    "RundownPlaylist \"{}\" is not active",
-                    cache.playlist.doc_id().unprotect()
-                )
-            })?;
+            let activation_id = cache
+                .playlist
+                .doc()
+                .activation_id
+                .clone()
+                .ok_or_else(|| UserError::create(UserErrorMessage::InactiveRundown))?;
 
             let rundown = cache
                 .rundowns
                 .find_one_by_id(&current_part_instance.rundown_id)
                 .ok_or_else(|| {
-                    format!(
-                        "Rundown \"{}\" is not active",
-                        current_part_instance.rundown_id.unprotect()
-                    )
+                    JobError::NotFound(DocumentId::Rundown(
+                        current_part_instance.rundown_id.clone(),
+                    ))
+                })?;
+
+            let next_rundown = cache
+                .rundowns
+                .find_one_by_id(&next_part_instance.rundown_id)
+                .ok_or_else(|| {
+                    JobError::NotFound(DocumentId::Rundown(next_part_instance.rundown_id.clone()))
                 })?;
 
             let ids_before_next_part =
@@ -284,7 +315,11 @@ pub async fn syncPlayheadInfinitesForNextPartInstance(
             let show_style_base = context
                 .get_show_style_base(&rundown.show_style_base_id)
                 .await?
-                .ok_or("ShowStyleBase not found".to_string())?;
+                .ok_or_else(|| {
+                    JobError::NotFound(DocumentId::ShowStyleBase(
+                        rundown.show_style_base_id.clone(),
+                    ))
+                })?;
 
             let ordered_parts_and_segments = cache.get_ordered_segments_and_parts();
 
@@ -333,7 +368,7 @@ pub async fn syncPlayheadInfinitesForNextPartInstance(
                     .into_iter()
                     .map(|p| (*p.piece).clone())
                     .collect_vec(),
-                &rundown,
+                &next_rundown,
                 &next_part_instance.part,
                 &next_part_instance.id,
                 can_continue_adlib_on_ends,
@@ -350,8 +385,7 @@ pub async fn syncPlayheadInfinitesForNextPartInstance(
                                 .map_or(false, |inf| inf.from_previous_playhead)
                     },
                     infinites,
-                )
-                .map_err(|_e| "Failed to save new infinites".to_string())?;
+                )?;
 
             Ok(())
         }
@@ -368,15 +402,15 @@ pub fn getPieceInstancesForPart(
     possible_pieces: &[Piece],
     new_instance_id: &PartInstanceId,
     is_temporary: bool,
-) -> Result<Vec<PieceInstance>, String> {
+) -> Result<Vec<PieceInstance>, JobError> {
     let ids_before_next_part = getIdsBeforeThisPart(context, cache, part);
 
-    let activation_id = cache.playlist.doc().activation_id.clone().ok_or_else(|| {
-        format!(
-            "RundownPlaylist \"{}\" is not active",
-            cache.playlist.doc_id().unprotect()
-        )
-    })?;
+    let activation_id = cache
+        .playlist
+        .doc()
+        .activation_id
+        .clone()
+        .ok_or_else(|| UserError::create(UserErrorMessage::InactiveRundown))?;
 
     let ordered_parts_and_segments = cache.get_ordered_segments_and_parts();
     let playing_piece_instances = playing_part_instance.map_or(Vec::new(), |instance| {
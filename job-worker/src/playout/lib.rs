@@ -1,8 +1,26 @@
 use std::ops::Sub;
 
 use chrono::{Duration, Utc};
+use log::info;
+use sofie_rust_experiment::get_random_id;
 
-use crate::data_model::part_instance::PartInstance;
+use crate::{
+    cache::object::{DbCacheReadObject, DbCacheWriteObject},
+    context::context::JobContext,
+    data_model::{
+        ids::{ProtectedId, RundownPlaylistActivationId},
+        part_instance::PartInstance,
+        rundown_playlist::RundownHoldState,
+    },
+    error::JobError,
+};
+
+use super::{
+    cache::PlayoutCache,
+    cleanup_orphaned::{resetPartInstancesWithPieceInstances, PartInstanceSelector},
+    select_next_part::select_next_part,
+    set_next_part::{setNextPart, SetNextPartTarget},
+};
 
 /**
  * time in ms before an autotake when we don't accept takes/updates
@@ -37,3 +55,69 @@ pub fn is_too_close_to_autonext(current_part_instance: &PartInstance, is_take: b
         }
     }
 }
+
+/**
+ * Reset the playlist, so that it is ready to be played from the beginning
+ */
+pub async fn reset_rundown_playlist(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+) -> Result<(), JobError> {
+    info!(
+        "resetRundownPlaylist {}",
+        cache.playlist.doc_id().unprotect()
+    );
+
+    // TODO - remove all dynamically inserted pieces (adlibs etc)
+    // removePartInstancesWithPieceInstances(context, cache, { rehearsal: true })
+    resetPartInstancesWithPieceInstances(context, cache, PartInstanceSelector::All).await?;
+
+    let now = Utc::now();
+    cache.playlist.update(|doc| {
+        let mut res = doc.clone();
+
+        res.previous_part_instance_id = None;
+        res.current_part_instance_id = None;
+        res.hold_state = RundownHoldState::NONE;
+        res.reset_time = Some(now);
+
+        res.last_take_time = None;
+        res.started_playback = None;
+        res.rundowns_started_playback = None;
+        res.previous_persistent_state = None;
+        res.tracked_ab_sessions = None;
+        res.next_segment_id = None;
+
+        Some(res)
+    })?;
+
+    if cache.playlist.doc().activation_id.is_some() {
+        // generate a new activationId
+        cache.playlist.update(|doc| {
+            let mut res = doc.clone();
+            res.activation_id = Some(RundownPlaylistActivationId::new_from(get_random_id()));
+            Some(res)
+        })?;
+
+        // put the first on queue:
+        let first_part = select_next_part(
+            cache.playlist.doc(),
+            None,
+            None,
+            cache.get_ordered_segments_and_parts(),
+            true,
+        );
+        setNextPart(
+            context,
+            cache,
+            first_part.map(SetNextPartTarget::Part),
+            false,
+            None,
+        )
+        .await?;
+    } else {
+        setNextPart(context, cache, None, false, None).await?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,319 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    rc::Rc,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use futures::future::LocalBoxFuture;
+use mongodb::bson::doc;
+use sofie_rust_experiment::get_random_id;
+use tokio::{
+    sync::{Mutex, OwnedMutexGuard},
+    time::sleep,
+};
+
+use crate::{
+    context::{context::JobContext, direct_collections::DirectCollections},
+    data_model::{
+        ids::{ProtectedId, RundownPlaylistId},
+        playlist_lock::PlaylistLock,
+    },
+    error::JobError,
+};
+
+use super::cache::PlayoutCache;
+
+/** How long a lease is valid for, if it is not renewed */
+const LEASE_DURATION: Duration = Duration::from_secs(10);
+/** How often a held lease gets renewed */
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(3);
+/** How long to wait between attempts to acquire a lease held by another worker */
+const LEASE_RETRY_INTERVAL: Duration = Duration::from_millis(100);
+/** How long to wait for a lease before giving up */
+const LEASE_ACQUIRE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/**
+ * Manages the locks on playlists, so that only one playout operation runs on a playlist at a time.
+ * Within this process this is done with a mutex per playlist, with a lease document in the database to coordinate with other worker processes
+ */
+pub struct PlaylistLockManager {
+    /** Id of this worker, used as the owner of the leases */
+    worker_id: String,
+
+    local_locks: RefCell<HashMap<RundownPlaylistId, Arc<Mutex<()>>>>,
+}
+impl PlaylistLockManager {
+    pub fn create() -> PlaylistLockManager {
+        PlaylistLockManager {
+            worker_id: get_random_id(),
+
+            local_locks: RefCell::new(HashMap::new()),
+        }
+    }
+
+    /**
+     * Acquire the lock for a playlist, waiting for it to be released if it is currently held
+     */
+    pub async fn lock(
+        &self,
+        collections: &Rc<DirectCollections>,
+        playlist_id: &RundownPlaylistId,
+    ) -> Result<PlaylistLockGuard, JobError> {
+        // First wait for any other job in this process
+        let local_lock = self
+            .local_locks
+            .borrow_mut()
+            .entry(playlist_id.clone())
+            .or_insert_with(|| Arc::new(Mutex::new(())))
+            .clone();
+        let local_guard = local_lock.lock_owned().await;
+
+        // Then wait for any other worker
+        let started = Instant::now();
+        loop {
+            if try_acquire_lease(collections, playlist_id, &self.worker_id).await? {
+                break;
+            } else if started.elapsed() < LEASE_ACQUIRE_TIMEOUT {
+                sleep(LEASE_RETRY_INTERVAL).await;
+            } else {
+                return Err(JobError::Internal(format!(
+                    "Timed out waiting for lock on RundownPlaylist \"{}\"",
+                    playlist_id
+                )));
+            }
+        }
+
+        Ok(PlaylistLockGuard {
+            collections: collections.clone(),
+            playlist_id: playlist_id.clone(),
+            owner: self.worker_id.clone(),
+            lost: RefCell::new(None),
+
+            _local_guard: local_guard,
+        })
+    }
+}
+
+/**
+ * Attempt to take the lease, if it is not held by someone else
+ */
+async fn try_acquire_lease(
+    collections: &DirectCollections,
+    playlist_id: &RundownPlaylistId,
+    owner: &str,
+) -> Result<bool, JobError> {
+    let now = Utc::now();
+    let expires_at = now + chrono::Duration::from_std(LEASE_DURATION).unwrap();
+
+    // Take over an existing lease if it has expired
+    let matched = collections
+        .playlist_locks
+        .update_one(
+            doc! {
+                "_id": playlist_id.unprotect(),
+                "$or": [
+                    { "owner": owner },
+                    { "expiresAt": { "$lt": now.timestamp_millis() } },
+                ],
+            },
+            doc! {
+                "$set": {
+                    "owner": owner,
+                    "expiresAt": expires_at.timestamp_millis(),
+                }
+            },
+        )
+        .await?;
+    if matched > 0 {
+        return Ok(true);
+    }
+
+    // Otherwise try to create it. This fails if someone else holds it
+    let inserted = collections
+        .playlist_locks
+        .insert_one(&PlaylistLock {
+            id: playlist_id.clone(),
+            owner: owner.to_string(),
+            expires_at,
+        })
+        .await;
+
+    match inserted {
+        Ok(()) => Ok(true),
+        Err(JobError::DuplicateKey(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/**
+ * A held lock on a playlist. This must be released once the job is complete
+ */
+pub struct PlaylistLockGuard {
+    collections: Rc<DirectCollections>,
+    playlist_id: RundownPlaylistId,
+    owner: String,
+    /** The failure to renew the lease, if it has been lost */
+    lost: RefCell<Option<JobError>>,
+
+    _local_guard: OwnedMutexGuard<()>,
+}
+impl PlaylistLockGuard {
+    /**
+     * Extend the lease, to stop it expiring while a job is still running
+     */
+    pub async fn renew(&self) -> Result<(), JobError> {
+        let expires_at = Utc::now() + chrono::Duration::from_std(LEASE_DURATION).unwrap();
+
+        let matched = self
+            .collections
+            .playlist_locks
+            .update_one(
+                doc! {
+                    "_id": self.playlist_id.unprotect(),
+                    "owner": &self.owner,
+                },
+                doc! {
+                    "$set": { "expiresAt": expires_at.timestamp_millis() }
+                },
+            )
+            .await?;
+
+        if matched == 0 {
+            Err(JobError::Internal(format!(
+                "Lost lock on RundownPlaylist \"{}\"",
+                self.playlist_id
+            )))
+        } else {
+            Ok(())
+        }
+    }
+
+    /**
+     * Check that the lease has not been lost while running the job.
+     * Nothing should be written once it has, as another worker may now be operating on the playlist
+     */
+    pub fn ensure_held(&self) -> Result<(), JobError> {
+        match &*self.lost.borrow() {
+            Some(err) => Err(err.clone()),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn release(self) -> Result<(), JobError> {
+        self.collections
+            .playlist_locks
+            .remove(doc! {
+                "_id": self.playlist_id.unprotect(),
+                "owner": &self.owner,
+            })
+            .await?;
+
+        Ok(())
+    }
+
+    /**
+     * Run a future while keeping the lease alive.
+     * The future is always run to completion, as abandoning it part way through a save would leave the save half applied.
+     * If the lease is lost, renewal stops and `ensure_held` reports the failure
+     */
+    pub async fn run<T, F: Future<Output = Result<T, JobError>>>(
+        &self,
+        fut: F,
+    ) -> Result<T, JobError> {
+        tokio::pin!(fut);
+
+        loop {
+            tokio::select! {
+                res = &mut fut => return res,
+                _ = sleep(LEASE_RENEW_INTERVAL), if self.lost.borrow().is_none() => {
+                    if let Err(err) = self.renew().await {
+                        *self.lost.borrow_mut() = Some(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/**
+ * Run a job against the PlayoutCache of a playlist, while holding the lock for the playlist.
+ * The cache is written to the database if the job succeeds, and any autonext is rescheduled
+ */
+pub async fn run_job_with_playout_cache<T, F>(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    job: F,
+) -> Result<T, JobError>
+where
+    F: for<'a> FnOnce(
+        &'a JobContext,
+        &'a mut PlayoutCache,
+    ) -> LocalBoxFuture<'a, Result<T, JobError>>,
+{
+    let collections = context.direct_collections_rc();
+
+    let lock = context
+        .playlist_locks()
+        .lock(collections, playlist_id)
+        .await?;
+
+    let result = lock
+        .run(async {
+            let mut cache = PlayoutCache::create(collections, playlist_id).await?;
+
+            let result = job(context, &mut cache).await?;
+
+            // Don't save if another worker may have taken over the playlist
+            lock.ensure_held()?;
+
+            cache
+                .write_to_database(collections, context.write_mode())
+                .await?;
+
+            // The job may have changed when the current part should autonext
+            context.autonext_scheduler().update_from_cache(&cache);
+
+            Ok(result)
+        })
+        .await;
+
+    let released = lock.release().await;
+
+    let result = result?;
+    released?;
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    #[test]
+    fn lease_is_exclusive_until_expired() {
+        let collections = DirectCollections::create_in_memory(Default::default()).unwrap();
+        let playlist_id = RundownPlaylistId::new_from("playlist0".to_string());
+
+        let try_acquire =
+            |owner: &str| block_on(try_acquire_lease(&collections, &playlist_id, owner)).unwrap();
+
+        assert!(try_acquire("worker0"));
+        assert!(!try_acquire("worker1"));
+        // The owner can take it again
+        assert!(try_acquire("worker0"));
+
+        block_on(collections.playlist_locks.update_one(
+            doc! { "_id": playlist_id.unprotect() },
+            doc! { "$set": { "expiresAt": 0_i64 } },
+        ))
+        .unwrap();
+        assert!(try_acquire("worker1"));
+        assert!(!try_acquire("worker0"));
+    }
+}
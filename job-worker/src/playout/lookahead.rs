@@ -0,0 +1,497 @@
+use chrono::Duration;
+use itertools::Itertools;
+use mongodb::bson::doc;
+
+use crate::{
+    cache::collection::DbCacheReadCollection,
+    constants::LOOKAHEAD_DEFAULT_SEARCH_DISTANCE,
+    context::{context::JobContext, direct_collections::FindOptions},
+    data_model::{
+        ids::{unprotect_array, PartId, PartInstanceId, PieceInstanceId, ProtectedId},
+        piece::{Piece, PieceEnableStart},
+        show_style_base::{LookaheadMode, OutputLayer},
+        timeline::{TimelineEnable, TimelineEnableValue, TimelineObjGenerated, TimelineObjType},
+    },
+    error::{DocumentId, JobError},
+};
+
+use super::{cache::PlayoutCache, timeline::parse_piece_timeline_objects};
+
+/** Lookahead objects are below anything else on the layer, so that they only play when the layer is clear */
+const LOOKAHEAD_OBJ_PRIORITY: i64 = -1;
+
+/**
+ * A piece which may be looked ahead to
+ */
+struct LookaheadPiece {
+    piece: Piece,
+    /** The PartInstance of the piece, if the part has been nexted */
+    part_instance_id: Option<PartInstanceId>,
+    piece_instance_id: Option<PieceInstanceId>,
+}
+
+/**
+ * Generate the lookahead objects for each output layer, from the pieces of the parts following the current part
+ */
+pub async fn get_lookahead_objects(
+    context: &JobContext,
+    cache: &PlayoutCache,
+) -> Result<Vec<TimelineObjGenerated>, JobError> {
+    let next_part_instance = cache.get_next_part_instance();
+    let ref_part_instance = match next_part_instance
+        .clone()
+        .or_else(|| cache.get_current_part_instance())
+    {
+        Some(part_instance) => part_instance,
+        None => return Ok(Vec::new()),
+    };
+
+    let rundown = cache
+        .rundowns
+        .find_one_by_id(&ref_part_instance.rundown_id)
+        .ok_or_else(|| {
+            JobError::NotFound(DocumentId::Rundown(ref_part_instance.rundown_id.clone()))
+        })?;
+    let show_style_base = context
+        .get_show_style_base(&rundown.show_style_base_id)
+        .await?
+        .ok_or_else(|| {
+            JobError::NotFound(DocumentId::ShowStyleBase(
+                rundown.show_style_base_id.clone(),
+            ))
+        })?;
+
+    let layers = show_style_base
+        .output_layers
+        .iter()
+        .filter(|(_, layer)| layer.lookahead != LookaheadMode::None)
+        .sorted_by_key(|(id, _)| id.as_str())
+        .collect::<Vec<_>>();
+    if layers.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let max_search_distance = layers
+        .iter()
+        .map(|(_, layer)| get_search_distance(layer))
+        .max()
+        .unwrap_or(LOOKAHEAD_DEFAULT_SEARCH_DISTANCE);
+
+    // The pieces of each upcoming part, in the order they will be played
+    let mut parts_pieces: Vec<Vec<LookaheadPiece>> = Vec::new();
+
+    if let Some(next_part_instance) = &next_part_instance {
+        // Infinites continued into the next part are already playing
+        let next_pieces = cache
+            .piece_instances
+            .find_some(|p| {
+                p.part_instance_id == next_part_instance.id
+                    && !p.disabled
+                    && p.infinite
+                        .as_ref()
+                        .map_or(true, |inf| inf.infinite_instance_index == 0)
+            })
+            .into_iter()
+            .map(|p| LookaheadPiece {
+                piece: p.piece,
+                part_instance_id: Some(next_part_instance.id.clone()),
+                piece_instance_id: Some(p.id),
+            })
+            .collect();
+        parts_pieces.push(next_pieces);
+    }
+
+    let future_part_ids = cache
+        .get_ordered_segments_and_parts()
+        .parts
+        .into_iter()
+        .skip_while(|p| p.id != ref_part_instance.part.id)
+        .skip(1)
+        .filter(|p| p.is_playable())
+        .take(max_search_distance.saturating_sub(parts_pieces.len()))
+        .map(|p| p.id)
+        .collect::<Vec<PartId>>();
+
+    if !future_part_ids.is_empty() {
+        let pieces = context
+            .direct_collections()
+            .pieces
+            .find_fetch(
+                doc! {
+                    "startPartId": { "$in": unprotect_array(&future_part_ids) },
+                    "invalid": { "$ne": true },
+                },
+                Some(FindOptions {
+                    // These pieces are only used to build timeline objects, so skip the ingest data
+                    projection: Some(doc! {
+                        "metaData": 0,
+                        "expectedPlayoutItems": 0,
+                        "expectedPackages": 0,
+                        "allowDirectPlay": 0,
+                    }),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
+        let mut pieces_by_part = pieces
+            .into_iter()
+            .into_group_map_by(|p| p.start_part_id.clone());
+        for part_id in future_part_ids.iter() {
+            let part_pieces = pieces_by_part
+                .remove(part_id)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|piece| LookaheadPiece {
+                    piece,
+                    part_instance_id: None,
+                    piece_instance_id: None,
+                })
+                .collect();
+            parts_pieces.push(part_pieces);
+        }
+    }
+
+    let mut objs = Vec::new();
+    for (layer_id, layer) in layers {
+        let pieces = find_lookahead_pieces_for_layer(
+            &parts_pieces,
+            layer_id,
+            layer.lookahead_depth.unwrap_or(1),
+            get_search_distance(layer),
+        );
+
+        for (index, piece) in pieces.into_iter().enumerate() {
+            objs.extend(create_lookahead_objects(piece, layer.lookahead, index));
+        }
+    }
+
+    Ok(objs)
+}
+
+fn get_search_distance(layer: &OutputLayer) -> usize {
+    layer
+        .lookahead_max_search_distance
+        .unwrap_or(LOOKAHEAD_DEFAULT_SEARCH_DISTANCE)
+}
+
+/**
+ * Find the first piece on the layer in each of the parts, until enough have been found
+ */
+fn find_lookahead_pieces_for_layer<'a>(
+    parts_pieces: &'a [Vec<LookaheadPiece>],
+    layer_id: &str,
+    depth: usize,
+    search_distance: usize,
+) -> Vec<&'a LookaheadPiece> {
+    parts_pieces
+        .iter()
+        .take(search_distance)
+        .filter_map(|pieces| {
+            pieces
+                .iter()
+                .filter(|p| p.piece.output_layer_id == layer_id && !p.piece.virtual_)
+                .min_by_key(|p| match p.piece.enable.start {
+                    PieceEnableStart::Offset(start) => start,
+                    PieceEnableStart::Now => Duration::zero(),
+                })
+        })
+        .take(depth)
+        .collect()
+}
+
+/**
+ * Create the lookahead objects for a piece. Only the top level objects are used, as the lookahead is not played in a group
+ */
+fn create_lookahead_objects(
+    piece: &LookaheadPiece,
+    mode: LookaheadMode,
+    index: usize,
+) -> Vec<TimelineObjGenerated> {
+    parse_piece_timeline_objects(&piece.piece)
+        .into_iter()
+        .filter(|obj| !obj.is_group && obj.in_group.is_none())
+        .map(|obj| {
+            let (layer, lookahead_for_layer) = match mode {
+                LookaheadMode::Preload => (format!("{}_lookahead", obj.layer), Some(obj.layer)),
+                LookaheadMode::WhenClear | LookaheadMode::None => (obj.layer, None),
+            };
+
+            TimelineObjGenerated {
+                id: format!("lookahead_{}_{}", piece.piece.id.unprotect(), obj.id),
+                object_type: TimelineObjType::Rundown,
+                enable: TimelineEnable {
+                    while_: Some(TimelineEnableValue::Time(1)),
+                    ..Default::default()
+                },
+                layer,
+                // The nearest piece takes precedence
+                priority: LOOKAHEAD_OBJ_PRIORITY - index as i64,
+                content: obj.content,
+                classes: obj.classes,
+                is_group: false,
+                in_group: None,
+                part_instance_id: piece.part_instance_id.clone(),
+                piece_instance_id: piece.piece_instance_id.clone(),
+                is_lookahead: true,
+                lookahead_for_layer,
+                other: obj.other,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use futures::executor::block_on;
+    use serde_json::json;
+
+    use super::*;
+    use crate::{
+        context::direct_collections::{DirectCollections, InMemoryCollectionsData},
+        data_model::{ids::RundownPlaylistId, piece::PieceLifespan},
+        playout::fixtures::*,
+    };
+
+    /** Create a piece of the part on the output layer */
+    fn create_layer_piece(
+        id: &str,
+        part_id: &str,
+        output_layer_id: &str,
+        start: i64,
+        timeline_objects: serde_json::Value,
+    ) -> Piece {
+        let part = create_part(part_id, "segment0", "rundown0");
+        let mut piece = create_piece(id, &part, PieceLifespan::WithinPart);
+        piece.output_layer_id = output_layer_id.to_string();
+        piece.enable.start = PieceEnableStart::Offset(Duration::milliseconds(start));
+        piece.timeline_objects_string = timeline_objects.to_string();
+        piece
+    }
+
+    fn lookahead_piece(piece: Piece) -> LookaheadPiece {
+        LookaheadPiece {
+            piece,
+            part_instance_id: None,
+            piece_instance_id: None,
+        }
+    }
+
+    fn get_ids(pieces: &[&LookaheadPiece]) -> Vec<String> {
+        pieces
+            .iter()
+            .map(|p| p.piece.id.unprotect().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn lookahead_uses_earliest_piece_of_each_part() {
+        let mut virtual_piece = create_layer_piece("virtual", "part0", "pgm", -1000, json!([]));
+        virtual_piece.virtual_ = true;
+        let mut now_piece = create_layer_piece("now", "part1", "pgm", 1000, json!([]));
+        now_piece.enable.start = PieceEnableStart::Now;
+
+        let parts_pieces = vec![
+            vec![
+                lookahead_piece(create_layer_piece("late", "part0", "pgm", 1000, json!([]))),
+                lookahead_piece(create_layer_piece("early", "part0", "pgm", 500, json!([]))),
+                lookahead_piece(create_layer_piece("other", "part0", "aux", 0, json!([]))),
+                lookahead_piece(virtual_piece),
+            ],
+            vec![
+                lookahead_piece(create_layer_piece("timed", "part1", "pgm", 100, json!([]))),
+                lookahead_piece(now_piece),
+            ],
+        ];
+
+        let pieces = find_lookahead_pieces_for_layer(&parts_pieces, "pgm", 10, 10);
+        assert_eq!(get_ids(&pieces), vec!["early", "now"]);
+
+        let pieces = find_lookahead_pieces_for_layer(&parts_pieces, "aux", 10, 10);
+        assert_eq!(get_ids(&pieces), vec!["other"]);
+    }
+
+    #[test]
+    fn lookahead_is_limited_by_depth_and_search_distance() {
+        let parts_pieces = vec![
+            vec![lookahead_piece(create_layer_piece(
+                "aux0",
+                "part0",
+                "aux",
+                0,
+                json!([]),
+            ))],
+            vec![lookahead_piece(create_layer_piece(
+                "pgm1",
+                "part1",
+                "pgm",
+                0,
+                json!([]),
+            ))],
+            vec![lookahead_piece(create_layer_piece(
+                "pgm2",
+                "part2",
+                "pgm",
+                0,
+                json!([]),
+            ))],
+            vec![lookahead_piece(create_layer_piece(
+                "pgm3",
+                "part3",
+                "pgm",
+                0,
+                json!([]),
+            ))],
+        ];
+
+        for (depth, search_distance, expected) in [
+            (10, 10, vec!["pgm1", "pgm2", "pgm3"]),
+            // Parts without a piece on the layer don't count towards the depth
+            (1, 10, vec!["pgm1"]),
+            (2, 10, vec!["pgm1", "pgm2"]),
+            // But they do count towards the search distance
+            (10, 1, vec![]),
+            (10, 2, vec!["pgm1"]),
+            (10, 3, vec!["pgm1", "pgm2"]),
+            (0, 10, vec![]),
+        ] {
+            let pieces =
+                find_lookahead_pieces_for_layer(&parts_pieces, "pgm", depth, search_distance);
+            assert_eq!(
+                get_ids(&pieces),
+                expected,
+                "depth {} search distance {}",
+                depth,
+                search_distance
+            );
+        }
+    }
+
+    #[test]
+    fn lookahead_objects_only_use_top_level_objects() {
+        let piece = LookaheadPiece {
+            piece: create_layer_piece(
+                "piece0",
+                "part0",
+                "pgm",
+                0,
+                json!([
+                    { "id": "obj0", "enable": { "start": 0 }, "layer": "vt", "content": { "file": "a" }, "classes": ["x"] },
+                    { "id": "group0", "enable": { "start": 0 }, "layer": "", "content": {}, "isGroup": true },
+                    { "id": "obj1", "enable": { "start": 0 }, "layer": "cg", "content": {}, "inGroup": "group0" },
+                ]),
+            ),
+            part_instance_id: Some(PartInstanceId::new_from("part0_instance".to_string())),
+            piece_instance_id: Some(PieceInstanceId::new_from("piece0_instance".to_string())),
+        };
+
+        for (mode, expected_layer, expected_lookahead_for_layer) in [
+            (LookaheadMode::Preload, "vt_lookahead", Some("vt")),
+            (LookaheadMode::WhenClear, "vt", None),
+        ] {
+            let objs = create_lookahead_objects(&piece, mode, 2);
+            assert_eq!(objs.len(), 1, "{:?}", mode);
+
+            let obj = &objs[0];
+            assert_eq!(obj.id, "lookahead_piece0_obj0");
+            assert_eq!(obj.layer, expected_layer);
+            assert_eq!(
+                obj.lookahead_for_layer.as_deref(),
+                expected_lookahead_for_layer
+            );
+            assert!(obj.is_lookahead);
+            assert_eq!(obj.in_group, None);
+            assert_eq!(obj.priority, LOOKAHEAD_OBJ_PRIORITY - 2);
+            assert_eq!(obj.enable.while_, Some(TimelineEnableValue::Time(1)));
+            assert_eq!(obj.content, json!({ "file": "a" }));
+            assert_eq!(obj.classes, vec!["x".to_string()]);
+            assert_eq!(obj.part_instance_id, piece.part_instance_id);
+            assert_eq!(obj.piece_instance_id, piece.piece_instance_id);
+        }
+    }
+
+    fn create_collections() -> Rc<DirectCollections> {
+        let timeline_objects =
+            json!([{ "id": "obj0", "enable": { "start": 0 }, "layer": "vt", "content": {} }]);
+
+        let part0 = create_part("part0", "segment0", "rundown0");
+        let mut part1 = create_part("part1", "segment0", "rundown0");
+        part1.rank = 1.0;
+        let part0_instance = create_part_instance(&part0);
+
+        let mut playlist = create_playlist(&["rundown0"]);
+        playlist.next_part_instance_id = Some(part0_instance.id.clone());
+
+        let next_pieces = vec![
+            // A continued infinite is already playing, so must not be looked ahead to, despite starting first
+            ("continued", 0, 1),
+            ("started", 500, 0),
+        ]
+        .into_iter()
+        .map(|(id, start, infinite_instance_index)| {
+            let piece = create_layer_piece(id, "part0", "pgm", start, timeline_objects.clone());
+            let mut piece_instance = create_infinite_piece_instance(piece, &part0_instance);
+            if let Some(infinite) = piece_instance.infinite.as_mut() {
+                infinite.infinite_instance_index = infinite_instance_index;
+            }
+            piece_instance
+        })
+        .collect();
+
+        let mut show_style_base = create_show_style_base("showstyle0");
+        show_style_base
+            .output_layers_with_overrides
+            .defaults
+            .insert(
+                "pgm".to_string(),
+                OutputLayer {
+                    lookahead: LookaheadMode::WhenClear,
+                    lookahead_depth: Some(2),
+                    lookahead_max_search_distance: None,
+                },
+            );
+
+        DirectCollections::create_in_memory(InMemoryCollectionsData {
+            rundown_playlists: vec![playlist],
+            rundowns: vec![create_rundown("rundown0", "showstyle0")],
+            segments: vec![create_segment("segment0", "rundown0")],
+            parts: vec![part0, part1],
+            part_instances: vec![part0_instance],
+            piece_instances: next_pieces,
+            pieces: vec![create_layer_piece(
+                "future",
+                "part1",
+                "pgm",
+                0,
+                timeline_objects,
+            )],
+            show_style_bases: vec![show_style_base],
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn lookahead_excludes_continued_infinites() {
+        let collections = create_collections();
+        let (context, _events_receiver) = create_context(&collections);
+        let cache = block_on(PlayoutCache::create(
+            &collections,
+            &RundownPlaylistId::new_from(PLAYLIST_ID.to_string()),
+        ))
+        .unwrap();
+
+        let objs = block_on(get_lookahead_objects(&context, &cache)).unwrap();
+
+        let ids = objs.iter().map(|o| o.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(ids, vec!["lookahead_started_obj0", "lookahead_future_obj0"]);
+        assert_eq!(
+            objs[0].piece_instance_id,
+            Some(PieceInstanceId::new_from(
+                "part0_instance_started".to_string()
+            ))
+        );
+        assert_eq!(objs[1].piece_instance_id, None);
+    }
+}
@@ -1,10 +1,22 @@
+pub mod active_playlist;
+pub mod autonext;
 pub mod cache;
 mod cleanup_orphaned;
+#[cfg(test)]
+pub mod fixtures;
+pub mod hold;
 mod infinites;
 mod infinites2;
 mod lib;
+pub mod lock;
+pub mod lookahead;
+pub mod move_next_part;
+pub mod playback;
 mod playlist;
 pub mod select_next_part;
 pub mod set_next_part;
+pub mod set_next_segment;
+pub mod stop_pieces;
 pub mod take;
+pub mod timeline;
 pub mod timings;
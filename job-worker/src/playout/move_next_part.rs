@@ -0,0 +1,338 @@
+use std::collections::HashMap;
+
+use log::warn;
+
+use crate::{
+    cache::object::DbCacheReadObject,
+    context::context::JobContext,
+    data_model::{
+        ids::{PartId, ProtectedId, RundownPlaylistId, SegmentId},
+        part::Part,
+        rundown_playlist::RundownHoldState,
+    },
+    error::{JobError, UserError, UserErrorMessage},
+};
+
+use super::{
+    cache::PlayoutCache,
+    lock::run_job_with_playout_cache,
+    playlist::sort_parts_in_sorted_segments,
+    select_next_part::SelectNextPartResult,
+    set_next_part::{setNextPart, SetNextPartTarget},
+    timeline::update_timeline,
+};
+
+/**
+ * Move the next part by a number of parts, or by a number of segments.
+ * Returns the id of the newly nexted part, if one was found
+ */
+pub async fn handle_move_next_part(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    parts_delta: isize,
+    segments_delta: isize,
+) -> Result<Option<PartId>, JobError> {
+    if parts_delta == 0 && segments_delta == 0 {
+        return Err(JobError::Internal(format!(
+            "rundownMoveNext: invalid delta: ({}, {})",
+            parts_delta, segments_delta
+        )));
+    }
+
+    run_job_with_playout_cache(context, playlist_id, move |context, cache| {
+        Box::pin(async move {
+            let playlist = cache.playlist.doc();
+            if playlist.activation_id.is_none() {
+                return Err(UserError::create(UserErrorMessage::InactiveRundown).into());
+            }
+            if playlist.hold_state == RundownHoldState::ACTIVE
+                || playlist.hold_state == RundownHoldState::PENDING
+            {
+                return Err(UserError::create(UserErrorMessage::DuringHold).into());
+            }
+            if playlist.next_part_instance_id.is_none()
+                && playlist.current_part_instance_id.is_none()
+            {
+                return Err(UserError::create(UserErrorMessage::NoCurrentOrNextPart).into());
+            }
+
+            move_next_part(context, cache, parts_delta, segments_delta).await
+        })
+    })
+    .await
+}
+
+pub async fn move_next_part(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    parts_delta: isize,
+    segments_delta: isize,
+) -> Result<Option<PartId>, JobError> {
+    let current_part_instance = cache.get_current_part_instance();
+    let ref_part = cache
+        .get_next_part_instance()
+        .or_else(|| current_part_instance.clone())
+        .map(|instance| instance.part)
+        .ok_or_else(|| {
+            JobError::Internal(format!(
+                "RundownPlaylist \"{}\" has no next and no current part!",
+                cache.playlist.doc_id().unprotect()
+            ))
+        })?;
+    let current_part_id = current_part_instance.map(|instance| instance.part.id);
+
+    let ordered = cache.get_ordered_segments_and_parts();
+
+    let selected_part = if segments_delta != 0 {
+        // Ignores parts_delta
+        let consider_segments = ordered
+            .segments
+            .iter()
+            .filter(|s| s.id == ref_part.segment_id || !s.is_hidden)
+            .collect::<Vec<_>>();
+        let ref_segment_index = consider_segments
+            .iter()
+            .position(|s| s.id == ref_part.segment_id)
+            .ok_or_else(|| {
+                JobError::Internal(format!(
+                    "Segment \"{}\" not found!",
+                    ref_part.segment_id.unprotect()
+                ))
+            })?;
+
+        let target_segment_index = ref_segment_index as isize + segments_delta;
+        if target_segment_index < 0 || target_segment_index as usize >= consider_segments.len() {
+            return Err(JobError::Internal("No Segment found!".to_string()));
+        }
+        let target_segment_index = target_segment_index as usize;
+
+        // Find the segments which are allowed to be nexted, in the order to search them
+        let allowed_segments = if segments_delta > 0 {
+            consider_segments[target_segment_index..].to_vec()
+        } else {
+            consider_segments[..=target_segment_index]
+                .iter()
+                .rev()
+                .cloned()
+                .collect()
+        };
+
+        let mut playable_parts_by_segment: HashMap<&SegmentId, Vec<&Part>> = HashMap::new();
+        for part in ordered.parts.iter().filter(|p| p.is_playable()) {
+            playable_parts_by_segment
+                .entry(&part.segment_id)
+                .or_default()
+                .push(part);
+        }
+
+        // Find the first part of the first segment which has something we can next
+        allowed_segments.into_iter().find_map(|segment| {
+            playable_parts_by_segment
+                .get(&segment.id)
+                .and_then(|parts| {
+                    // Cant go to the current part (yet)
+                    parts
+                        .iter()
+                        .find(|p| Some(&p.id) != current_part_id.as_ref())
+                })
+                .map(|p| (*p).clone())
+        })
+    } else {
+        let mut playable_parts = ordered
+            .parts
+            .iter()
+            .filter(|p| p.id == ref_part.id || p.is_playable())
+            .cloned()
+            .collect::<Vec<_>>();
+        let ref_part_index = match playable_parts.iter().position(|p| p.id == ref_part.id) {
+            Some(index) => index,
+            None => {
+                // The part is orphaned, so insert it where it would be, making sure it won't be nexted itself
+                let mut tmp_ref_part = ref_part.clone();
+                tmp_ref_part.invalid = true;
+                playable_parts.push(tmp_ref_part);
+                playable_parts = sort_parts_in_sorted_segments(playable_parts, &ordered.segments);
+
+                playable_parts
+                    .iter()
+                    .position(|p| p.id == ref_part.id)
+                    .ok_or_else(|| {
+                        JobError::Internal(format!(
+                            "Part \"{}\" not found after insert!",
+                            ref_part.id.unprotect()
+                        ))
+                    })?
+            }
+        };
+
+        let get_part = |index: isize| {
+            if index < 0 {
+                None
+            } else {
+                playable_parts
+                    .get(index as usize)
+                    .filter(|p| p.is_playable())
+            }
+        };
+
+        let target_part_index = ref_part_index as isize + parts_delta;
+        match get_part(target_part_index) {
+            Some(target_part) if Some(&target_part.id) == current_part_id.as_ref() => {
+                // Cant go to the current part (yet)
+                get_part(target_part_index + parts_delta.signum()).cloned()
+            }
+            target_part => target_part.cloned(),
+        }
+    };
+
+    match selected_part {
+        Some(selected_part) => {
+            let index = ordered
+                .parts
+                .iter()
+                .position(|p| p.id == selected_part.id)
+                .unwrap_or_default();
+
+            setNextPart(
+                context,
+                cache,
+                Some(SetNextPartTarget::Part(SelectNextPartResult {
+                    part_id: selected_part.id.clone(),
+                    segment_id: selected_part.segment_id.clone(),
+                    index,
+                    consumes_next_segment_id: false,
+                })),
+                true,
+                None,
+            )
+            .await?;
+
+            update_timeline(context, cache, None).await?;
+
+            Ok(Some(selected_part.id))
+        }
+        None => {
+            // Nothing looked valid so do nothing
+            warn!(
+                "moveNextPart: Found no new part (partsDelta={}, segmentsDelta={})",
+                parts_delta, segments_delta
+            );
+            Ok(None)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::direct_collections::DirectCollections,
+        data_model::{ids::StudioId, part_instance::PartInstance},
+        playout::fixtures::*,
+    };
+
+    async fn fetch_next_part_instance(collections: &DirectCollections) -> PartInstance {
+        let next_id = fetch_playlist(collections)
+            .await
+            .next_part_instance_id
+            .expect("no next part");
+        fetch_part_instance(collections, &next_id).await
+    }
+
+    #[tokio::test]
+    async fn move_next_part_skips_current_part() {
+        let mut data = create_playlist_data();
+        add_selected_part_instances(&mut data, 1, 2);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let part_id = handle_move_next_part(&context, &playlist_id(), -1, 0)
+            .await
+            .unwrap();
+
+        let part0 = PartId::new_from("part0".to_string());
+        assert_eq!(part_id, Some(part0.clone()));
+        let next = fetch_next_part_instance(&collections).await;
+        assert_eq!(next.part.id, part0);
+
+        // The timeline has been regenerated
+        let timeline = collections
+            .timelines
+            .find_one_by_id(&StudioId::new_from("studio0".to_string()), None)
+            .await
+            .unwrap();
+        assert!(timeline.is_some());
+    }
+
+    #[tokio::test]
+    async fn move_next_part_skips_unplayable_parts() {
+        let mut data = create_playlist_data();
+        data.parts[2].floated = true;
+        let mut part3 = create_part("part3", "segment1", "rundown0");
+        part3.rank = 3.0;
+        data.parts.push(part3);
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let part_id = handle_move_next_part(&context, &playlist_id(), 1, 0)
+            .await
+            .unwrap();
+
+        let part3 = PartId::new_from("part3".to_string());
+        assert_eq!(part_id, Some(part3.clone()));
+        assert_eq!(fetch_next_part_instance(&collections).await.part.id, part3);
+    }
+
+    #[tokio::test]
+    async fn move_next_segment() {
+        let mut data = create_playlist_data();
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let part_id = handle_move_next_part(&context, &playlist_id(), 0, 1)
+            .await
+            .unwrap();
+
+        let part2 = PartId::new_from("part2".to_string());
+        assert_eq!(part_id, Some(part2.clone()));
+        assert_eq!(fetch_next_part_instance(&collections).await.part.id, part2);
+    }
+
+    #[tokio::test]
+    async fn move_next_part_is_rejected_during_hold() {
+        let mut data = create_playlist_data();
+        let (_current, next) = add_selected_part_instances(&mut data, 0, 1);
+        data.rundown_playlists[0].hold_state = RundownHoldState::PENDING;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_move_next_part(&context, &playlist_id(), 1, 0).await,
+            UserErrorMessage::DuringHold,
+        );
+        assert_eq!(
+            fetch_playlist(&collections).await.next_part_instance_id,
+            Some(next.id)
+        );
+    }
+
+    #[tokio::test]
+    async fn move_next_part_is_rejected_for_inactive_playlist() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_move_next_part(&context, &playlist_id(), 1, 0).await,
+            UserErrorMessage::InactiveRundown,
+        );
+    }
+}
@@ -0,0 +1,689 @@
+use chrono::{DateTime, Duration, Utc};
+use log::{error, info, warn};
+
+use crate::{
+    cache::{
+        collection::{DbCacheReadCollection, DbCacheWriteCollection},
+        object::{DbCacheReadObject, DbCacheWriteObject},
+    },
+    context::context::JobContext,
+    data_model::{
+        ids::{PartInstanceId, PieceInstanceId, ProtectedId, RundownPlaylistId},
+        part_instance::PartInstance,
+        piece_instance::PieceInstance,
+        rundown_playlist::RundownHoldState,
+    },
+    error::{DocumentId, JobError},
+};
+
+use super::{
+    cache::PlayoutCache,
+    lock::run_job_with_playout_cache,
+    select_next_part::select_next_part,
+    set_next_part::{setNextPart, SetNextPartTarget},
+    take::{after_take, clear_next_segment_id, reset_previous_segment, updatePartInstanceOnTake},
+    timeline::update_timeline,
+};
+
+/**
+ * How long to wait between repeated reports of a part playing which was not selected, before updating the timeline again
+ */
+const INCORRECT_PLAYING_PART_DEBOUNCE: i64 = 5000;
+
+/**
+ * The playout gateway reports that a PartInstance has started playback
+ */
+pub async fn handle_on_part_playback_started(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    part_instance_id: &PartInstanceId,
+    started_playback: DateTime<Utc>,
+) -> Result<(), JobError> {
+    let part_instance_id = part_instance_id.clone();
+    run_job_with_playout_cache(context, playlist_id, move |context, cache| {
+        Box::pin(on_part_playback_started(
+            context,
+            cache,
+            part_instance_id,
+            started_playback,
+        ))
+    })
+    .await
+}
+
+async fn on_part_playback_started(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    part_instance_id: PartInstanceId,
+    started_playback: DateTime<Utc>,
+) -> Result<(), JobError> {
+    let playing_part_instance = cache
+        .part_instances
+        .find_one_by_id(&part_instance_id)
+        .ok_or_else(|| JobError::NotFound(DocumentId::PartInstance(part_instance_id.clone())))?;
+
+    // make sure we don't run multiple times, even if TSR calls us multiple times
+    if playing_part_instance
+        .timings
+        .reported_started_playback
+        .is_some()
+    {
+        return Ok(());
+    }
+
+    info!(
+        "Playout reports PartInstance \"{}\" has started playback on timestamp {}",
+        part_instance_id.unprotect(),
+        started_playback
+    );
+
+    let rundown = cache
+        .rundowns
+        .find_one_by_id(&playing_part_instance.rundown_id)
+        .ok_or_else(|| {
+            JobError::NotFound(DocumentId::Rundown(
+                playing_part_instance.rundown_id.clone(),
+            ))
+        })?;
+
+    let playlist = cache.playlist.doc();
+    if playlist.current_part_instance_id.as_ref() == Some(&part_instance_id) {
+        // this is the current part, it has just started playback
+        report_part_instance_has_started(cache, &playing_part_instance, started_playback)?;
+
+        // complete the take
+        after_take(context, cache, &playing_part_instance, None).await?;
+    } else if playlist.next_part_instance_id.as_ref() == Some(&part_instance_id) {
+        // this is the next part, clearly an autoNext has taken place
+        let current_part_instance = cache.get_current_part_instance();
+
+        cache.playlist.update(|doc| {
+            let mut res = doc.clone();
+
+            res.previous_part_instance_id = res.current_part_instance_id;
+            res.current_part_instance_id = Some(playing_part_instance.id.clone());
+            res.hold_state = RundownHoldState::NONE;
+
+            Some(res)
+        })?;
+
+        report_part_instance_has_started(cache, &playing_part_instance, started_playback)?;
+
+        // Update generated properties on the newly playing partInstance
+        let show_style_rundown = current_part_instance
+            .as_ref()
+            .and_then(|instance| cache.rundowns.find_one_by_id(&instance.rundown_id))
+            .unwrap_or_else(|| rundown.clone());
+        let show_style = context
+            .get_show_style_compound(
+                &show_style_rundown.show_style_variant_id,
+                &show_style_rundown.show_style_base_id,
+            )
+            .await?
+            .ok_or_else(|| {
+                JobError::NotFound(DocumentId::ShowStyleBase(
+                    show_style_rundown.show_style_base_id.clone(),
+                ))
+            })?;
+
+        updatePartInstanceOnTake(
+            context,
+            cache,
+            &show_style,
+            &rundown,
+            &playing_part_instance,
+            current_part_instance.as_ref(),
+        )?;
+
+        clear_next_segment_id(cache, &playing_part_instance)?;
+        reset_previous_segment(cache)?;
+
+        // Update the next partinstance
+        let next_part = select_next_part(
+            cache.playlist.doc(),
+            Some(&playing_part_instance),
+            None,
+            cache.get_ordered_segments_and_parts(),
+            true,
+        );
+        setNextPart(
+            context,
+            cache,
+            next_part.map(SetNextPartTarget::Part),
+            false,
+            None,
+        )
+        .await?;
+
+        // complete the take
+        after_take(context, cache, &playing_part_instance, None).await?;
+    } else {
+        // a part is being played that has not been selected for playback by Core
+        // show must go on, so find next part and update the Rundown, but log an error
+        let now = Utc::now();
+        let previous_reported = playlist.last_incorrect_part_playback_reported;
+        if previous_reported.map_or(true, |previous_reported| {
+            now - previous_reported > Duration::milliseconds(INCORRECT_PLAYING_PART_DEBOUNCE)
+        }) {
+            // first time this has happened for a while, let's make sure it has the correct timeline
+            update_timeline(context, cache, None).await?;
+        }
+
+        cache.playlist.update(|doc| {
+            let mut res = doc.clone();
+            res.last_incorrect_part_playback_reported = Some(now);
+            Some(res)
+        })?;
+
+        error!(
+            "PartInstance \"{}\" has started playback by the playout gateway, but has not been selected for playback!",
+            part_instance_id.unprotect()
+        );
+    }
+
+    Ok(())
+}
+
+/**
+ * Record that a PartInstance has started playback, and the playlist and rundown if this is their first part
+ */
+fn report_part_instance_has_started(
+    cache: &mut PlayoutCache,
+    part_instance: &PartInstance,
+    timestamp: DateTime<Utc>,
+) -> Result<(), JobError> {
+    let timestamp_updated = cache.part_instances.update_one(&part_instance.id, |doc| {
+        let mut res = doc.clone();
+        let mut changed = false;
+
+        // If the startedPlayback has already been set, we shouldn't set it to another value
+        if res.timings.reported_started_playback.is_none() {
+            changed = true;
+            res.timings.reported_started_playback = Some(timestamp);
+            res.timings.planned_started_playback = Some(timestamp);
+        }
+
+        // Unset stoppedPlayback if it is set
+        if res.timings.reported_stopped_playback.is_some() || res.timings.duration.is_some() {
+            changed = true;
+            res.timings.reported_stopped_playback = None;
+            res.timings.duration = None;
+            res.timings.planned_stopped_playback = None;
+        }
+
+        if changed {
+            Some(res)
+        } else {
+            None
+        }
+    })?;
+
+    if timestamp_updated {
+        if let Some(previous_part_instance_id) =
+            cache.playlist.doc().previous_part_instance_id.clone()
+        {
+            // Ensure the plannedStoppedPlayback is set for the previous partinstance too
+            cache
+                .part_instances
+                .update_one(&previous_part_instance_id, |doc| {
+                    if doc.timings.planned_stopped_playback.is_none() {
+                        let mut res = doc.clone();
+                        res.timings.planned_stopped_playback = Some(timestamp);
+                        Some(res)
+                    } else {
+                        None
+                    }
+                })?;
+        }
+    }
+
+    // If the partInstance is "untimed", it will not update the playlist's startedPlayback and will not count time in the GUI
+    if !part_instance.part.untimed {
+        cache.playlist.update(|doc| {
+            let mut res = doc.clone();
+
+            let rundowns_started_playback = res
+                .rundowns_started_playback
+                .get_or_insert_with(Default::default);
+            let mut changed = false;
+            if !rundowns_started_playback.contains_key(&part_instance.rundown_id) {
+                rundowns_started_playback.insert(part_instance.rundown_id.clone(), timestamp);
+                changed = true;
+            }
+            if res.started_playback.is_none() {
+                res.started_playback = Some(timestamp);
+                changed = true;
+            }
+
+            if changed {
+                Some(res)
+            } else {
+                None
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+/**
+ * The playout gateway reports that a PartInstance has stopped playback
+ */
+pub async fn handle_on_part_playback_stopped(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    part_instance_id: &PartInstanceId,
+    stopped_playback: DateTime<Utc>,
+) -> Result<(), JobError> {
+    let part_instance_id = part_instance_id.clone();
+    run_job_with_playout_cache(context, playlist_id, move |_context, cache| {
+        Box::pin(async move {
+            if let Some(part_instance) = cache.part_instances.find_one_by_id(&part_instance_id) {
+                // make sure we don't run multiple times, even if TSR calls us multiple times
+                let is_playing = part_instance.timings.reported_started_playback.is_some()
+                    && part_instance.timings.reported_stopped_playback.is_none();
+                if is_playing {
+                    info!(
+                        "Playout reports PartInstance \"{}\" has stopped playback on timestamp {}",
+                        part_instance_id.unprotect(),
+                        stopped_playback
+                    );
+
+                    cache.part_instances.update_one(&part_instance_id, |doc| {
+                        let mut res = doc.clone();
+                        res.timings.reported_stopped_playback = Some(stopped_playback);
+                        res.timings.planned_stopped_playback = Some(stopped_playback);
+                        res.timings.duration = Some(
+                            stopped_playback
+                                - res
+                                    .timings
+                                    .reported_started_playback
+                                    .unwrap_or(stopped_playback),
+                        );
+                        Some(res)
+                    })?;
+                }
+
+                Ok(())
+            } else if cache.playlist.doc().activation_id.is_none() {
+                warn!(
+                    "onPartPlaybackStopped: Received for inactive RundownPlaylist \"{}\"",
+                    cache.playlist.doc_id().unprotect()
+                );
+                Ok(())
+            } else {
+                Err(JobError::NotFound(DocumentId::PartInstance(
+                    part_instance_id,
+                )))
+            }
+        })
+    })
+    .await
+}
+
+/**
+ * The playout gateway reports that a PieceInstance has started playback
+ */
+pub async fn handle_on_piece_playback_started(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    piece_instance_id: &PieceInstanceId,
+    started_playback: DateTime<Utc>,
+) -> Result<(), JobError> {
+    let piece_instance_id = piece_instance_id.clone();
+    run_job_with_playout_cache(context, playlist_id, move |_context, cache| {
+        Box::pin(async move {
+            match find_piece_instance_for_report(cache, &piece_instance_id)? {
+                Some(piece_instance) => {
+                    let is_playing = piece_instance.reported_started_playback.is_some()
+                        && piece_instance.reported_stopped_playback.is_none();
+                    if !is_playing {
+                        report_piece_has_started(cache, &piece_instance, started_playback)?;
+                        // We don't need to bother with an updateTimeline(), as this hasn't changed anything, but lets us accurately add started items when reevaluating
+                    }
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        })
+    })
+    .await
+}
+
+/**
+ * The playout gateway reports that a PieceInstance has stopped playback
+ */
+pub async fn handle_on_piece_playback_stopped(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    piece_instance_id: &PieceInstanceId,
+    stopped_playback: DateTime<Utc>,
+) -> Result<(), JobError> {
+    let piece_instance_id = piece_instance_id.clone();
+    run_job_with_playout_cache(context, playlist_id, move |_context, cache| {
+        Box::pin(async move {
+            match find_piece_instance_for_report(cache, &piece_instance_id)? {
+                Some(piece_instance) => {
+                    let is_playing = piece_instance.reported_started_playback.is_some()
+                        && piece_instance.reported_stopped_playback.is_none();
+                    if is_playing {
+                        cache
+                            .piece_instances
+                            .update_one(&piece_instance.id, |doc| {
+                                let mut res = doc.clone();
+                                res.reported_stopped_playback = Some(stopped_playback);
+                                res.planned_stopped_playback = Some(stopped_playback);
+                                Some(res)
+                            })?;
+                    }
+                    Ok(())
+                }
+                None => Ok(()),
+            }
+        })
+    })
+    .await
+}
+
+/**
+ * Find the PieceInstance a playback report is for. Reports for a playlist which has since been deactivated are ignored
+ */
+fn find_piece_instance_for_report(
+    cache: &PlayoutCache,
+    piece_instance_id: &PieceInstanceId,
+) -> Result<Option<PieceInstance>, JobError> {
+    if let Some(piece_instance) = cache.piece_instances.find_one_by_id(piece_instance_id) {
+        Ok(Some(piece_instance))
+    } else if cache.playlist.doc().activation_id.is_none() {
+        warn!(
+            "Received PieceInstance playback report for inactive RundownPlaylist \"{}\"",
+            cache.playlist.doc_id().unprotect()
+        );
+        Ok(None)
+    } else {
+        Err(JobError::NotFound(DocumentId::PieceInstance(
+            piece_instance_id.clone(),
+        )))
+    }
+}
+
+fn report_piece_has_started(
+    cache: &mut PlayoutCache,
+    piece_instance: &PieceInstance,
+    timestamp: DateTime<Utc>,
+) -> Result<(), JobError> {
+    if piece_instance.reported_started_playback == Some(timestamp) {
+        return Ok(());
+    }
+
+    cache
+        .piece_instances
+        .update_one(&piece_instance.id, |doc| {
+            let mut res = doc.clone();
+            res.reported_started_playback = Some(timestamp);
+            res.reported_stopped_playback = None;
+            res.planned_started_playback = Some(timestamp);
+            res.planned_stopped_playback = None;
+            Some(res)
+        })?;
+
+    // Update the copy in the next-part if there is one, so that the infinite has the same start after a take
+    let next_part_instance_id = cache.playlist.doc().next_part_instance_id.clone();
+    if let (Some(infinite), Some(next_part_instance_id)) =
+        (&piece_instance.infinite, next_part_instance_id)
+    {
+        cache.piece_instances.update_all(|doc| {
+            if doc.part_instance_id == next_part_instance_id
+                && doc.infinite.as_ref().map_or(false, |inf| {
+                    inf.infinite_instance_id == infinite.infinite_instance_id
+                })
+            {
+                let mut res = doc.clone();
+                res.reported_started_playback = Some(timestamp);
+                res.reported_stopped_playback = None;
+                res.planned_started_playback = Some(timestamp);
+                res.planned_stopped_playback = None;
+                Some(res)
+            } else {
+                None
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        context::direct_collections::{DirectCollections, InMemoryCollectionsData},
+        data_model::ids::PartId,
+        events::EventsJob,
+        playout::fixtures::*,
+    };
+
+    fn timestamp() -> DateTime<Utc> {
+        Utc.timestamp_millis_opt(1_000_000).unwrap()
+    }
+
+    /** The playlist data, with part0 taken but not yet reported as playing, and part1 next */
+    fn create_taken_data() -> (InMemoryCollectionsData, PartInstance, PartInstance) {
+        let mut data = create_playlist_data();
+        let (mut current, next) = add_selected_part_instances(&mut data, 0, 1);
+        current.timings.reported_started_playback = None;
+        data.part_instances[0] = current.clone();
+
+        (data, current, next)
+    }
+
+    fn piece_instance_id(part_instance: &PartInstance) -> PieceInstanceId {
+        PieceInstanceId::new_from(format!(
+            "{}_{}_piece",
+            part_instance.id.unprotect(),
+            part_instance.part.id.unprotect()
+        ))
+    }
+
+    async fn fetch_piece_instance(
+        collections: &DirectCollections,
+        id: &PieceInstanceId,
+    ) -> PieceInstance {
+        collections
+            .piece_instances
+            .find_one_by_id(id, None)
+            .await
+            .unwrap()
+            .expect("PieceInstance is missing")
+    }
+
+    #[tokio::test]
+    async fn current_part_started_playback() {
+        let (mut data, mut current, _next) = create_taken_data();
+        current.part.should_notify_current_playing_part = true;
+        data.part_instances[0] = current.clone();
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, mut events_receiver) = create_context(&collections);
+
+        handle_on_part_playback_started(&context, &playlist_id(), &current.id, timestamp())
+            .await
+            .unwrap();
+
+        let part_instance = fetch_part_instance(&collections, &current.id).await;
+        assert_eq!(
+            part_instance.timings.reported_started_playback,
+            Some(timestamp())
+        );
+        assert_eq!(
+            fetch_playlist(&collections).await.started_playback,
+            Some(timestamp())
+        );
+
+        // The NRCS is told of the part now playing
+        match events_receiver.try_recv() {
+            Ok(EventsJob::NotifyCurrentlyPlayingPart {
+                part_external_id, ..
+            }) => assert_eq!(part_external_id, Some("part0".to_string())),
+            job => panic!("Unexpected events job {:?}", job),
+        }
+
+        // A repeated report is ignored
+        let later = timestamp() + Duration::seconds(1);
+        handle_on_part_playback_started(&context, &playlist_id(), &current.id, later)
+            .await
+            .unwrap();
+
+        let part_instance = fetch_part_instance(&collections, &current.id).await;
+        assert_eq!(
+            part_instance.timings.reported_started_playback,
+            Some(timestamp())
+        );
+    }
+
+    #[tokio::test]
+    async fn next_part_started_playback_is_an_autonext() {
+        let mut data = create_playlist_data();
+        let (current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_on_part_playback_started(&context, &playlist_id(), &next.id, timestamp())
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.previous_part_instance_id, Some(current.id.clone()));
+        assert_eq!(playlist.current_part_instance_id, Some(next.id.clone()));
+
+        let new_next_id = playlist.next_part_instance_id.expect("no next part");
+        let new_next = fetch_part_instance(&collections, &new_next_id).await;
+        assert_eq!(new_next.part.id, PartId::new_from("part2".to_string()));
+
+        let playing = fetch_part_instance(&collections, &next.id).await;
+        assert_eq!(playing.timings.reported_started_playback, Some(timestamp()));
+
+        let previous = fetch_part_instance(&collections, &current.id).await;
+        assert_eq!(previous.timings.planned_stopped_playback, Some(timestamp()));
+    }
+
+    #[tokio::test]
+    async fn part_started_playback_is_rejected_for_unknown_part_instance() {
+        let (data, _current, _next) = create_taken_data();
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let result = handle_on_part_playback_started(
+            &context,
+            &playlist_id(),
+            &PartInstanceId::new_from("missing".to_string()),
+            timestamp(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(JobError::NotFound(DocumentId::PartInstance(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn part_stopped_playback() {
+        let mut data = create_playlist_data();
+        let (current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        // As stored, which is only to the millisecond
+        let started = fetch_part_instance(&collections, &current.id)
+            .await
+            .timings
+            .reported_started_playback
+            .unwrap();
+        handle_on_part_playback_stopped(&context, &playlist_id(), &current.id, timestamp())
+            .await
+            .unwrap();
+
+        let part_instance = fetch_part_instance(&collections, &current.id).await;
+        assert_eq!(
+            part_instance.timings.reported_stopped_playback,
+            Some(timestamp())
+        );
+        assert_eq!(part_instance.timings.duration, Some(timestamp() - started));
+
+        // The next part hasn't started, so can't stop
+        handle_on_part_playback_stopped(&context, &playlist_id(), &next.id, timestamp())
+            .await
+            .unwrap();
+        let part_instance = fetch_part_instance(&collections, &next.id).await;
+        assert_eq!(part_instance.timings.reported_stopped_playback, None);
+    }
+
+    #[tokio::test]
+    async fn piece_playback() {
+        let (data, current, _next) = create_taken_data();
+        let piece_instance_id = piece_instance_id(&current);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_on_piece_playback_started(&context, &playlist_id(), &piece_instance_id, timestamp())
+            .await
+            .unwrap();
+
+        let piece_instance = fetch_piece_instance(&collections, &piece_instance_id).await;
+        assert_eq!(piece_instance.reported_started_playback, Some(timestamp()));
+        assert_eq!(piece_instance.reported_stopped_playback, None);
+
+        let stopped = timestamp() + Duration::seconds(1);
+        handle_on_piece_playback_stopped(&context, &playlist_id(), &piece_instance_id, stopped)
+            .await
+            .unwrap();
+
+        let piece_instance = fetch_piece_instance(&collections, &piece_instance_id).await;
+        assert_eq!(piece_instance.reported_started_playback, Some(timestamp()));
+        assert_eq!(piece_instance.reported_stopped_playback, Some(stopped));
+    }
+
+    #[tokio::test]
+    async fn piece_playback_is_rejected_for_unknown_piece_instance() {
+        let (data, _current, _next) = create_taken_data();
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let result = handle_on_piece_playback_started(
+            &context,
+            &playlist_id(),
+            &PieceInstanceId::new_from("missing".to_string()),
+            timestamp(),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(JobError::NotFound(DocumentId::PieceInstance(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn piece_playback_is_ignored_for_inactive_playlist() {
+        let (mut data, _current, _next) = create_taken_data();
+        data.rundown_playlists[0].activation_id = None;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_on_piece_playback_stopped(
+            &context,
+            &playlist_id(),
+            &PieceInstanceId::new_from("missing".to_string()),
+            timestamp(),
+        )
+        .await
+        .unwrap();
+    }
+}
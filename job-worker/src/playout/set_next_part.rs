@@ -10,21 +10,94 @@ use crate::{
     },
     context::context::JobContext,
     data_model::{
-        ids::{PartInstanceId, ProtectedId, SegmentPlayoutId},
+        ids::{PartId, PartInstanceId, ProtectedId, RundownPlaylistId, SegmentPlayoutId},
         part_instance::{PartInstance, PartInstanceTimings},
+        rundown_playlist::RundownHoldState,
     },
+    error::{DocumentId, JobError, UserError, UserErrorMessage},
 };
 
 use super::{
     cache::PlayoutCache,
-    cleanup_orphaned::{cleanupOrphanedItems, resetPartInstancesWithPieceInstances},
+    cleanup_orphaned::{
+        cleanupOrphanedItems, resetPartInstancesWithPieceInstances, PartInstanceSelector,
+    },
     infinites2::{
         fetchPiecesThatMayBeActiveForPart, getPieceInstancesForPart,
         syncPlayheadInfinitesForNextPartInstance,
     },
+    lock::run_job_with_playout_cache,
     select_next_part::SelectNextPartResult,
+    timeline::update_timeline,
 };
 
+/**
+ * Set the next part to a Part chosen by the user.
+ * The current part can only be nexted again when set manually, to replay it
+ */
+pub async fn handle_set_next_part(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    next_part_id: &PartId,
+    set_manually: bool,
+    next_time_offset: Option<Duration>,
+) -> Result<(), JobError> {
+    let next_part_id = next_part_id.clone();
+    run_job_with_playout_cache(context, playlist_id, move |context, cache| {
+        Box::pin(async move {
+            let playlist = cache.playlist.doc();
+            if playlist.activation_id.is_none() {
+                return Err(UserError::create(UserErrorMessage::InactiveRundown).into());
+            }
+            if playlist.hold_state != RundownHoldState::NONE
+                && playlist.hold_state != RundownHoldState::COMPLETE
+            {
+                return Err(UserError::create(UserErrorMessage::DuringHold).into());
+            }
+
+            // Ensure the part is playable and belongs to this playlist
+            let ordered_parts = cache.get_ordered_segments_and_parts().parts;
+            let (index, next_part) = ordered_parts
+                .iter()
+                .enumerate()
+                .find(|(_, p)| p.id == next_part_id)
+                .ok_or_else(|| UserError::create(UserErrorMessage::PartNotFound))?;
+            if !next_part.is_playable() {
+                return Err(UserError::create(UserErrorMessage::PartNotPlayable).into());
+            }
+
+            let current_part_instance = cache.get_current_part_instance();
+            if !set_manually
+                && current_part_instance.map_or(false, |instance| instance.part.id == next_part.id)
+            {
+                return Err(UserError::create(UserErrorMessage::PartAlreadyCurrent).into());
+            }
+
+            // An offset of zero or less means to play the part from the start
+            let next_time_offset = next_time_offset.filter(|offset| *offset > Duration::zero());
+
+            setNextPart(
+                context,
+                cache,
+                Some(SetNextPartTarget::Part(SelectNextPartResult {
+                    part_id: next_part.id.clone(),
+                    segment_id: next_part.segment_id.clone(),
+                    index,
+                    consumes_next_segment_id: false,
+                })),
+                set_manually,
+                next_time_offset,
+            )
+            .await?;
+
+            update_timeline(context, cache, None).await?;
+
+            Ok(())
+        })
+    })
+    .await
+}
+
 pub enum SetNextPartTarget {
     Part(SelectNextPartResult),
     PartInstance(PartInstance),
@@ -37,7 +110,7 @@ pub async fn setNextPart(
     // 	rawNextPart: Omit<SelectNextPartResult, 'index'> | DBPartInstance | null,
     setManually: bool,
     nextTimeOffset: Option<Duration>,
-) -> Result<(), String> {
+) -> Result<(), JobError> {
     let rundownIds = cache
         .get_rundown_ids_from_cache()
         .into_iter()
@@ -46,25 +119,27 @@ pub async fn setNextPart(
     let nextPartInstance = cache.get_next_part_instance();
 
     if let Some(rawNextPart) = rawNextPart {
-        let activation_id = cache.playlist.doc().activation_id.clone().ok_or_else(|| {
-            format!(
-                "RundownPlaylist \"{}\" is not active",
-                cache.playlist.doc_id().unprotect()
-            )
-        })?;
+        let activation_id = cache
+            .playlist
+            .doc()
+            .activation_id
+            .clone()
+            .ok_or_else(|| UserError::create(UserErrorMessage::InactiveRundown))?;
 
         // create new instance
         let new_instance_id = match &rawNextPart {
             SetNextPartTarget::PartInstance(instance) => {
                 if instance.part.invalid {
-                    return Err("Part is marked as invalid, cannot set as next.".to_string());
+                    return Err(JobError::Internal(
+                        "Part is marked as invalid, cannot set as next.".to_string(),
+                    ));
                 } else if !rundownIds.contains(&instance.rundown_id) {
-                    return Err(format!(
+                    return Err(JobError::Internal(format!(
                         "PartInstance \"{}\" of rundown \"{}\" not part of RundownPlaylist \"{}\"",
                         instance.id.unprotect(),
                         instance.rundown_id.unprotect(),
                         cache.playlist.doc_id().unprotect()
-                    ));
+                    )));
                 }
 
                 cache
@@ -75,8 +150,7 @@ pub async fn setNextPart(
                         res.consumes_next_segment_id = false;
 
                         Some(res)
-                    })
-                    .map_err(|_| "Failed to reuse part instance".to_string())?;
+                    })?;
 
                 syncPlayheadInfinitesForNextPartInstance(context, cache).await?;
 
@@ -102,8 +176,7 @@ pub async fn setNextPart(
                             res.consumes_next_segment_id = false;
 
                             Some(res)
-                        })
-                        .map_err(|_| "Failed to reuse part instance".to_string())?;
+                        })?;
 
                     syncPlayheadInfinitesForNextPartInstance(context, cache).await?;
 
@@ -113,17 +186,21 @@ pub async fn setNextPart(
                     let part = cache
                         .parts
                         .find_one_by_id(&selected_part.part_id)
-                        .ok_or_else(|| "Failed to find part to set as next".to_string())?;
+                        .ok_or_else(|| {
+                            JobError::NotFound(DocumentId::Part(selected_part.part_id.clone()))
+                        })?;
 
                     if part.invalid {
-                        return Err("Part is marked as invalid, cannot set as next.".to_string());
+                        return Err(JobError::Internal(
+                            "Part is marked as invalid, cannot set as next.".to_string(),
+                        ));
                     } else if !rundownIds.contains(&part.rundown_id) {
-                        return Err(format!(
+                        return Err(JobError::Internal(format!(
                             "Part \"{}\" of rundown \"{}\" not part of RundownPlaylist \"{}\"",
                             part.id.unprotect(),
                             part.rundown_id.unprotect(),
                             cache.playlist.doc_id().unprotect()
-                        ));
+                        )));
                     }
 
                     let id = PartInstanceId::new_from(format!(
@@ -164,6 +241,9 @@ pub async fn setNextPart(
 
                                 planned_started_playback: None,
                                 planned_stopped_playback: None,
+                                reported_started_playback: None,
+                                reported_stopped_playback: None,
+                                duration: None,
 
                                 take: None,
                                 play_offset: None,
@@ -176,15 +256,14 @@ pub async fn setNextPart(
                             orphaned: None,
 
                             previous_part_end_state: None,
-                        })
-                        .map_err(|_| "Failed to create part instance".to_string())?;
+                        })?;
 
                     let rundown =
                         cache
                             .rundowns
                             .find_one_by_id(&part.rundown_id)
                             .ok_or_else(|| {
-                                format!("Could not find rundown {}", part.rundown_id.unprotect())
+                                JobError::NotFound(DocumentId::Rundown(part.rundown_id.clone()))
                             })?;
 
                     let possible_pieces =
@@ -202,8 +281,7 @@ pub async fn setNextPart(
                     for piece_instance in new_piece_instances {
                         cache
                             .piece_instances
-                            .insert(piece_instance)
-                            .map_err(|_| "Failed to insert piece instance".to_string())?;
+                            .insert(piece_instance)?;
                     }
 
                     id
@@ -220,13 +298,19 @@ pub async fn setNextPart(
             selected_part_instance_ids.insert(id.clone());
         }
 
-        // TODO
-        // 		// reset any previous instances of this part
-        // 		resetPartInstancesWithPieceInstances(context, cache, {
-        // 			_id: { $nin: selectedPartInstanceIds },
-        // 			rundownId: nextPart.rundownId,
-        // 			'part._id': nextPart._id,
-        // 		})
+        // reset any previous instances of this part
+        if let Some(new_instance) = cache.part_instances.find_one_by_id(&new_instance_id) {
+            resetPartInstancesWithPieceInstances(
+                context,
+                cache,
+                PartInstanceSelector::InstancesOfPart {
+                    rundown_id: new_instance.rundown_id,
+                    part_id: new_instance.part.id,
+                    exclude_ids: selected_part_instance_ids.into_iter().collect(),
+                },
+            )
+            .await?;
+        }
 
         let next_part_is_orphaned = match rawNextPart {
             SetNextPartTarget::Part(_) => false,
@@ -243,8 +327,7 @@ pub async fn setNextPart(
                 res.next_time_offset = nextTimeOffset;
 
                 Some(res)
-            })
-            .map_err(|_| "failed to set next part instance".to_string())?;
+            })?;
     } else {
         // Set to null
 
@@ -258,8 +341,7 @@ pub async fn setNextPart(
                 res.next_time_offset = None;
 
                 Some(res)
-            })
-            .map_err(|_| "failed to clear next part instance".to_string())?;
+            })?;
     }
 
     {
@@ -270,15 +352,13 @@ pub async fn setNextPart(
                 !p.is_taken
                     && Some(&p.id) != cache.playlist.doc().next_part_instance_id.as_ref()
                     && Some(&p.id) != cache.playlist.doc().current_part_instance_id.as_ref()
-            })
-            .map_err(|_| "failed to find part instances to cleanup".to_string())?;
+            })?;
         let instances_ids_to_remove_set =
             instances_ids_to_remove.into_iter().collect::<HashSet<_>>();
 
         cache
             .piece_instances
-            .remove_by_filter(|p| instances_ids_to_remove_set.contains(&p.part_instance_id))
-            .map_err(|_| "failed to cleanup piece instances".to_string())?;
+            .remove_by_filter(|p| instances_ids_to_remove_set.contains(&p.part_instance_id))?;
     }
 
     {
@@ -329,16 +409,126 @@ pub async fn setNextPart(
             }
 
             if !resetPartInstanceIds.is_empty() {
-                resetPartInstancesWithPieceInstances(context, cache, "AA".to_string());
-                // TODO
-                // {
-                // 	_id: { $in: Array.from(resetPartInstanceIds) },
-                // })
+                resetPartInstancesWithPieceInstances(
+                    context,
+                    cache,
+                    PartInstanceSelector::Ids(resetPartInstanceIds.into_iter().collect()),
+                )
+                .await?;
             }
         }
     }
 
-    cleanupOrphanedItems(context, cache).await;
+    cleanupOrphanedItems(context, cache).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{context::direct_collections::DirectCollections, playout::fixtures::*};
+
+    #[tokio::test]
+    async fn set_next_part_by_id() {
+        let mut data = create_playlist_data();
+        let (_current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let part2 = PartId::new_from("part2".to_string());
+        handle_set_next_part(
+            &context,
+            &playlist_id(),
+            &part2,
+            true,
+            Some(Duration::milliseconds(500)),
+        )
+        .await
+        .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert!(playlist.next_part_manual);
+        assert_eq!(playlist.next_time_offset, Some(Duration::milliseconds(500)));
+
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        let new_next = fetch_part_instance(&collections, &next_id).await;
+        assert_eq!(new_next.part.id, part2);
+
+        // The previous next PartInstance was never taken
+        assert_part_instance_removed(&collections, &next.id).await;
+    }
+
+    #[tokio::test]
+    async fn set_next_part_replays_current_part_when_set_manually() {
+        let mut data = create_playlist_data();
+        let (current, _next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_set_next_part(&context, &playlist_id(), &current.part.id, true, None)
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.current_part_instance_id, Some(current.id.clone()));
+
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        assert_ne!(next_id, current.id);
+        let next = fetch_part_instance(&collections, &next_id).await;
+        assert_eq!(next.part.id, current.part.id);
+    }
+
+    #[tokio::test]
+    async fn set_next_part_is_rejected_for_current_part() {
+        let mut data = create_playlist_data();
+        let (current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_set_next_part(&context, &playlist_id(), &current.part.id, false, None).await,
+            UserErrorMessage::PartAlreadyCurrent,
+        );
+        assert_eq!(
+            fetch_playlist(&collections).await.next_part_instance_id,
+            Some(next.id)
+        );
+    }
+
+    #[tokio::test]
+    async fn set_next_part_is_rejected_for_unplayable_part() {
+        let mut data = create_playlist_data();
+        data.parts[2].invalid = true;
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_set_next_part(
+                &context,
+                &playlist_id(),
+                &PartId::new_from("part2".to_string()),
+                true,
+                None,
+            )
+            .await,
+            UserErrorMessage::PartNotPlayable,
+        );
+        assert_user_error(
+            handle_set_next_part(
+                &context,
+                &playlist_id(),
+                &PartId::new_from("missing".to_string()),
+                true,
+                None,
+            )
+            .await,
+            UserErrorMessage::PartNotFound,
+        );
+    }
+}
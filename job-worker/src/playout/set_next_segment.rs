@@ -0,0 +1,226 @@
+use crate::{
+    cache::{
+        collection::DbCacheReadCollection,
+        object::{DbCacheReadObject, DbCacheWriteObject},
+    },
+    context::context::JobContext,
+    data_model::{
+        ids::{ProtectedId, RundownPlaylistId, SegmentId},
+        rundown_playlist::RundownHoldState,
+        segment::Segment,
+    },
+    error::{DocumentId, JobError, UserError, UserErrorMessage},
+};
+
+use super::{
+    cache::PlayoutCache,
+    lock::run_job_with_playout_cache,
+    select_next_part::SelectNextPartResult,
+    set_next_part::{setNextPart, SetNextPartTarget},
+    timeline::update_timeline,
+};
+
+/**
+ * Queue a Segment to be played once the current Segment has finished, or clear the queued Segment when None
+ */
+pub async fn handle_set_next_segment(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    next_segment_id: Option<SegmentId>,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, move |context, cache| {
+        Box::pin(async move {
+            let playlist = cache.playlist.doc();
+            if playlist.activation_id.is_none() {
+                return Err(UserError::create(UserErrorMessage::InactiveRundown).into());
+            }
+            if playlist.hold_state != RundownHoldState::NONE
+                && playlist.hold_state != RundownHoldState::COMPLETE
+            {
+                return Err(UserError::create(UserErrorMessage::DuringHold).into());
+            }
+
+            let next_segment = match next_segment_id {
+                Some(next_segment_id) => Some(
+                    cache
+                        .segments
+                        .find_one_by_id(&next_segment_id)
+                        .ok_or(JobError::NotFound(DocumentId::Segment(next_segment_id)))?,
+                ),
+                None => None,
+            };
+
+            set_next_segment(context, cache, next_segment.as_ref()).await?;
+
+            update_timeline(context, cache, None).await?;
+
+            Ok(())
+        })
+    })
+    .await
+}
+
+pub async fn set_next_segment(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    next_segment: Option<&Segment>,
+) -> Result<(), JobError> {
+    if let Some(next_segment) = next_segment {
+        let ordered_parts = cache.get_ordered_segments_and_parts().parts;
+        let (first_index, first_part) = ordered_parts
+            .iter()
+            .enumerate()
+            .find(|(_, p)| p.segment_id == next_segment.id && p.is_playable())
+            .ok_or_else(|| {
+                JobError::Internal(format!(
+                    "Segment \"{}\" contains no valid parts",
+                    next_segment.id.unprotect()
+                ))
+            })?;
+
+        let current_part_instance = cache.get_current_part_instance();
+        let next_part_instance = cache.get_next_part_instance();
+
+        match (current_part_instance, next_part_instance) {
+            (Some(current_part_instance), Some(next_part_instance))
+                if next_part_instance.segment_id == current_part_instance.segment_id =>
+            {
+                cache.playlist.update(|doc| {
+                    let mut res = doc.clone();
+                    res.next_segment_id = Some(next_segment.id.clone());
+                    Some(res)
+                })?;
+            }
+            _ => {
+                // Special: in this case, the user probably doesn't want to queue the segment, but rather to set the next part
+                setNextPart(
+                    context,
+                    cache,
+                    Some(SetNextPartTarget::Part(SelectNextPartResult {
+                        part_id: first_part.id.clone(),
+                        segment_id: first_part.segment_id.clone(),
+                        index: first_index,
+                        consumes_next_segment_id: false,
+                    })),
+                    true,
+                    None,
+                )
+                .await?;
+            }
+        }
+    } else {
+        cache.playlist.update(|doc| {
+            if doc.next_segment_id.is_some() {
+                let mut res = doc.clone();
+                res.next_segment_id = None;
+                Some(res)
+            } else {
+                None
+            }
+        })?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        context::direct_collections::DirectCollections, data_model::ids::PartId,
+        playout::fixtures::*,
+    };
+
+    #[tokio::test]
+    async fn set_next_segment_is_queued() {
+        let mut data = create_playlist_data();
+        let (_current, next) = add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let segment1 = SegmentId::new_from("segment1".to_string());
+        handle_set_next_segment(&context, &playlist_id(), Some(segment1.clone()))
+            .await
+            .unwrap();
+
+        // The rest of the current segment is still played first
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.next_segment_id, Some(segment1));
+        assert_eq!(playlist.next_part_instance_id, Some(next.id.clone()));
+
+        handle_set_next_segment(&context, &playlist_id(), None)
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.next_segment_id, None);
+        assert_eq!(playlist.next_part_instance_id, Some(next.id));
+    }
+
+    #[tokio::test]
+    async fn set_next_segment_sets_next_part_when_leaving_segment() {
+        let mut data = create_playlist_data();
+        add_selected_part_instances(&mut data, 1, 2);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        handle_set_next_segment(
+            &context,
+            &playlist_id(),
+            Some(SegmentId::new_from("segment0".to_string())),
+        )
+        .await
+        .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.next_segment_id, None);
+        assert!(playlist.next_part_manual);
+
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        let next = fetch_part_instance(&collections, &next_id).await;
+        assert_eq!(next.part.id, PartId::new_from("part0".to_string()));
+    }
+
+    #[tokio::test]
+    async fn set_next_segment_is_rejected_during_hold() {
+        let mut data = create_playlist_data();
+        add_selected_part_instances(&mut data, 0, 1);
+        data.rundown_playlists[0].hold_state = RundownHoldState::ACTIVE;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_set_next_segment(
+                &context,
+                &playlist_id(),
+                Some(SegmentId::new_from("segment1".to_string())),
+            )
+            .await,
+            UserErrorMessage::DuringHold,
+        );
+        assert_eq!(fetch_playlist(&collections).await.next_segment_id, None);
+    }
+
+    #[tokio::test]
+    async fn set_next_segment_is_rejected_for_unknown_segment() {
+        let mut data = create_playlist_data();
+        add_selected_part_instances(&mut data, 0, 1);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        let result = handle_set_next_segment(
+            &context,
+            &playlist_id(),
+            Some(SegmentId::new_from("missing".to_string())),
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(JobError::NotFound(DocumentId::Segment(_)))
+        ));
+    }
+}
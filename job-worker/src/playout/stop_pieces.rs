@@ -0,0 +1,250 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, Utc};
+use log::info;
+use serde_json::json;
+use sofie_rust_experiment::get_random_id;
+
+use crate::{
+    cache::{
+        collection::{DbCacheReadCollection, DbCacheWriteCollection},
+        object::DbCacheReadObject,
+    },
+    context::context::JobContext,
+    data_model::{
+        ids::{
+            PartInstanceId, PieceId, PieceInstanceId, PieceInstanceInfiniteId, ProtectedId,
+            RundownPlaylistId,
+        },
+        part_instance::PartInstance,
+        piece::{IBlueprintPieceType, Piece, PieceEnable, PieceEnableStart, PieceLifespan},
+        piece_instance::{rewrapPieceToInstance, PieceInstance, PieceInstanceInfinite},
+        show_style_base::SourceLayers,
+    },
+    error::{DocumentId, JobError, UserError, UserErrorMessage},
+};
+
+use super::{
+    cache::PlayoutCache, infinites::processAndPrunePieceInstanceTimings,
+    infinites2::syncPlayheadInfinitesForNextPartInstance, lock::run_job_with_playout_cache,
+    timeline::update_timeline,
+};
+
+/**
+ * Stop any pieces on the given source layers which are playing in the current PartInstance
+ */
+pub async fn handle_stop_pieces_on_source_layers(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    part_instance_id: &PartInstanceId,
+    source_layer_ids: Vec<String>,
+) -> Result<(), JobError> {
+    if source_layer_ids.is_empty() {
+        return Ok(());
+    }
+
+    let part_instance_id = part_instance_id.clone();
+    run_job_with_playout_cache(context, playlist_id, move |context, cache| {
+        Box::pin(async move {
+            let playlist = cache.playlist.doc();
+            if playlist.activation_id.is_none() {
+                return Err(UserError::create(UserErrorMessage::InactiveRundown).into());
+            }
+            if playlist.current_part_instance_id.as_ref() != Some(&part_instance_id) {
+                return Err(UserError::create(
+                    UserErrorMessage::ActionsNotAllowedOnNonCurrentPartInstance,
+                )
+                .into());
+            }
+
+            let part_instance = cache
+                .part_instances
+                .find_one_by_id(&part_instance_id)
+                .ok_or_else(|| {
+                    JobError::NotFound(DocumentId::PartInstance(part_instance_id.clone()))
+                })?;
+
+            let rundown = cache
+                .rundowns
+                .find_one_by_id(&part_instance.rundown_id)
+                .ok_or_else(|| {
+                    JobError::NotFound(DocumentId::Rundown(part_instance.rundown_id.clone()))
+                })?;
+
+            let show_style_base = context
+                .get_show_style_base(&rundown.show_style_base_id)
+                .await?
+                .ok_or_else(|| {
+                    JobError::NotFound(DocumentId::ShowStyleBase(
+                        rundown.show_style_base_id.clone(),
+                    ))
+                })?;
+
+            let source_layer_ids: HashSet<String> = source_layer_ids.into_iter().collect();
+            let stopped_ids = stop_pieces(
+                cache,
+                &show_style_base.source_layers,
+                &part_instance,
+                |piece_instance| source_layer_ids.contains(&piece_instance.piece.source_layer_id),
+                None,
+            )?;
+
+            if !stopped_ids.is_empty() {
+                syncPlayheadInfinitesForNextPartInstance(context, cache).await?;
+
+                update_timeline(context, cache, None).await?;
+            }
+
+            Ok(())
+        })
+    })
+    .await
+}
+
+/**
+ * Stop the PieceInstances in the current PartInstance which match the filter, at now plus the optional offset.
+ * Pieces are cropped by setting their user_duration, except for infinites which continue beyond this part,
+ * which are stopped by inserting a virtual piece on the same layer.
+ * Returns the ids of the PieceInstances which were stopped
+ */
+pub fn stop_pieces<F>(
+    cache: &mut PlayoutCache,
+    source_layers: &SourceLayers,
+    current_part_instance: &PartInstance,
+    filter: F,
+    time_offset: Option<Duration>,
+) -> Result<Vec<PieceInstanceId>, JobError>
+where
+    F: Fn(&PieceInstance) -> bool,
+{
+    let last_started_playback = current_part_instance
+        .timings
+        .planned_started_playback
+        .ok_or_else(|| {
+            JobError::Internal(
+                "Cannot stop pieceInstances when partInstance hasnt started playback".to_string(),
+            )
+        })?;
+
+    let now = Utc::now();
+    let stop_at = now + time_offset.unwrap_or_else(Duration::zero);
+    let relative_stop_at = stop_at - last_started_playback;
+
+    let piece_instances = cache
+        .piece_instances
+        .find_some(|p| p.part_instance_id == current_part_instance.id);
+    let resolved_pieces = processAndPrunePieceInstanceTimings(
+        source_layers,
+        &piece_instances,
+        now - last_started_playback,
+        false,
+        false,
+    );
+
+    let mut stopped_instances = Vec::new();
+
+    for resolved_piece in resolved_pieces {
+        let piece_instance = resolved_piece.piece.as_ref();
+
+        let has_started = match &piece_instance.piece.enable.start {
+            PieceEnableStart::Offset(start) => *start <= relative_stop_at,
+            PieceEnableStart::Now => true,
+        };
+
+        if piece_instance.user_duration.is_some()
+            || piece_instance.piece.virtual_
+            || piece_instance.planned_stopped_playback.is_some()
+            || !has_started
+            || !filter(piece_instance)
+        {
+            continue;
+        }
+
+        match piece_instance.piece.lifespan {
+            PieceLifespan::WithinPart
+            | PieceLifespan::OutOnSegmentChange
+            | PieceLifespan::OutOnRundownChange => {
+                info!(
+                    "Cropping PieceInstance \"{}\" to {}",
+                    piece_instance.id.unprotect(),
+                    stop_at
+                );
+
+                cache
+                    .piece_instances
+                    .update_one(&piece_instance.id, |doc| {
+                        let mut res = doc.clone();
+                        res.user_duration = Some(
+                            json!({ "endRelativeToPart": relative_stop_at.num_milliseconds() }),
+                        );
+                        Some(res)
+                    })?;
+            }
+            PieceLifespan::OutOnSegmentEnd
+            | PieceLifespan::OutOnRundownEnd
+            | PieceLifespan::OutOnShowStyleEnd => {
+                info!(
+                    "Cropping PieceInstance \"{}\" to {} with a virtual",
+                    piece_instance.id.unprotect(),
+                    stop_at
+                );
+
+                let piece_id = PieceId::new_from(get_random_id());
+                let mut virtual_instance = rewrapPieceToInstance(
+                    Piece {
+                        id: piece_id.clone(),
+                        start_part_id: current_part_instance.part.id.clone(),
+                        start_segment_id: current_part_instance.segment_id.clone(),
+                        start_rundown_id: current_part_instance.rundown_id.clone(),
+                        external_id: "-".to_string(),
+                        name: "".to_string(),
+                        meta_data: None,
+                        enable: PieceEnable {
+                            start: PieceEnableStart::Offset(relative_stop_at),
+                            duration: None,
+                        },
+                        lifespan: piece_instance.piece.lifespan,
+                        preroll_duration: Duration::zero(),
+                        postroll_duration: Duration::zero(),
+                        source_layer_id: piece_instance.piece.source_layer_id.clone(),
+                        output_layer_id: piece_instance.piece.output_layer_id.clone(),
+                        virtual_: true,
+                        piece_type: IBlueprintPieceType::Normal,
+                        extend_on_hold: false,
+                        invalid: false,
+                        content: json!({}),
+                        status: 0,
+                        continues_ref_id: None,
+                        timeline_objects_string: "[]".to_string(),
+                        to_be_queued: false,
+                        expected_playout_items: None,
+                        expected_packages: None,
+                        allow_direct_play: None,
+                        tags: None,
+                        has_side_effects: false,
+                        not_in_vision: false,
+                    },
+                    current_part_instance.playlist_activation_id.clone(),
+                    current_part_instance.rundown_id.clone(),
+                    current_part_instance.id.clone(),
+                    false,
+                );
+                virtual_instance.dynamically_inserted = Some(now);
+                virtual_instance.infinite = Some(PieceInstanceInfinite {
+                    infinite_instance_id: PieceInstanceInfiniteId::new_from(get_random_id()),
+                    infinite_instance_index: 0,
+                    infinite_piece_id: piece_id,
+                    from_previous_part: false,
+                    from_previous_playhead: false,
+                    from_hold: false,
+                });
+
+                cache.piece_instances.insert(virtual_instance)?;
+            }
+        }
+
+        stopped_instances.push(piece_instance.id.clone());
+    }
+
+    Ok(stopped_instances)
+}
@@ -1,14 +1,18 @@
 use std::{collections::HashSet, ops::Add};
 
 use chrono::{DateTime, Duration, Utc};
+use log::{error, info};
 use sofie_rust_experiment::get_random_id;
 
 use super::{
     cache::PlayoutCache,
     infinites::processAndPrunePieceInstanceTimings,
     lib::is_too_close_to_autonext,
+    lock::run_job_with_playout_cache,
     select_next_part::select_next_part,
     set_next_part::{setNextPart, SetNextPartTarget},
+    stop_pieces::stop_pieces,
+    timeline::update_timeline,
     timings::calculatePartTimings,
 };
 use crate::{
@@ -20,7 +24,7 @@ use crate::{
     data_model::{
         ids::{
             PartInstanceId, PieceInstanceId, PieceInstanceInfiniteId, ProtectedId,
-            RundownPlaylistActivationId,
+            RundownPlaylistActivationId, RundownPlaylistId,
         },
         part_instance::PartInstance,
         piece::PieceEnableStart,
@@ -28,23 +32,54 @@ use crate::{
         rundown::Rundown,
         rundown_playlist::{progress_hold_state, RundownHoldState},
     },
+    error::{DocumentId, JobError, UserError, UserErrorMessage},
+    events::EventsJob,
 };
 
+/**
+ * Take the next part of the playlist.
+ * The take is refused if the current PartInstance is no longer the one the take was requested from
+ */
+pub async fn handle_take_next_part(
+    context: &JobContext,
+    playlist_id: &RundownPlaylistId,
+    from_part_instance_id: Option<PartInstanceId>,
+) -> Result<(), JobError> {
+    run_job_with_playout_cache(context, playlist_id, move |context, cache| {
+        Box::pin(async move {
+            let now = Utc::now();
+
+            let playlist = cache.playlist.doc();
+            if playlist.activation_id.is_none() {
+                return Err(UserError::create(UserErrorMessage::InactiveRundown).into());
+            }
+            if playlist.next_part_instance_id.is_none()
+                && playlist.hold_state != RundownHoldState::ACTIVE
+            {
+                return Err(UserError::create(UserErrorMessage::TakeNoNextPart).into());
+            }
+            if playlist.current_part_instance_id != from_part_instance_id {
+                return Err(UserError::create(UserErrorMessage::TakeFromIncorrectPart).into());
+            }
+
+            take_next_part_inner(context, cache, now).await
+        })
+    })
+    .await
+}
+
 pub async fn take_next_part_inner(
-    context: JobContext,
+    context: &JobContext,
     cache: &mut PlayoutCache,
     now: DateTime<Utc>,
-) -> Result<(), String> {
+) -> Result<(), JobError> {
     let playlist_activation_id = {
         let playlist = cache.playlist.doc();
 
         if let Some(activation_id) = &playlist.activation_id {
             Ok(activation_id.clone())
         } else {
-            Err(format!(
-                "Rundown Playlist {} is not active!",
-                playlist.id.unprotect()
-            ))
+            Err(UserError::create(UserErrorMessage::InactiveRundown))
         }
     }?;
 
@@ -64,13 +99,10 @@ pub async fn take_next_part_inner(
                 .rundowns
                 .find_one_by_id(&part_instance.rundown_id)
                 .ok_or_else(|| {
-                    format!(
-                        "Rundown \"{}\" could not be found!",
-                        &part_instance.rundown_id.unprotect()
-                    )
+                    JobError::NotFound(DocumentId::Rundown(part_instance.rundown_id.clone()))
                 })
         } else {
-            Err("No PartInstance could be found!".to_string())
+            Err(UserError::create(UserErrorMessage::TakeNoNextPart).into())
         }
     }?;
 
@@ -83,12 +115,12 @@ pub async fn take_next_part_inner(
         if let Some(block_take_until) = current_part_instance.block_take_until {
             let remaining_time = block_take_until.signed_duration_since(now);
             if remaining_time > Duration::zero() {
-                println!(
+                info!(
                     "Take is blocked until {}. Which is in: {}ms",
                     block_take_until,
                     remaining_time.num_milliseconds()
                 );
-                return Err("TakeBlockedDuration".to_string());
+                return Err(UserError::create(UserErrorMessage::TakeBlockedDuration).into());
             }
         }
 
@@ -99,14 +131,16 @@ pub async fn take_next_part_inner(
             if let Some(start) = current_part_instance.timings.planned_started_playback {
                 if let Some(in_transition) = &current_part_instance.part.in_transition {
                     if now < start.add(in_transition.block_take_duration) {
-                        return Err("TakeDuringTransition".to_string());
+                        return Err(
+                            UserError::create(UserErrorMessage::TakeDuringTransition).into()
+                        );
                     }
                 }
             }
         }
 
         if is_too_close_to_autonext(current_part_instance, true) {
-            return Err("TakeCloseToAutonext".to_string());
+            return Err(UserError::create(UserErrorMessage::TakeCloseToAutonext).into());
         }
     }
 
@@ -117,25 +151,30 @@ pub async fn take_next_part_inner(
             Some(res)
         });
         if let Err(_err) = err {
-            println!("Failed to update PartInstance")
+            error!("Failed to update PartInstance")
         }
 
         // If hold is active, then this take is to clear it
     } else if cache.playlist.doc().hold_state == RundownHoldState::ACTIVE {
-        let show_style = p_show_style
-            .await?
-            .ok_or("ShowStyleCompound not found".to_string())?;
+        let show_style = p_show_style.await?.ok_or_else(|| {
+            JobError::NotFound(DocumentId::ShowStyleBase(
+                current_rundown.show_style_base_id.clone(),
+            ))
+        })?;
 
-        complete_hold(cache, &show_style).await?;
+        complete_hold(context, cache, &show_style).await?;
 
         return Ok(());
     }
 
-    let take_part_instance = next_part_instance.ok_or_else(|| "takePart not found!".to_string())?;
+    let take_part_instance =
+        next_part_instance.ok_or_else(|| UserError::create(UserErrorMessage::TakeNoNextPart))?;
     let take_rundown = cache
         .rundowns
         .find_one_by_id(&take_part_instance.rundown_id)
-        .ok_or_else(|| "takeRundown: takeRundown not found!".to_string())?;
+        .ok_or_else(|| {
+            JobError::NotFound(DocumentId::Rundown(take_part_instance.rundown_id.clone()))
+        })?;
 
     // Autonext may have setup the plannedStartedPlayback. Clear it so that a new value is generated
     cache
@@ -149,8 +188,7 @@ pub async fn take_next_part_inner(
             } else {
                 None
             }
-        })
-        .map_err(|_| "Failed to clear plannedStartedPlayback".to_string())?;
+        })?;
 
     // it is only a first take if the Playlist has no startedPlayback and the taken PartInstance is not untimed
     let _is_first_time =
@@ -166,9 +204,11 @@ pub async fn take_next_part_inner(
         true,
     );
 
-    let show_style = p_show_style
-        .await?
-        .ok_or("ShowStyleCompound not found".to_string())?;
+    let show_style = p_show_style.await?.ok_or_else(|| {
+        JobError::NotFound(DocumentId::ShowStyleBase(
+            current_rundown.show_style_base_id.clone(),
+        ))
+    })?;
     // 	const blueprint = await context.getShowStyleBlueprint(showStyle._id)
     // 	if (blueprint.blueprint.onPreTake) {
     // 		const span = context.startSpan('blueprint.onPreTake')
@@ -191,7 +231,7 @@ pub async fn take_next_part_inner(
     // 	}
 
     updatePartInstanceOnTake(
-        &context,
+        context,
         cache,
         &show_style,
         // blueprint,
@@ -212,8 +252,7 @@ pub async fn take_next_part_inner(
             res.hold_state = progress_hold_state(&doc.hold_state);
 
             Some(res)
-        })
-        .map_err(|_| "Failed to update selected instance ids".to_string())?;
+        })?;
 
     cache
         .part_instances
@@ -226,14 +265,13 @@ pub async fn take_next_part_inner(
             res.timings.play_offset = time_offset;
 
             Some(res)
-        })
-        .map_err(|_| "Failed to update taken partinstance".to_string())?;
+        })?;
 
     reset_previous_segment(cache)?;
 
     // Once everything is synced, we can choose the next part
     setNextPart(
-        &context,
+        context,
         cache,
         next_part.map(SetNextPartTarget::Part),
         false,
@@ -246,7 +284,9 @@ pub async fn take_next_part_inner(
         && cache.playlist.doc().hold_state == RundownHoldState::ACTIVE
     {
         let hold_from_part_instance =
-            &current_part_instance.ok_or_else(|| "previousPart not found!".to_string())?;
+            &current_part_instance.ok_or_else(|| {
+                JobError::Internal("previousPart not found!".to_string())
+            })?;
 
         start_hold(
             cache,
@@ -256,7 +296,7 @@ pub async fn take_next_part_inner(
         )?;
     }
 
-    after_take(&context, cache, &take_part_instance, time_offset).await;
+    after_take(context, cache, &take_part_instance, time_offset).await?;
 
     // Last: TODO
     // 	const takeDoneTime = getCurrentTime()
@@ -269,7 +309,7 @@ pub async fn take_next_part_inner(
 pub fn clear_next_segment_id(
     cache: &mut PlayoutCache,
     take_or_current_part_instance: &PartInstance,
-) -> Result<(), String> {
+) -> Result<(), JobError> {
     if take_or_current_part_instance.consumes_next_segment_id
         && cache.playlist.doc().next_segment_id.as_ref()
             == Some(&take_or_current_part_instance.segment_id)
@@ -283,14 +323,13 @@ pub fn clear_next_segment_id(
                 res.next_segment_id = None;
 
                 Some(res)
-            })
-            .map_err(|_| "Failed to clear nextSegmentId".to_string())?;
+            })?;
     }
 
     Ok(())
 }
 
-pub fn reset_previous_segment(cache: &mut PlayoutCache) -> Result<(), String> {
+pub fn reset_previous_segment(cache: &mut PlayoutCache) -> Result<(), JobError> {
     let current_part_instance = cache.get_current_part_instance();
     let previous_part_instance = cache.get_previous_part_instance();
 
@@ -316,8 +355,7 @@ pub fn reset_previous_segment(cache: &mut PlayoutCache) -> Result<(), String> {
                     } else {
                         None
                     }
-                })
-                .map_err(|_| "Failed to reset PartInstances")?;
+                })?;
 
             let updated_ids_set: HashSet<PartInstanceId> =
                 HashSet::from_iter(updated_ids.into_iter());
@@ -334,8 +372,7 @@ pub fn reset_previous_segment(cache: &mut PlayoutCache) -> Result<(), String> {
                     } else {
                         None
                     }
-                })
-                .map_err(|_| "Failed to reset PieceInstances")?;
+                })?;
         }
     }
 
@@ -429,7 +466,7 @@ pub fn updatePartInstanceOnTake(
     _take_rundown: &Rundown,
     take_part_instance: &PartInstance,
     current_part_instance: Option<&PartInstance>,
-) -> Result<(), String> {
+) -> Result<(), JobError> {
     let _playlist = cache.playlist.doc();
 
     // 	// TODO - the state could change after this sampling point. This should be handled properly
@@ -523,41 +560,44 @@ pub fn updatePartInstanceOnTake(
             // 		}
 
             Some(res)
-        })
-        .map_err(|_| "Failed to update taken part instance".to_string())?;
+        })?;
 
     Ok(())
 }
 
 pub async fn after_take(
-    _context: &JobContext,
-    _cache: &mut PlayoutCache,
-    _take_part_instance: &PartInstance,
-    _time_offset_into_part: Option<Duration>,
-) {
-    // 	const span = context.startSpan('afterTake')
-    // 	// This function should be called at the end of a "take" event (when the Parts have been updated)
-    // 	// or after a new part has started playing
-
-    // 	await updateTimeline(context, cache, timeOffsetIntoPart || undefined)
-
-    // 	cache.deferAfterSave(async () => {
-    // 		// This is low-prio, defer so that it's executed well after publications has been updated,
-    // 		// so that the playout gateway has haf the chance to learn about the timeline changes
-    // 		if (takePartInstance.part.shouldNotifyCurrentPlayingPart) {
-    // 			context
-    // 				.queueEventJob(EventsJobs.NotifyCurrentlyPlayingPart, {
-    // 					rundownId: takePartInstance.rundownId,
-    // 					isRehearsal: !!cache.Playlist.doc.rehearsal,
-    // 					partExternalId: takePartInstance.part.externalId,
-    // 				})
-    // 				.catch((e) => {
-    // 					logger.warn(`Failed to queue NotifyCurrentlyPlayingPart job: ${e}`)
-    // 				})
-    // 		}
-    // 	})
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    take_part_instance: &PartInstance,
+    time_offset_into_part: Option<Duration>,
+) -> Result<(), JobError> {
+    // This function should be called at the end of a "take" event (when the Parts have been updated)
+    // or after a new part has started playing
+
+    update_timeline(context, cache, time_offset_into_part).await?;
+
+    if take_part_instance.part.should_notify_current_playing_part {
+        let events_queue = context.events_queue().clone();
+        let job = EventsJob::NotifyCurrentlyPlayingPart {
+            rundown_id: take_part_instance.rundown_id.clone(),
+            is_rehearsal: cache.playlist.doc().rehearsal,
+            part_external_id: Some(take_part_instance.part.external_id.clone()),
+        };
+
+        cache.defer_after_save(move |_collections| {
+            Box::pin(async move {
+                // This is low-prio, defer so that it's executed well after publications has been updated,
+                // so that the playout gateway has had the chance to learn about the timeline changes
+                if let Err(err) = events_queue.queue(job) {
+                    error!("Failed to queue NotifyCurrentlyPlayingPart job: {}", err);
+                }
+
+                Ok(())
+            })
+        });
+    }
 
-    // 	if (span) span.end()
+    Ok(())
 }
 
 /**
@@ -568,7 +608,7 @@ fn start_hold(
     activation_id: &RundownPlaylistActivationId,
     hold_from_part_instance: &PartInstance,
     hold_to_part_instance: &PartInstance,
-) -> Result<(), String> {
+) -> Result<(), JobError> {
     let items_to_copy = cache.piece_instances.find_some(|doc| {
         doc.part_instance_id == hold_from_part_instance.id && doc.piece.extend_on_hold
     });
@@ -593,8 +633,7 @@ fn start_hold(
                     });
 
                     Some(res)
-                })
-                .map_err(|_| "Failed to make held piece infinite".to_string())?;
+                })?;
 
             // make the extension
             let mut new_instance_piece = instance.piece.clone();
@@ -637,17 +676,17 @@ fn start_hold(
             // This gets deleted once the nextpart is activated, so it doesnt linger for long
             cache
                 .piece_instances
-                .replace_one(new_instance)
-                .map_err(|_| "Failed to insert held piece".to_string())?;
+                .replace_one(new_instance)?;
         }
     }
     Ok(())
 }
 
 async fn complete_hold(
+    context: &JobContext,
     cache: &mut PlayoutCache,
-    _show_style: &ShowStyleBase,
-) -> Result<(), String> {
+    show_style: &ShowStyleBase,
+) -> Result<(), JobError> {
     cache
         .playlist
         .update(|doc| {
@@ -656,28 +695,146 @@ async fn complete_hold(
             res.hold_state = RundownHoldState::COMPLETE;
 
             Some(res)
-        })
-        .map_err(|_| "Failed to mark hold completed".to_string())?;
+        })?;
 
     if cache.playlist.doc().current_part_instance_id.is_some() {
-        let _current_part_instance = cache
+        let current_part_instance = cache
             .get_current_part_instance()
-            .ok_or_else(|| "currentPart not found!".to_string())?;
+            .ok_or_else(|| JobError::Internal("currentPart not found!".to_string()))?;
 
-        todo!();
         // Clear the current extension line
-        // innerStopPieces(
-        // 	context,
-        // 	cache,
-        // 	showStyleCompound.sourceLayers,
-        // 	currentPartInstance,
-        // 	(p) => !!p.infinite?.fromHold,
-        // 	undefined
-        // )
+        stop_pieces(
+            cache,
+            &show_style.source_layers,
+            &current_part_instance,
+            |p| p.infinite.as_ref().map_or(false, |inf| inf.from_hold),
+            None,
+        )?;
     }
 
-    todo!();
-    // await updateTimeline(context, cache)
+    update_timeline(context, cache, None).await?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use mongodb::bson::doc;
+
+    use super::*;
+    use crate::{
+        context::direct_collections::DirectCollections,
+        data_model::{ids::PartId, timeline::TimelineComplete},
+        playout::fixtures::*,
+    };
+
+    #[tokio::test]
+    async fn take_sequence_is_saved() {
+        let mut data = create_playlist_data();
+        let part0_instance = create_part_instance(&data.parts[0]);
+        data.rundown_playlists[0].next_part_instance_id = Some(part0_instance.id.clone());
+        data.part_instances.push(part0_instance.clone());
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        // The first take, from nothing playing
+        handle_take_next_part(&context, &playlist_id(), None)
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.previous_part_instance_id, None);
+        assert_eq!(
+            playlist.current_part_instance_id,
+            Some(part0_instance.id.clone())
+        );
+
+        let taken = fetch_part_instance(&collections, &part0_instance.id).await;
+        assert!(taken.is_taken);
+        assert!(taken.timings.take.is_some());
+
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        let next = fetch_part_instance(&collections, &next_id).await;
+        assert_eq!(next.part.id, PartId::new_from("part1".to_string()));
+        assert!(!next.reset);
+        assert!(!next.is_taken);
+
+        // The pieces of the next part are ready to play
+        let piece_instances = collections
+            .piece_instances
+            .find_fetch(doc! { "partInstanceId": next_id.unprotect() }, None)
+            .await
+            .unwrap();
+        assert_eq!(piece_instances.len(), 1);
+        assert_eq!(piece_instances[0].piece.id.unprotect(), "part1_piece");
+
+        // The timeline has been written for the studio
+        let timeline: Option<TimelineComplete> = collections
+            .timelines
+            .find_one(doc! { "_id": "studio0" }, None)
+            .await
+            .unwrap();
+        assert!(timeline.is_some());
+
+        // The second take moves on into the next part, and selects the following segment
+        handle_take_next_part(&context, &playlist_id(), Some(part0_instance.id.clone()))
+            .await
+            .unwrap();
+
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(
+            playlist.previous_part_instance_id,
+            Some(part0_instance.id.clone())
+        );
+        assert_eq!(playlist.current_part_instance_id, Some(next_id.clone()));
+        assert!(fetch_part_instance(&collections, &next_id).await.is_taken);
+
+        let next_id = playlist.next_part_instance_id.expect("no next part");
+        let next = fetch_part_instance(&collections, &next_id).await;
+        assert_eq!(next.part.id, PartId::new_from("part2".to_string()));
+    }
+
+    #[tokio::test]
+    async fn take_is_rejected_when_playing_part_has_changed() {
+        let mut data = create_playlist_data();
+        let part0_instance = create_part_instance(&data.parts[0]);
+        data.rundown_playlists[0].next_part_instance_id = Some(part0_instance.id.clone());
+        data.part_instances.push(part0_instance.clone());
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_take_next_part(&context, &playlist_id(), Some(part0_instance.id.clone())).await,
+            UserErrorMessage::TakeFromIncorrectPart,
+        );
+
+        // Nothing was changed
+        let playlist = fetch_playlist(&collections).await;
+        assert_eq!(playlist.current_part_instance_id, None);
+        assert_eq!(
+            playlist.next_part_instance_id,
+            Some(part0_instance.id.clone())
+        );
+        assert!(
+            !fetch_part_instance(&collections, &part0_instance.id)
+                .await
+                .is_taken
+        );
+    }
+
+    #[tokio::test]
+    async fn take_is_rejected_for_inactive_playlist() {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        let (context, _events_receiver) = create_context(&collections);
+
+        assert_user_error(
+            handle_take_next_part(&context, &playlist_id(), None).await,
+            UserErrorMessage::InactiveRundown,
+        );
+    }
+}
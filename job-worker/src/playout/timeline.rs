@@ -0,0 +1,1388 @@
+use std::collections::HashSet;
+
+use chrono::{Duration, Utc};
+use log::{error, warn};
+use serde_json::json;
+use sofie_rust_experiment::get_random_id;
+
+use crate::{
+    cache::{
+        collection::{DbCacheReadCollection, DbCacheWriteCollection},
+        object::DbCacheReadObject,
+    },
+    context::context::JobContext,
+    data_model::{
+        extra::get_piece_control_object_id,
+        ids::{PieceInstanceInfiniteId, ProtectedId, StudioId},
+        part_instance::{PartCalculatedTimings, PartInstance},
+        piece::{IBlueprintPieceType, Piece, PieceEnableStart, PieceLifespan},
+        piece_instance::PieceInstance,
+        rundown_playlist::{RundownHoldState, RundownPlaylist},
+        timeline::{
+            TimelineComplete, TimelineEnable, TimelineEnableValue, TimelineObjGenerated,
+            TimelineObjType,
+        },
+    },
+    error::{DocumentId, JobError},
+};
+
+use super::{
+    active_playlist::find_other_active_playlists,
+    cache::PlayoutCache,
+    infinites::{processAndPrunePieceInstanceTimings, PieceInstanceWithTimings, ResolvedEndCap},
+    lookahead::get_lookahead_objects,
+    timings::calculatePartTimings,
+};
+
+/** The deviceType of objects which don't control a device (TSR.DeviceType.ABSTRACT) */
+const DEVICE_TYPE_ABSTRACT: i64 = 0;
+
+/**
+ * The timeline related information of one of the selected PartInstances
+ */
+struct SelectedPartInstanceTimelineInfo {
+    part_instance: PartInstance,
+    piece_instances: Vec<PieceInstanceWithTimings>,
+    calculated_timings: PartCalculatedTimings,
+}
+
+struct SelectedPartInstancesTimelineInfo {
+    previous: Option<SelectedPartInstanceTimelineInfo>,
+    current: Option<SelectedPartInstanceTimelineInfo>,
+    next: Option<SelectedPartInstanceTimelineInfo>,
+}
+
+/**
+ * Regenerate the timeline for the playlist of the cache.
+ * The timeline is written to the database for the studio when the cache is saved
+ */
+pub async fn update_timeline(
+    context: &JobContext,
+    cache: &mut PlayoutCache,
+    time_offset_into_part: Option<Duration>,
+) -> Result<(), JobError> {
+    let playlist = cache.playlist.doc().clone();
+
+    let timeline_objs = if playlist.activation_id.is_some() {
+        set_current_part_instance_start(cache, &playlist, time_offset_into_part)?;
+
+        let info = get_selected_part_instances_timeline_info(context, cache).await?;
+        let mut timeline_objs = build_timeline_objs_for_rundown(&playlist, &info);
+        timeline_objs.extend(get_lookahead_objects(context, cache).await?);
+        timeline_objs
+    } else {
+        // The timeline belongs to the studio, so it must be left alone if another playlist is playing there
+        if !find_other_active_playlists(context, &playlist)
+            .await?
+            .is_empty()
+        {
+            return Ok(());
+        }
+
+        // Nothing should be playing
+        Vec::new()
+    };
+
+    let timeline_blob = serde_json::to_string(&timeline_objs)
+        .map_err(|err| JobError::Internal(format!("Failed to serialize timeline: {}", err)))?;
+
+    cache.set_timeline(TimelineComplete {
+        id: StudioId::new_from(playlist.studio_id.clone()),
+        timeline_hash: get_random_id(),
+        generated: Utc::now(),
+        timeline_blob,
+    });
+
+    Ok(())
+}
+
+/**
+ * The current PartInstance needs a fixed start time, so that regenerating the timeline doesn't restart it.
+ * This gets replaced with the actual time once the gateway reports the PartInstance as having started playback
+ */
+fn set_current_part_instance_start(
+    cache: &mut PlayoutCache,
+    playlist: &RundownPlaylist,
+    time_offset_into_part: Option<Duration>,
+) -> Result<(), JobError> {
+    if let Some(current_part_instance_id) = &playlist.current_part_instance_id {
+        let start = Utc::now() - time_offset_into_part.unwrap_or_else(Duration::zero);
+
+        cache
+            .part_instances
+            .update_one(current_part_instance_id, |doc| {
+                if doc.timings.planned_started_playback.is_none() {
+                    let mut res = doc.clone();
+                    res.timings.planned_started_playback = Some(start);
+                    Some(res)
+                } else {
+                    None
+                }
+            })?;
+    }
+
+    Ok(())
+}
+
+async fn get_selected_part_instances_timeline_info(
+    context: &JobContext,
+    cache: &PlayoutCache,
+) -> Result<SelectedPartInstancesTimelineInfo, JobError> {
+    let now = Utc::now();
+
+    let get_now_in_part = |part_instance: &PartInstance| {
+        part_instance
+            .timings
+            .planned_started_playback
+            .map_or_else(Duration::zero, |start| now - start)
+    };
+    let get_stored_timings = |part_instance: &PartInstance| {
+        part_instance
+            .part_playout_timings
+            .clone()
+            .unwrap_or(PartCalculatedTimings {
+                in_transition_start: None,
+                to_part_delay: Duration::zero(),
+                to_part_postroll: Duration::zero(),
+                from_part_remaining: Duration::zero(),
+                from_part_postroll: Duration::zero(),
+            })
+    };
+
+    let previous = match cache.get_previous_part_instance() {
+        Some(part_instance) => {
+            let now_in_part = get_now_in_part(&part_instance);
+            let calculated_timings = get_stored_timings(&part_instance);
+            Some(
+                get_part_instance_timeline_info(
+                    context,
+                    cache,
+                    part_instance,
+                    now_in_part,
+                    calculated_timings,
+                )
+                .await?,
+            )
+        }
+        None => None,
+    };
+
+    let current = match cache.get_current_part_instance() {
+        Some(part_instance) => {
+            let now_in_part = get_now_in_part(&part_instance);
+            let calculated_timings = get_stored_timings(&part_instance);
+            Some(
+                get_part_instance_timeline_info(
+                    context,
+                    cache,
+                    part_instance,
+                    now_in_part,
+                    calculated_timings,
+                )
+                .await?,
+            )
+        }
+        None => None,
+    };
+
+    let next = match cache.get_next_part_instance() {
+        Some(part_instance) => {
+            // The next part hasn't been taken, so its timings depend on what is currently playing
+            let from_pieces = current.as_ref().map(|current| {
+                current
+                    .piece_instances
+                    .iter()
+                    .map(|p| p.piece.piece.clone())
+                    .collect::<Vec<_>>()
+            });
+            let to_pieces = cache
+                .piece_instances
+                .find_some(|p| {
+                    p.part_instance_id == part_instance.id
+                        && p.infinite
+                            .as_ref()
+                            .map_or(true, |inf| inf.infinite_instance_index == 0)
+                })
+                .into_iter()
+                .map(|p| p.piece)
+                .collect::<Vec<_>>();
+
+            let calculated_timings = calculatePartTimings(
+                cache.playlist.doc().hold_state,
+                current.as_ref().map(|current| &current.part_instance.part),
+                from_pieces.as_deref(),
+                &part_instance.part,
+                &to_pieces,
+            );
+
+            Some(
+                get_part_instance_timeline_info(
+                    context,
+                    cache,
+                    part_instance,
+                    Duration::zero(),
+                    calculated_timings,
+                )
+                .await?,
+            )
+        }
+        None => None,
+    };
+
+    Ok(SelectedPartInstancesTimelineInfo {
+        previous,
+        current,
+        next,
+    })
+}
+
+async fn get_part_instance_timeline_info(
+    context: &JobContext,
+    cache: &PlayoutCache,
+    part_instance: PartInstance,
+    now_in_part: Duration,
+    calculated_timings: PartCalculatedTimings,
+) -> Result<SelectedPartInstanceTimelineInfo, JobError> {
+    let rundown = cache
+        .rundowns
+        .find_one_by_id(&part_instance.rundown_id)
+        .ok_or_else(|| JobError::NotFound(DocumentId::Rundown(part_instance.rundown_id.clone())))?;
+
+    let show_style_base = context
+        .get_show_style_base(&rundown.show_style_base_id)
+        .await?
+        .ok_or_else(|| {
+            JobError::NotFound(DocumentId::ShowStyleBase(
+                rundown.show_style_base_id.clone(),
+            ))
+        })?;
+
+    let raw_piece_instances = cache
+        .piece_instances
+        .find_some(|p| p.part_instance_id == part_instance.id);
+    let piece_instances = processAndPrunePieceInstanceTimings(
+        &show_style_base.source_layers,
+        &raw_piece_instances,
+        now_in_part,
+        false,
+        false,
+    );
+
+    Ok(SelectedPartInstanceTimelineInfo {
+        part_instance,
+        piece_instances,
+        calculated_timings,
+    })
+}
+
+fn build_timeline_objs_for_rundown(
+    playlist: &RundownPlaylist,
+    info: &SelectedPartInstancesTimelineInfo,
+) -> Vec<TimelineObjGenerated> {
+    let mut status_obj = create_timeline_obj(
+        format!("{}_status", playlist.id.unprotect()),
+        "rundown_status".to_string(),
+        TimelineEnable {
+            while_: Some(TimelineEnableValue::Time(1)),
+            ..Default::default()
+        },
+        json!({ "deviceType": DEVICE_TYPE_ABSTRACT }),
+    );
+    status_obj.classes.push(if playlist.rehearsal {
+        "rundown_rehersal".to_string()
+    } else {
+        "rundown_active".to_string()
+    });
+    if playlist.next_part_instance_id.is_none() {
+        status_obj.classes.push("last_part".to_string());
+    }
+
+    let mut timeline_objs = vec![status_obj];
+
+    let current = match &info.current {
+        Some(current) => current,
+        None => {
+            if playlist.next_part_instance_id.is_some() {
+                warn!("Attempted to update timeline, but there is no current part!");
+            }
+            return timeline_objs;
+        }
+    };
+    let is_in_hold = playlist.hold_state == RundownHoldState::ACTIVE;
+
+    let current_part_start = match current.part_instance.timings.planned_started_playback {
+        Some(start) => TimelineEnableValue::from_date(start),
+        None => TimelineEnableValue::Expression("now".to_string()),
+    };
+    // If there is a valid autonext out of the current part, then it has a duration
+    let current_part_duration = match (&info.next, current.part_instance.part.expected_duration) {
+        (Some(_), Some(expected_duration)) if current.part_instance.part.autonext => {
+            Some(expected_duration + current.calculated_timings.to_part_delay)
+        }
+        _ => None,
+    };
+    let current_part_group = create_part_group(
+        &current.part_instance,
+        TimelineEnable {
+            start: Some(current_part_start),
+            duration: current_part_duration.map(TimelineEnableValue::from_duration),
+            ..Default::default()
+        },
+    );
+
+    let current_infinite_ids = get_infinite_instance_ids(&current.piece_instances);
+
+    if let Some(previous) = &info.previous {
+        timeline_objs.extend(generate_previous_part_instance_objects(
+            playlist,
+            previous,
+            &current_infinite_ids,
+            &current_part_group,
+            &current.calculated_timings,
+        ));
+    }
+
+    // Any continued infinites need to skip the part group, as they need a different start time
+    let next_infinite_ids = info
+        .next
+        .as_ref()
+        .map(|next| get_infinite_instance_ids(&next.piece_instances))
+        .unwrap_or_default();
+    for piece in current.piece_instances.iter() {
+        if let Some(infinite) = &piece.piece.infinite {
+            if piece.piece.piece.lifespan != PieceLifespan::WithinPart {
+                // Stop with the part, unless it continues into the next one
+                let ends_with_part = current_part_duration.is_some()
+                    && !next_infinite_ids.contains(&infinite.infinite_instance_id);
+
+                timeline_objs.extend(generate_current_infinite_piece_objects(
+                    playlist,
+                    &current.part_instance,
+                    piece,
+                    &current_part_group,
+                    ends_with_part,
+                ));
+            }
+        }
+    }
+
+    let current_pieces = current
+        .piece_instances
+        .iter()
+        .filter(|p| {
+            p.piece.infinite.is_none() || p.piece.piece.lifespan == PieceLifespan::WithinPart
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    let current_objs = transform_part_into_timeline(
+        playlist,
+        &current_pieces,
+        &["current_part"],
+        &current_part_group,
+        &current.calculated_timings,
+        is_in_hold,
+    );
+    timeline_objs.push(create_part_group_first_object(
+        playlist,
+        &current.part_instance,
+        &current_part_group,
+    ));
+    timeline_objs.push(current_part_group.clone());
+    timeline_objs.extend(current_objs);
+
+    // The next part is only added when the current part will autonext into it
+    if let (Some(next), Some(_)) = (&info.next, current_part_duration) {
+        timeline_objs.extend(generate_next_part_instance_objects(
+            playlist,
+            next,
+            &current_part_group,
+        ));
+    }
+
+    timeline_objs
+}
+
+fn get_infinite_instance_ids(
+    piece_instances: &[PieceInstanceWithTimings],
+) -> HashSet<PieceInstanceInfiniteId> {
+    piece_instances
+        .iter()
+        .filter_map(|p| {
+            p.piece
+                .infinite
+                .as_ref()
+                .map(|inf| inf.infinite_instance_id.clone())
+        })
+        .collect()
+}
+
+fn generate_previous_part_instance_objects(
+    playlist: &RundownPlaylist,
+    previous: &SelectedPartInstanceTimelineInfo,
+    current_infinite_ids: &HashSet<PieceInstanceInfiniteId>,
+    current_part_group: &TimelineObjGenerated,
+    current_part_timings: &PartCalculatedTimings,
+) -> Vec<TimelineObjGenerated> {
+    let started_playback = match previous.part_instance.timings.planned_started_playback {
+        Some(started_playback) => started_playback,
+        None => return Vec::new(),
+    };
+
+    // The previous part should continue for a while into the current one
+    let overlap = match previous.part_instance.part.autonext_overlap {
+        Some(overlap) if previous.part_instance.part.autonext && overlap > Duration::zero() => {
+            overlap
+        }
+        _ => current_part_timings.from_part_remaining,
+    };
+
+    let mut previous_part_group = create_part_group(
+        &previous.part_instance,
+        TimelineEnable {
+            start: Some(TimelineEnableValue::from_date(started_playback)),
+            end: Some(TimelineEnableValue::Expression(format!(
+                "#{}.start + {}",
+                current_part_group.id,
+                overlap.num_milliseconds()
+            ))),
+            ..Default::default()
+        },
+    );
+    previous_part_group.priority = -1;
+
+    // Infinites which continue in the current part are only added there, to avoid id collisions
+    let previous_pieces = previous
+        .piece_instances
+        .iter()
+        .filter(|p| {
+            p.piece.infinite.as_ref().map_or(true, |inf| {
+                !current_infinite_ids.contains(&inf.infinite_instance_id)
+            })
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut objs = transform_part_into_timeline(
+        playlist,
+        &previous_pieces,
+        &["previous_part"],
+        &previous_part_group,
+        &previous.calculated_timings,
+        playlist.hold_state == RundownHoldState::ACTIVE,
+    );
+    objs.insert(0, previous_part_group);
+    objs
+}
+
+fn generate_next_part_instance_objects(
+    playlist: &RundownPlaylist,
+    next: &SelectedPartInstanceTimelineInfo,
+    current_part_group: &TimelineObjGenerated,
+) -> Vec<TimelineObjGenerated> {
+    let next_part_group = create_part_group(
+        &next.part_instance,
+        TimelineEnable {
+            start: Some(TimelineEnableValue::Expression(format!(
+                "#{}.end - {}",
+                current_part_group.id,
+                next.calculated_timings
+                    .from_part_remaining
+                    .num_milliseconds()
+            ))),
+            ..Default::default()
+        },
+    );
+
+    // Infinites continued from the current part are already playing
+    let next_pieces = next
+        .piece_instances
+        .iter()
+        .filter(|p| {
+            p.piece
+                .infinite
+                .as_ref()
+                .map_or(true, |inf| inf.infinite_instance_index == 0)
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let mut objs = vec![
+        create_part_group_first_object(playlist, &next.part_instance, &next_part_group),
+        next_part_group.clone(),
+    ];
+    objs.extend(transform_part_into_timeline(
+        playlist,
+        &next_pieces,
+        &["next_part"],
+        &next_part_group,
+        &next.calculated_timings,
+        false,
+    ));
+    objs
+}
+
+fn generate_current_infinite_piece_objects(
+    playlist: &RundownPlaylist,
+    current_part_instance: &PartInstance,
+    piece: &PieceInstanceWithTimings,
+    current_part_group: &TimelineObjGenerated,
+    ends_with_part: bool,
+) -> Vec<TimelineObjGenerated> {
+    let infinite = match &piece.piece.infinite {
+        Some(infinite) => infinite,
+        None => return Vec::new(),
+    };
+
+    // Once the infinite has started it should keep going from that time, rather than restart with each part
+    let (group_start, piece_enable) = match piece.piece.planned_started_playback {
+        Some(started_playback) => (
+            TimelineEnableValue::from_date(started_playback),
+            TimelineEnable {
+                start: Some(TimelineEnableValue::Time(0)),
+                duration: piece
+                    .piece
+                    .piece
+                    .enable
+                    .duration
+                    .map(TimelineEnableValue::from_duration),
+                ..Default::default()
+            },
+        ),
+        None => (
+            TimelineEnableValue::Expression(format!("#{}.start", current_part_group.id)),
+            TimelineEnable {
+                start: Some(get_piece_start(&piece.piece)),
+                duration: piece
+                    .piece
+                    .piece
+                    .enable
+                    .duration
+                    .map(TimelineEnableValue::from_duration),
+                ..Default::default()
+            },
+        ),
+    };
+
+    let mut infinite_group = create_part_group(
+        current_part_instance,
+        TimelineEnable {
+            start: Some(group_start),
+            end: if ends_with_part {
+                Some(TimelineEnableValue::Expression(format!(
+                    "#{}.end",
+                    current_part_group.id
+                )))
+            } else {
+                None
+            },
+            ..Default::default()
+        },
+    );
+    infinite_group.id = format!("part_group_{}", infinite.infinite_instance_id.unprotect());
+    infinite_group.priority = 1;
+
+    let classes: &[&str] = if infinite.from_previous_part {
+        &["current_part", "continues_infinite"]
+    } else {
+        &["current_part"]
+    };
+
+    let mut objs =
+        create_piece_group_and_cap(playlist, piece, classes, &infinite_group, piece_enable);
+    objs.insert(0, infinite_group);
+    objs
+}
+
+fn transform_part_into_timeline(
+    playlist: &RundownPlaylist,
+    piece_instances: &[PieceInstanceWithTimings],
+    classes: &[&str],
+    part_group: &TimelineObjGenerated,
+    part_timings: &PartCalculatedTimings,
+    is_in_hold: bool,
+) -> Vec<TimelineObjGenerated> {
+    let mut objs = Vec::new();
+
+    for piece in piece_instances {
+        if piece.piece.disabled {
+            continue;
+        }
+        if is_in_hold && !piece.piece.piece.extend_on_hold {
+            continue;
+        }
+
+        if let Some(piece_enable) =
+            get_piece_enable_inside_part(&piece.piece, part_timings, part_group)
+        {
+            objs.extend(create_piece_group_and_cap(
+                playlist,
+                piece,
+                classes,
+                part_group,
+                piece_enable,
+            ));
+        }
+    }
+
+    objs
+}
+
+fn get_piece_start(piece_instance: &PieceInstance) -> TimelineEnableValue {
+    match piece_instance.piece.enable.start {
+        PieceEnableStart::Offset(start) => TimelineEnableValue::from_duration(start),
+        PieceEnableStart::Now => TimelineEnableValue::Expression("now".to_string()),
+    }
+}
+
+/**
+ * Calculate the enable of a piece within its part group, offsetting it to allow for any transitions.
+ * Returns None if the piece should not be played
+ */
+fn get_piece_enable_inside_part(
+    piece_instance: &PieceInstance,
+    part_timings: &PartCalculatedTimings,
+    part_group: &TimelineObjGenerated,
+) -> Option<TimelineEnable> {
+    let mut piece_enable = TimelineEnable {
+        start: Some(get_piece_start(piece_instance)),
+        duration: piece_instance
+            .piece
+            .enable
+            .duration
+            .map(TimelineEnableValue::from_duration),
+        ..Default::default()
+    };
+
+    match piece_instance.piece.piece_type {
+        IBlueprintPieceType::InTransition => {
+            // Only played when the part is transitioned into
+            piece_enable.start = Some(TimelineEnableValue::from_duration(
+                part_timings.in_transition_start?,
+            ));
+        }
+        IBlueprintPieceType::OutTransition => {
+            // Played at the end of the part, so the part must have an end
+            if part_group.enable.duration.is_none() && part_group.enable.end.is_none() {
+                return None;
+            }
+
+            let duration = piece_instance
+                .piece
+                .enable
+                .duration
+                .unwrap_or_else(Duration::zero);
+            piece_enable.start = Some(TimelineEnableValue::Expression(format!(
+                "#{}.end - {}",
+                part_group.id,
+                duration.num_milliseconds()
+            )));
+        }
+        IBlueprintPieceType::Normal => {
+            // Timed pieces are delayed by any transition into the part. Adlibs are played at the time they are inserted
+            if let PieceEnableStart::Offset(start) = piece_instance.piece.enable.start {
+                if piece_instance.dynamically_inserted.is_none() {
+                    piece_enable.start = Some(TimelineEnableValue::from_duration(
+                        start + part_timings.to_part_delay,
+                    ));
+                }
+            }
+
+            // Stop before the postroll of the part, unless the piece has its own duration
+            if part_timings.to_part_postroll > Duration::zero() && piece_enable.duration.is_none() {
+                piece_enable.end = Some(TimelineEnableValue::Expression(format!(
+                    "#{}.end - {}",
+                    part_group.id,
+                    part_timings.to_part_postroll.num_milliseconds()
+                )));
+            }
+        }
+    }
+
+    Some(piece_enable)
+}
+
+/**
+ * Get the end of a piece set by the user, relative to the start of the part
+ */
+fn get_user_duration_end(piece_instance: &PieceInstance) -> Option<Duration> {
+    piece_instance
+        .user_duration
+        .as_ref()?
+        .get("endRelativeToPart")?
+        .as_i64()
+        .map(Duration::milliseconds)
+}
+
+/**
+ * Create the control object for a piece, along with the group containing the timeline objects of the piece.
+ * The control object determines when the piece plays, with the group extended to allow for the preroll and postroll
+ */
+fn create_piece_group_and_cap(
+    playlist: &RundownPlaylist,
+    piece: &PieceInstanceWithTimings,
+    classes: &[&str],
+    part_group: &TimelineObjGenerated,
+    mut piece_enable: TimelineEnable,
+) -> Vec<TimelineObjGenerated> {
+    let piece_instance = &piece.piece;
+    let control_id = get_piece_control_object_id(&piece_instance.id);
+
+    let mut objs = Vec::new();
+
+    // The end cap, and any duration set by the user, limit how long the piece plays for
+    let end_cap = match (
+        &piece.resolved_end_cap,
+        get_user_duration_end(piece_instance),
+    ) {
+        (ResolvedEndCap::Absolute(end), Some(user_end)) if *end < user_end => {
+            Some(TimelineEnableValue::from_duration(*end))
+        }
+        (_, Some(user_end)) => Some(TimelineEnableValue::from_duration(user_end)),
+        (ResolvedEndCap::Absolute(end), None) => Some(TimelineEnableValue::from_duration(*end)),
+        (ResolvedEndCap::Relative(end), None) => Some(TimelineEnableValue::Expression(end.clone())),
+        (ResolvedEndCap::None, None) => None,
+    };
+
+    let mut control_parent_id = part_group.id.clone();
+    if let Some(end_cap) = end_cap {
+        if piece_enable.end.is_none() && piece_enable.duration.is_none() {
+            piece_enable.end = Some(end_cap);
+        } else {
+            // The piece already has an end, so wrap it in a group which limits it to the cap
+            let mut cap_group = create_timeline_obj(
+                format!("{}_cap", control_id),
+                "".to_string(),
+                TimelineEnable {
+                    start: Some(TimelineEnableValue::Time(0)),
+                    end: Some(end_cap),
+                    ..Default::default()
+                },
+                json!({ "deviceType": DEVICE_TYPE_ABSTRACT, "type": "group" }),
+            );
+            cap_group.is_group = true;
+            cap_group.in_group = Some(part_group.id.clone());
+            cap_group.part_instance_id = part_group.part_instance_id.clone();
+
+            control_parent_id = cap_group.id.clone();
+            objs.push(cap_group);
+        }
+    }
+
+    let mut control_obj = create_timeline_obj(
+        control_id.clone(),
+        piece_instance.piece.source_layer_id.clone(),
+        piece_enable,
+        json!({
+            "deviceType": DEVICE_TYPE_ABSTRACT,
+            "type": "callback",
+            "callBack": "piecePlaybackStarted",
+            "callBackData": {
+                "rundownPlaylistId": playlist.id.unprotect(),
+                "pieceInstanceId": piece_instance.id.unprotect(),
+                "dynamicallyInserted": piece_instance.dynamically_inserted.is_some(),
+            },
+            "callBackStopped": "piecePlaybackStopped",
+        }),
+    );
+    control_obj.classes = classes.iter().map(|c| c.to_string()).collect();
+    control_obj.in_group = Some(control_parent_id.clone());
+    control_obj.part_instance_id = part_group.part_instance_id.clone();
+    control_obj.piece_instance_id = Some(piece_instance.id.clone());
+
+    let mut piece_group = create_timeline_obj(
+        format!("piece_group_{}", piece_instance.id.unprotect()),
+        "".to_string(),
+        TimelineEnable {
+            start: Some(TimelineEnableValue::Expression(format!(
+                "#{}.start - {}",
+                control_id,
+                piece_instance.piece.preroll_duration.num_milliseconds()
+            ))),
+            end: Some(TimelineEnableValue::Expression(format!(
+                "#{}.end + {}",
+                control_id,
+                piece_instance.piece.postroll_duration.num_milliseconds()
+            ))),
+            ..Default::default()
+        },
+        json!({ "deviceType": DEVICE_TYPE_ABSTRACT, "type": "group" }),
+    );
+    piece_group.is_group = true;
+    piece_group.in_group = Some(control_parent_id);
+    piece_group.part_instance_id = part_group.part_instance_id.clone();
+    piece_group.piece_instance_id = Some(piece_instance.id.clone());
+
+    let piece_objs = get_piece_timeline_objects(piece_instance, &piece_group);
+
+    objs.push(control_obj);
+    objs.push(piece_group);
+    objs.extend(piece_objs);
+    objs
+}
+
+/**
+ * Parse the timeline objects defined by the blueprints for a piece, making their ids unique to the PieceInstance
+ */
+fn get_piece_timeline_objects(
+    piece_instance: &PieceInstance,
+    piece_group: &TimelineObjGenerated,
+) -> Vec<TimelineObjGenerated> {
+    let mut objs = parse_piece_timeline_objects(&piece_instance.piece);
+
+    let prefix = format!("{}_", piece_instance.id.unprotect());
+    let ids = objs.iter().map(|o| o.id.clone()).collect::<HashSet<_>>();
+
+    for obj in objs.iter_mut() {
+        obj.id = format!("{}{}", prefix, obj.id);
+        obj.object_type = TimelineObjType::Rundown;
+        obj.in_group = Some(match &obj.in_group {
+            Some(in_group) if ids.contains(in_group) => format!("{}{}", prefix, in_group),
+            _ => piece_group.id.clone(),
+        });
+        obj.part_instance_id = piece_group.part_instance_id.clone();
+        obj.piece_instance_id = Some(piece_instance.id.clone());
+
+        for value in [
+            &mut obj.enable.start,
+            &mut obj.enable.end,
+            &mut obj.enable.duration,
+            &mut obj.enable.while_,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if let TimelineEnableValue::Expression(expr) = value {
+                *expr = prefix_expression_references(expr, &ids, &prefix);
+            }
+        }
+    }
+
+    objs
+}
+
+/**
+ * Parse the timeline objects defined by the blueprints for a piece.
+ * Invalid objects are logged and ignored, as they shouldn't stop the rest of the timeline from playing
+ */
+pub fn parse_piece_timeline_objects(piece: &Piece) -> Vec<TimelineObjGenerated> {
+    if piece.timeline_objects_string.trim().is_empty() {
+        return Vec::new();
+    }
+
+    match serde_json::from_str::<Vec<TimelineObjGenerated>>(&piece.timeline_objects_string) {
+        Ok(objs) => objs,
+        Err(err) => {
+            error!(
+                "Failed to parse timeline objects of Piece \"{}\": {}",
+                piece.id.unprotect(),
+                err
+            );
+            Vec::new()
+        }
+    }
+}
+
+/**
+ * Prefix any `#id` references to the given ids in a timeline expression
+ */
+fn prefix_expression_references(expr: &str, ids: &HashSet<String>, prefix: &str) -> String {
+    let mut result = String::with_capacity(expr.len());
+
+    let mut chars = expr.chars().peekable();
+    while let Some(c) = chars.next() {
+        result.push(c);
+        if c == '#' {
+            let mut id = String::new();
+            while let Some(&next) = chars.peek() {
+                if next.is_alphanumeric() || next == '_' || next == '-' {
+                    id.push(next);
+                    chars.next();
+                } else {
+                    break;
+                }
+            }
+
+            if ids.contains(&id) {
+                result.push_str(prefix);
+            }
+            result.push_str(&id);
+        }
+    }
+
+    result
+}
+
+fn create_timeline_obj(
+    id: String,
+    layer: String,
+    enable: TimelineEnable,
+    content: serde_json::Value,
+) -> TimelineObjGenerated {
+    TimelineObjGenerated {
+        id,
+        object_type: TimelineObjType::Rundown,
+        enable,
+        layer,
+        priority: 0,
+        content,
+        classes: Vec::new(),
+        is_group: false,
+        in_group: None,
+        part_instance_id: None,
+        piece_instance_id: None,
+        is_lookahead: false,
+        lookahead_for_layer: None,
+        other: serde_json::Map::new(),
+    }
+}
+
+fn create_part_group(part_instance: &PartInstance, enable: TimelineEnable) -> TimelineObjGenerated {
+    let mut part_group = create_timeline_obj(
+        format!("part_group_{}", part_instance.id.unprotect()),
+        // These should coexist
+        "".to_string(),
+        enable,
+        json!({ "deviceType": DEVICE_TYPE_ABSTRACT, "type": "group" }),
+    );
+    part_group.priority = 5;
+    part_group.is_group = true;
+    part_group.part_instance_id = Some(part_instance.id.clone());
+    part_group
+}
+
+/**
+ * Create the object at the start of a part group, which reports to us when the part starts and stops playback
+ */
+fn create_part_group_first_object(
+    playlist: &RundownPlaylist,
+    part_instance: &PartInstance,
+    part_group: &TimelineObjGenerated,
+) -> TimelineObjGenerated {
+    let mut first_obj = create_timeline_obj(
+        format!("{}_firstobject", part_group.id),
+        "group_first_object".to_string(),
+        TimelineEnable {
+            start: Some(TimelineEnableValue::Time(0)),
+            ..Default::default()
+        },
+        json!({
+            "deviceType": DEVICE_TYPE_ABSTRACT,
+            "type": "callback",
+            "callBack": "partPlaybackStarted",
+            "callBackData": {
+                "rundownPlaylistId": playlist.id.unprotect(),
+                "partInstanceId": part_instance.id.unprotect(),
+            },
+            "callBackStopped": "partPlaybackStopped",
+        }),
+    );
+    first_obj.in_group = Some(part_group.id.clone());
+    first_obj.part_instance_id = Some(part_instance.id.clone());
+    first_obj
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{
+        context::direct_collections::DirectCollections,
+        data_model::{ids::RundownPlaylistId, part::Part},
+        playout::{fixtures::*, lock::run_job_with_playout_cache},
+    };
+
+    /** Create a piece in part0, with the given enable and a preroll and postroll */
+    fn create_timed_piece(id: &str, piece_enable: serde_json::Value) -> Piece {
+        let mut piece = create_piece(id, &create_part0(), PieceLifespan::OutOnRundownChange);
+        piece.enable = serde_json::from_value(piece_enable).unwrap();
+        piece.preroll_duration = Duration::milliseconds(100);
+        piece.postroll_duration = Duration::milliseconds(200);
+        piece
+    }
+
+    fn create_part0() -> Part {
+        create_part("part0", "segment0", "rundown0")
+    }
+
+    fn with_timings(
+        piece_instance: PieceInstance,
+        resolved_end_cap: ResolvedEndCap,
+    ) -> PieceInstanceWithTimings {
+        PieceInstanceWithTimings {
+            piece: Rc::new(piece_instance),
+            resolved_end_cap,
+            priority: 0,
+        }
+    }
+
+    fn find_obj<'a>(objs: &'a [TimelineObjGenerated], id: &str) -> &'a TimelineObjGenerated {
+        objs.iter()
+            .find(|o| o.id == id)
+            .unwrap_or_else(|| panic!("Missing timeline object \"{}\"", id))
+    }
+
+    #[test]
+    fn piece_group_without_cap() {
+        let playlist = create_playlist(&["rundown0"]);
+        let part_group = create_part_group(
+            &create_part_instance(&create_part0()),
+            TimelineEnable::default(),
+        );
+        let piece = with_timings(
+            create_piece_instance(
+                create_timed_piece("piece0", json!({ "start": 0 })),
+                &create_part_instance(&create_part0()),
+            ),
+            ResolvedEndCap::None,
+        );
+
+        let objs = create_piece_group_and_cap(
+            &playlist,
+            &piece,
+            &["current_part"],
+            &part_group,
+            TimelineEnable {
+                start: Some(TimelineEnableValue::Time(0)),
+                ..Default::default()
+            },
+        );
+
+        let ids = objs.iter().map(|o| o.id.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            ids,
+            vec![
+                "piece_group_control_part0_instance_piece0",
+                "piece_group_part0_instance_piece0"
+            ]
+        );
+
+        let control = find_obj(&objs, "piece_group_control_part0_instance_piece0");
+        assert_eq!(
+            control.in_group.as_deref(),
+            Some("part_group_part0_instance")
+        );
+        assert_eq!(control.enable.end, None);
+        assert_eq!(control.classes, vec!["current_part".to_string()]);
+
+        let piece_group = find_obj(&objs, "piece_group_part0_instance_piece0");
+        assert_eq!(
+            piece_group.in_group.as_deref(),
+            Some("part_group_part0_instance")
+        );
+        assert_eq!(
+            piece_group.enable.start,
+            Some(TimelineEnableValue::Expression(
+                "#piece_group_control_part0_instance_piece0.start - 100".to_string()
+            ))
+        );
+        assert_eq!(
+            piece_group.enable.end,
+            Some(TimelineEnableValue::Expression(
+                "#piece_group_control_part0_instance_piece0.end + 200".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn piece_group_cap_is_used_as_end() {
+        let playlist = create_playlist(&["rundown0"]);
+        let part_group = create_part_group(
+            &create_part_instance(&create_part0()),
+            TimelineEnable::default(),
+        );
+
+        for (resolved_end_cap, user_duration, expected_end) in [
+            (
+                ResolvedEndCap::Absolute(Duration::milliseconds(5000)),
+                None,
+                TimelineEnableValue::Time(5000),
+            ),
+            (
+                ResolvedEndCap::Relative("#other.start".to_string()),
+                None,
+                TimelineEnableValue::Expression("#other.start".to_string()),
+            ),
+            // The earliest of the cap and the user duration is used
+            (
+                ResolvedEndCap::Absolute(Duration::milliseconds(5000)),
+                Some(3000),
+                TimelineEnableValue::Time(3000),
+            ),
+            (
+                ResolvedEndCap::Absolute(Duration::milliseconds(2000)),
+                Some(3000),
+                TimelineEnableValue::Time(2000),
+            ),
+            // A relative cap can't be compared, so the user duration wins
+            (
+                ResolvedEndCap::Relative("#other.start".to_string()),
+                Some(3000),
+                TimelineEnableValue::Time(3000),
+            ),
+            (
+                ResolvedEndCap::None,
+                Some(3000),
+                TimelineEnableValue::Time(3000),
+            ),
+        ] {
+            let mut piece_instance = create_piece_instance(
+                create_timed_piece("piece0", json!({ "start": 0 })),
+                &create_part_instance(&create_part0()),
+            );
+            piece_instance.user_duration =
+                user_duration.map(|end| json!({ "endRelativeToPart": end }));
+            let piece = with_timings(piece_instance, resolved_end_cap);
+
+            let objs = create_piece_group_and_cap(
+                &playlist,
+                &piece,
+                &["current_part"],
+                &part_group,
+                TimelineEnable {
+                    start: Some(TimelineEnableValue::Time(0)),
+                    ..Default::default()
+                },
+            );
+
+            assert_eq!(objs.len(), 2, "{:?}", expected_end);
+            let control = find_obj(&objs, "piece_group_control_part0_instance_piece0");
+            assert_eq!(control.enable.end, Some(expected_end));
+            assert_eq!(
+                control.in_group.as_deref(),
+                Some("part_group_part0_instance")
+            );
+        }
+    }
+
+    #[test]
+    fn piece_group_with_end_is_wrapped_in_cap_group() {
+        let playlist = create_playlist(&["rundown0"]);
+        let part_group = create_part_group(
+            &create_part_instance(&create_part0()),
+            TimelineEnable::default(),
+        );
+        let piece = with_timings(
+            create_piece_instance(
+                create_timed_piece("piece0", json!({ "start": 0, "duration": 8000 })),
+                &create_part_instance(&create_part0()),
+            ),
+            ResolvedEndCap::Absolute(Duration::milliseconds(5000)),
+        );
+
+        let objs = create_piece_group_and_cap(
+            &playlist,
+            &piece,
+            &["current_part"],
+            &part_group,
+            TimelineEnable {
+                start: Some(TimelineEnableValue::Time(0)),
+                duration: Some(TimelineEnableValue::Time(8000)),
+                ..Default::default()
+            },
+        );
+
+        let cap_group = find_obj(&objs, "piece_group_control_part0_instance_piece0_cap");
+        assert!(cap_group.is_group);
+        assert_eq!(
+            cap_group.in_group.as_deref(),
+            Some("part_group_part0_instance")
+        );
+        assert_eq!(cap_group.enable.start, Some(TimelineEnableValue::Time(0)));
+        assert_eq!(cap_group.enable.end, Some(TimelineEnableValue::Time(5000)));
+
+        // The piece keeps its own duration, inside the cap
+        let control = find_obj(&objs, "piece_group_control_part0_instance_piece0");
+        assert_eq!(
+            control.in_group.as_deref(),
+            Some("piece_group_control_part0_instance_piece0_cap")
+        );
+        assert_eq!(control.enable.end, None);
+        assert_eq!(
+            control.enable.duration,
+            Some(TimelineEnableValue::Time(8000))
+        );
+
+        let piece_group = find_obj(&objs, "piece_group_part0_instance_piece0");
+        assert_eq!(
+            piece_group.in_group.as_deref(),
+            Some("piece_group_control_part0_instance_piece0_cap")
+        );
+    }
+
+    #[test]
+    fn continuing_infinite_is_placed_in_own_group() {
+        let playlist = create_playlist(&["rundown0"]);
+        let part_instance = create_part_instance(&create_part0());
+        let current_part_group = create_part_group(&part_instance, TimelineEnable::default());
+
+        let started_playback = Utc.timestamp_millis_opt(1_000_000).unwrap();
+        let mut piece_instance = create_infinite_piece_instance(
+            create_timed_piece("piece0", json!({ "start": 0 })),
+            &part_instance,
+        );
+        piece_instance.planned_started_playback = Some(started_playback);
+        if let Some(infinite) = piece_instance.infinite.as_mut() {
+            infinite.infinite_instance_index = 1;
+            infinite.from_previous_part = true;
+        }
+        let piece = with_timings(piece_instance, ResolvedEndCap::None);
+
+        for ends_with_part in [true, false] {
+            let objs = generate_current_infinite_piece_objects(
+                &playlist,
+                &part_instance,
+                &piece,
+                &current_part_group,
+                ends_with_part,
+            );
+
+            // The group keeps playing from when the infinite started, rather than restarting with the part
+            let infinite_group = &objs[0];
+            assert_eq!(infinite_group.id, "part_group_piece0_infinite");
+            assert!(infinite_group.is_group);
+            assert_eq!(infinite_group.in_group, None);
+            assert_eq!(infinite_group.priority, 1);
+            assert_eq!(
+                infinite_group.enable.start,
+                Some(TimelineEnableValue::Time(1_000_000))
+            );
+            assert_eq!(
+                infinite_group.enable.end,
+                if ends_with_part {
+                    Some(TimelineEnableValue::Expression(
+                        "#part_group_part0_instance.end".to_string(),
+                    ))
+                } else {
+                    None
+                }
+            );
+
+            let control = find_obj(&objs, "piece_group_control_part0_instance_piece0");
+            assert_eq!(
+                control.in_group.as_deref(),
+                Some("part_group_piece0_infinite")
+            );
+            assert_eq!(control.enable.start, Some(TimelineEnableValue::Time(0)));
+            assert_eq!(
+                control.classes,
+                vec!["current_part".to_string(), "continues_infinite".to_string()]
+            );
+        }
+    }
+
+    #[test]
+    fn unstarted_infinite_starts_with_part() {
+        let playlist = create_playlist(&["rundown0"]);
+        let part_instance = create_part_instance(&create_part0());
+        let current_part_group = create_part_group(&part_instance, TimelineEnable::default());
+
+        let piece_instance = create_infinite_piece_instance(
+            create_timed_piece("piece0", json!({ "start": 500 })),
+            &part_instance,
+        );
+        let piece = with_timings(piece_instance, ResolvedEndCap::None);
+
+        let objs = generate_current_infinite_piece_objects(
+            &playlist,
+            &part_instance,
+            &piece,
+            &current_part_group,
+            false,
+        );
+
+        assert_eq!(objs[0].id, "part_group_piece0_infinite");
+        assert_eq!(
+            objs[0].enable.start,
+            Some(TimelineEnableValue::Expression(
+                "#part_group_part0_instance.start".to_string()
+            ))
+        );
+
+        let control = find_obj(&objs, "piece_group_control_part0_instance_piece0");
+        assert_eq!(control.enable.start, Some(TimelineEnableValue::Time(500)));
+        assert_eq!(control.classes, vec!["current_part".to_string()]);
+    }
+
+    #[test]
+    fn prefix_expression_references_only_known_ids() {
+        let ids = ["obj0", "obj-1", "obj_2"]
+            .iter()
+            .map(|id| id.to_string())
+            .collect::<HashSet<_>>();
+
+        for (expr, expected) in [
+            ("#obj0.start", "#p_obj0.start"),
+            ("#obj0.end - #other.start", "#p_obj0.end - #other.start"),
+            ("#obj-1.start + #obj_2.end", "#p_obj-1.start + #p_obj_2.end"),
+            // A reference must match an id exactly
+            ("#obj0a.start", "#obj0a.start"),
+            ("#obj.start", "#obj.start"),
+            ("1000", "1000"),
+            ("#", "#"),
+            ("", ""),
+        ] {
+            assert_eq!(
+                prefix_expression_references(expr, &ids, "p_"),
+                expected,
+                "{}",
+                expr
+            );
+        }
+    }
+
+    /** Regenerate the timeline for an inactive playlist0, with the studio timeline last generated by playlist1 */
+    async fn update_inactive_playlist_timeline(other_playlist_active: bool) -> TimelineComplete {
+        let mut data = create_playlist_data();
+        data.rundown_playlists[0].activation_id = None;
+
+        let mut other_playlist = create_playlist(&[]);
+        other_playlist.id = RundownPlaylistId::new_from("playlist1".to_string());
+        if !other_playlist_active {
+            other_playlist.activation_id = None;
+        }
+        data.rundown_playlists.push(other_playlist);
+
+        let collections = DirectCollections::create_in_memory(data).unwrap();
+        collections
+            .timelines
+            .insert_one(&TimelineComplete {
+                id: StudioId::new_from("studio0".to_string()),
+                timeline_hash: "playlist1_hash".to_string(),
+                generated: Utc::now(),
+                timeline_blob: "[{}]".to_string(),
+            })
+            .await
+            .unwrap();
+
+        let (context, _events_receiver) = create_context(&collections);
+        run_job_with_playout_cache(&context, &playlist_id(), |context, cache| {
+            Box::pin(update_timeline(context, cache, None))
+        })
+        .await
+        .unwrap();
+
+        collections
+            .timelines
+            .find_one_by_id(&StudioId::new_from("studio0".to_string()), None)
+            .await
+            .unwrap()
+            .expect("timeline is missing")
+    }
+
+    #[tokio::test]
+    async fn inactive_playlist_clears_timeline() {
+        let timeline = update_inactive_playlist_timeline(false).await;
+        assert_ne!(timeline.timeline_hash, "playlist1_hash");
+        assert_eq!(timeline.timeline_blob, "[]");
+    }
+
+    #[tokio::test]
+    async fn inactive_playlist_keeps_timeline_of_active_playlist() {
+        let timeline = update_inactive_playlist_timeline(true).await;
+        assert_eq!(timeline.timeline_hash, "playlist1_hash");
+        assert_eq!(timeline.timeline_blob, "[{}]");
+    }
+}